@@ -0,0 +1,360 @@
+//! One record for every session, written once it is over.
+//!
+//! A record tells when the session started and ended, who the client was
+//! and the user it logged in as, the target it asked for and the address
+//! that was connected to (unless an upstream did that), the connector it
+//! was routed to, the reply it got, the bytes relayed each way, how long it
+//! took and why it ended.
+//!
+//! It is written as JSON:
+//!
+//! ```text
+//! {"start":"2024-05-01T12:00:00.000Z","end":"2024-05-01T12:00:01.250Z","client":"192.0.2.1:50312","user":"alice","target":"example.com:443","target_addr":"93.184.216.34:443","route":"direct","reply":0,"bytes_up":517,"bytes_down":6012,"duration":1.250,"reason":"done"}
+//! ```
+//!
+//! as logfmt, where whatever isn't known is left out:
+//!
+//! ```text
+//! start=2024-05-01T12:00:00.000Z end=2024-05-01T12:00:01.250Z client=192.0.2.1:50312 user=alice target=example.com:443 target_addr=93.184.216.34:443 route=direct reply=0 bytes_up=517 bytes_down=6012 duration=1.250 reason=done
+//! ```
+//!
+//! or in the Common Log Format of web servers, with the reply in place of
+//! the status and the bytes sent to the client:
+//!
+//! ```text
+//! 192.0.2.1 - alice [01/May/2024:12:00:00 +0000] "CONNECT example.com:443" 0 6012
+//! ```
+//!
+//! The reply is the one of SOCKS5 (RFC 1928), which the other protocols are
+//! given the same codes of as well. See `AccessLogConfig` for where records
+//! go.
+//!
+//! Sessions only format their records. A thread of its own writes them, so
+//! a slow disk or syslog never holds up the runtime, and records it falls
+//! too far behind on are dropped and counted in `Metrics::dropped_records`.
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::client::Origin;
+use crate::config::{AccessLogConfig, LogFormat, LogSink};
+use crate::connector::TargetAddr;
+use crate::metrics::{self, METRICS};
+use crate::utilities::{civil, push_json_string, timestamp};
+
+// Records waiting for the writer, past which new ones are dropped.
+const BACKLOG: usize = 4096;
+
+tokio::task_local! {
+    static RECORD: RefCell<Record>;
+}
+
+/// What is known about a session so far.
+pub struct Record {
+    start: SystemTime,
+    started: Instant,
+    duration: Duration,
+    client: Option<SocketAddr>,
+    user: Option<String>,
+    pub target: Option<TargetAddr>,
+    pub target_addr: Option<SocketAddr>,
+    pub route: Option<&'static str>,
+    pub reply: Option<u8>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    reason: String
+}
+
+impl Record {
+    fn new() -> Record {
+        Record {
+            start: SystemTime::now(),
+            started: Instant::now(),
+            duration: Duration::ZERO,
+            client: None,
+            user: None,
+            target: None,
+            target_addr: None,
+            route: None,
+            reply: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            reason: String::new()
+        }
+    }
+
+    /// The session of the client from `origin` ended with `result`.
+    pub fn close<T>(&mut self, origin: &Origin, result: &io::Result<T>) {
+        self.duration = self.started.elapsed();
+        self.client = Some(origin.addr);
+        self.user = origin.user.clone();
+        self.reason = match result {
+            Ok(_) => "done".to_string(),
+            Err(e) => e.to_string(),
+        };
+    }
+}
+
+/// Runs the session `serve`, and resolves to its result along with what it
+/// told `note` about itself.
+pub async fn collect<T>(serve: impl Future<Output = T>) -> (T, Record) {
+    RECORD.scope(RefCell::new(Record::new()), async {
+        let res = serve.await;
+        (res, RECORD.with(|r| r.replace(Record::new())))
+    }).await
+}
+
+/// Adds to the record of the session this is called from, if there is one.
+pub fn note(f: impl FnOnce(&mut Record)) {
+    let _ = RECORD.try_with(|r| f(&mut r.borrow_mut()));
+}
+
+/// How records look, and the writer they are handed to.
+pub struct AccessLog {
+    format: LogFormat,
+    lines: SyncSender<String>
+}
+
+enum Sink {
+    Stderr,
+    File(Rotating),
+    #[cfg(unix)]
+    Syslog(UnixDatagram, PathBuf)
+}
+
+impl Sink {
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stderr => writeln!(io::stderr().lock(), "{}", line),
+            Sink::File(file) => file.write_line(line),
+            #[cfg(unix)]
+            Sink::Syslog(socket, path) => {
+                // Facility user, severity info, as in RFC 3164.
+                let msg = format!("<14>rustoxy[{}]: {}", std::process::id(), line);
+                socket.send_to(msg.as_bytes(), path).map(|_| ())
+            }
+        }
+    }
+}
+
+impl AccessLog {
+    /// Opens the sink of `config`, and starts the thread writing to it,
+    /// which is done once the log is dropped.
+    pub fn open(config: &AccessLogConfig) -> io::Result<AccessLog> {
+        let mut sink = match config.sink {
+            LogSink::Stderr => Sink::Stderr,
+            LogSink::File => {
+                let path = config.path.clone().expect("path is checked by Config::validate");
+                Sink::File(Rotating::open(path, config.max_size, config.keep)?)
+            }
+            #[cfg(unix)]
+            LogSink::Syslog => {
+                let path = config.path.clone().unwrap_or_else(|| PathBuf::from("/dev/log"));
+                Sink::Syslog(UnixDatagram::unbound()?, path)
+            }
+            #[cfg(not(unix))]
+            LogSink::Syslog => unreachable!("syslog is refused by Config::validate"),
+        };
+        let (lines, rx) = mpsc::sync_channel::<String>(BACKLOG);
+        thread::Builder::new().name("access-log".to_string()).spawn(move || {
+            for line in rx {
+                if let Err(e) = sink.write(&line) {
+                    warn!("dropped access log record: {}", e);
+                }
+            }
+        })?;
+        Ok(AccessLog { format: config.format, lines })
+    }
+
+    /// Hands `record` to the writer. Records which can't be written are
+    /// dropped, so as not to take sessions down with the log.
+    pub fn write(&self, record: &Record) {
+        let line = match self.format {
+            LogFormat::Json => json(record),
+            LogFormat::Logfmt => logfmt(record),
+            LogFormat::Clf => clf(record),
+        };
+        if self.lines.try_send(line).is_err() {
+            metrics::add(&METRICS.dropped_records, 1);
+        }
+    }
+}
+
+// A file which is moved to `<path>.1` once it'd grow past `max_size`, with
+// `<path>.1` moved to `<path>.2` and so on, up to `keep` old files.
+struct Rotating {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    keep: usize
+}
+
+impl Rotating {
+    fn open(path: PathBuf, max_size: Option<u64>, keep: usize) -> io::Result<Rotating> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Rotating { path, file, size, max_size, keep })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size.is_some_and(|max| self.size > 0 && self.size + len > max) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let from = numbered(&self.path, i);
+                if from.exists() {
+                    fs::rename(from, numbered(&self.path, i + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        *self = Rotating::open(self.path.clone(), self.max_size, self.keep)?;
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", i));
+    PathBuf::from(name)
+}
+
+// The fields of a record in order, as text or numbers.
+enum Value {
+    Text(String),
+    Number(String)
+}
+
+fn fields(r: &Record) -> Vec<(&'static str, Option<Value>)> {
+    let text = |s: String| Some(Value::Text(s));
+    let number = |s: String| Some(Value::Number(s));
+    vec![
+        ("start", text(timestamp(r.start))),
+        ("end", text(timestamp(r.start + r.duration))),
+        ("client", r.client.and_then(|a| text(a.to_string()))),
+        ("user", r.user.clone().and_then(text)),
+        ("target", r.target.as_ref().and_then(|t| text(t.to_string()))),
+        ("target_addr", r.target_addr.and_then(|a| text(a.to_string()))),
+        ("route", r.route.and_then(|s| text(s.to_string()))),
+        ("reply", r.reply.and_then(|rep| number(rep.to_string()))),
+        ("bytes_up", number(r.bytes_up.to_string())),
+        ("bytes_down", number(r.bytes_down.to_string())),
+        ("duration", number(format!("{:.3}", r.duration.as_secs_f64()))),
+        ("reason", text(r.reason.clone())),
+    ]
+}
+
+fn json(r: &Record) -> String {
+    let mut line = String::from("{");
+    for (i, (key, value)) in fields(r).into_iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        let _ = write!(line, "\"{}\":", key);
+        match value {
+            None => line.push_str("null"),
+            Some(Value::Number(n)) => line.push_str(&n),
+            Some(Value::Text(s)) => push_json_string(&mut line, &s),
+        }
+    }
+    line.push('}');
+    line
+}
+
+fn logfmt(r: &Record) -> String {
+    let mut pairs = Vec::new();
+    for (key, value) in fields(r) {
+        let value = match value {
+            None => continue,
+            Some(Value::Number(n)) => n,
+            Some(Value::Text(s)) if s.is_empty() || s.contains([' ', '=', '"', '\\']) => {
+                format!("{:?}", s)
+            }
+            Some(Value::Text(s)) => s,
+        };
+        pairs.push(format!("{}={}", key, value));
+    }
+    pairs.join(" ")
+}
+
+fn clf(r: &Record) -> String {
+    let (year, month, day, h, m, s, _) = civil(r.start);
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep",
+                                "Oct", "Nov", "Dec"];
+    let request = match r.target {
+        Some(ref target) => format!("\"CONNECT {}\"", target),
+        None => "\"-\"".to_string(),
+    };
+    format!("{} - {} [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] {} {} {}",
+            r.client.map_or("-".to_string(), |a| a.ip().to_string()),
+            // Spaces would split the field.
+            r.user.as_deref().map_or("-".to_string(), |u| u.replace(' ', "_")),
+            day, MONTHS[month as usize - 1], year, h, m, s, request,
+            r.reply.map_or("-".to_string(), |rep| rep.to_string()), r.bytes_down)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    fn record(n: u64) -> Record {
+        let mut record = Record::new();
+        record.bytes_up = n;
+        let origin = Origin { addr: "192.0.2.1:50312".parse().unwrap(), user: None };
+        record.close(&origin, &Ok(()));
+        record
+    }
+
+    #[test]
+    fn records_are_written_by_the_writer_thread() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        // A file holds two records.
+        let max_size = 2 * (logfmt(&record(0)).len() as u64 + 1);
+        let config = AccessLogConfig { format: LogFormat::Logfmt, sink: LogSink::File,
+                                       path: Some(path.clone()), max_size: Some(max_size),
+                                       keep: 1 };
+        let log = AccessLog::open(&config).unwrap();
+        for n in 0..5 {
+            log.write(&record(n));
+        }
+        let old = numbered(&path, 1);
+        let lines = || {
+            let read = |p: &Path| fs::read_to_string(p).unwrap_or_default();
+            read(&old).lines().chain(read(&path).lines()).map(String::from).collect::<Vec<_>>()
+        };
+        for _ in 0..100 {
+            if lines().last().is_some_and(|l| l.contains("bytes_up=4 ")) {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        // Only one old file is kept.
+        let lines = lines();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("bytes_up=2 "));
+        assert!(lines[2].contains("bytes_up=4 "));
+        assert!(!numbered(&path, 2).exists());
+    }
+}
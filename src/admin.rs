@@ -0,0 +1,276 @@
+//! A small HTTP API to look after a running server.
+//!
+//! ```text
+//! GET    /metrics            the process wide counters, as text
+//! GET    /bans               the bans of the lockout in force, as JSON
+//! DELETE /bans               lifts every ban
+//! DELETE /bans/ip/<address>  lifts the ban of an address
+//! DELETE /bans/user/<user>   lifts the ban of a user, percent-encoded
+//! ```
+//!
+//! Bans are listed as
+//!
+//! ```text
+//! [{"ip":"192.0.2.1","failures":5,"until":"2024-05-01T12:15:00.000Z"},{"user":"alice","failures":7,"until":"2024-05-01T12:20:00.000Z"}]
+//! ```
+//!
+//! and lifting them answers how many there were, as `{"lifted":1}`. Every
+//! connection gets a single answer and is closed after it. See
+//! `AdminConfig`.
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use std::fmt::Write as _;
+use std::io;
+use std::net::IpAddr;
+use std::str;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::digest;
+use crate::config::AdminConfig;
+use crate::lockout::{Key, Lockout};
+use crate::metrics::METRICS;
+use crate::utilities::{digests_match, other, push_json_string, timestamp};
+
+// Longest a request head may be, and take to arrive.
+const MAX_HEAD: u64 = 8 << 10;
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Admin {
+    token: Option<[u8; 32]>,
+    lockout: Option<Arc<Lockout>>
+}
+
+/// Answers the requests of the admin API on `listener`, for as long as this
+/// runs.
+pub async fn serve(listener: TcpListener, config: AdminConfig, lockout: Option<Arc<Lockout>>) {
+    let admin = Arc::new(Admin { token: config.token.as_deref().map(digest), lockout });
+    loop {
+        let (conn, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("error accepting admin client: {}", e);
+                continue;
+            }
+        };
+        let admin = admin.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin.handle(conn).await {
+                debug!("admin client {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+// An answer with its status line and content type.
+struct Answer(&'static str, &'static str, String);
+
+impl Answer {
+    fn json(body: String) -> Answer {
+        Answer("200 OK", "application/json", body)
+    }
+
+    fn error(status: &'static str, msg: &str) -> Answer {
+        let mut body = String::from("{\"error\":");
+        push_json_string(&mut body, msg);
+        body.push('}');
+        Answer(status, "application/json", body)
+    }
+}
+
+impl Admin {
+    async fn handle(&self, mut conn: TcpStream) -> io::Result<()> {
+        let (r, mut w) = conn.split();
+        let mut r = BufReader::new(r.take(MAX_HEAD));
+        let head = tokio::time::timeout(HEAD_TIMEOUT, read_head(&mut r)).await
+            .map_err(|_| other("request head timed out"))??;
+        let Answer(status, content_type, body) = self.answer(&head);
+        let response = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                                Connection: close\r\n\r\n{}",
+                               status, content_type, body.len(), body);
+        w.write_all(response.as_bytes()).await?;
+        w.shutdown().await
+    }
+
+    fn answer(&self, head: &[String]) -> Answer {
+        let mut request = head[0].split_whitespace();
+        let (method, target) = match (request.next(), request.next()) {
+            (Some(method), Some(target)) => (method, target),
+            _ => return Answer::error("400 Bad Request", "malformed request line"),
+        };
+        if let Some(ref expected) = self.token {
+            let given = head[1..].iter().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("authorization").then(|| value.trim())
+            });
+            let given = given.and_then(|v| v.strip_prefix("Bearer ")).map(digest);
+            let matches = given.is_some_and(|given| digests_match(expected, &given));
+            if !matches {
+                return Answer::error("401 Unauthorized", "a bearer token is required");
+            }
+        }
+        let path = target.split('?').next().unwrap_or("");
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["metrics"]) => Answer("200 OK", "text/plain", format!("{}\n", METRICS)),
+            ("GET", ["bans"]) => Answer::json(self.bans()),
+            ("DELETE", ["bans"]) => self.lift(None),
+            ("DELETE", ["bans", "ip", ip]) => match ip.parse::<IpAddr>() {
+                Ok(ip) => self.lift(Some(Key::Ip(ip))),
+                Err(_) => Answer::error("400 Bad Request", "malformed address"),
+            },
+            ("DELETE", ["bans", "user", user]) => match percent_decode(user) {
+                Some(user) => self.lift(Some(Key::User(user))),
+                None => Answer::error("400 Bad Request", "malformed user"),
+            },
+            (_, ["metrics"] | ["bans", ..]) => {
+                Answer::error("405 Method Not Allowed", "method not allowed")
+            }
+            _ => Answer::error("404 Not Found", "not found"),
+        }
+    }
+
+    fn bans(&self) -> String {
+        let mut bans = self.lockout.as_ref().map_or(Vec::new(), |l| l.bans());
+        bans.sort_by_key(|ban| ban.until);
+        let mut body = String::from("[");
+        for (i, ban) in bans.iter().enumerate() {
+            if i > 0 {
+                body.push(',');
+            }
+            match ban.key {
+                Key::Ip(ip) => {
+                    body.push_str("{\"ip\":");
+                    push_json_string(&mut body, &ip.to_string());
+                }
+                Key::User(ref user) => {
+                    body.push_str("{\"user\":");
+                    push_json_string(&mut body, user);
+                }
+            }
+            let _ = write!(body, ",\"failures\":{},\"until\":", ban.failures);
+            push_json_string(&mut body, &timestamp(ban.until));
+            body.push('}');
+        }
+        body.push(']');
+        body
+    }
+
+    fn lift(&self, key: Option<Key>) -> Answer {
+        let lifted = self.lockout.as_ref().map_or(0, |l| l.lift(key.as_ref()));
+        if lifted == 0 && key.is_some() {
+            return Answer::error("404 Not Found", "no such ban");
+        }
+        Answer::json(format!("{{\"lifted\":{}}}", lifted))
+    }
+}
+
+// The request line and the header lines, without their line breaks.
+async fn read_head<R>(r: &mut R) -> io::Result<Vec<String>>
+    where R: AsyncBufReadExt + Unpin
+{
+    let mut head = Vec::new();
+    loop {
+        let mut line = String::new();
+        if r.read_line(&mut line).await? == 0 {
+            return Err(other("request head cut short"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            if head.is_empty() {
+                // Tolerated before the request line, as RFC 9112 asks.
+                continue;
+            }
+            return Ok(head);
+        }
+        head.push(line.to_string());
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LockoutConfig;
+
+    fn admin(token: Option<&str>) -> Admin {
+        let lockout = Lockout::new(LockoutConfig { max_failures: 1, ..LockoutConfig::default() });
+        lockout.failed("192.0.2.1".parse().unwrap(), "al ice");
+        Admin { token: token.map(digest), lockout: Some(Arc::new(lockout)) }
+    }
+
+    fn ask(admin: &Admin, head: &[&str]) -> (&'static str, String) {
+        let head: Vec<String> = head.iter().map(|line| line.to_string()).collect();
+        let Answer(status, _, body) = admin.answer(&head);
+        (status, body)
+    }
+
+    #[test]
+    fn a_token_is_asked_for() {
+        let admin = admin(Some("s3cr3t"));
+        for auth in [None, Some("Authorization: Bearer wrong"), Some("Authorization: s3cr3t")] {
+            let head: Vec<&str> = ["GET /metrics HTTP/1.1"].into_iter().chain(auth).collect();
+            assert_eq!(ask(&admin, &head).0, "401 Unauthorized");
+        }
+        let head = ["GET /metrics HTTP/1.1", "Host: localhost", "authorization: Bearer s3cr3t"];
+        assert_eq!(ask(&admin, &head).0, "200 OK");
+    }
+
+    #[test]
+    fn bans_are_listed_and_lifted() {
+        let admin = admin(None);
+        let (status, body) = ask(&admin, &["GET /bans HTTP/1.1"]);
+        assert_eq!(status, "200 OK");
+        assert!(body.contains("{\"ip\":\"192.0.2.1\",\"failures\":1,"));
+        assert!(body.contains("{\"user\":\"al ice\",\"failures\":1,"));
+
+        let lift_user = ["DELETE /bans/user/al%20ice HTTP/1.1"];
+        assert_eq!(ask(&admin, &lift_user), ("200 OK", "{\"lifted\":1}".to_string()));
+        assert_eq!(ask(&admin, &lift_user).0, "404 Not Found");
+        assert_eq!(ask(&admin, &["DELETE /bans/user/%zz HTTP/1.1"]).0, "400 Bad Request");
+        assert_eq!(ask(&admin, &["DELETE /bans/ip/nowhere HTTP/1.1"]).0, "400 Bad Request");
+        assert_eq!(ask(&admin, &["DELETE /bans HTTP/1.1"]).1, "{\"lifted\":1}");
+        assert_eq!(ask(&admin, &["GET /bans HTTP/1.1"]).1, "[]");
+    }
+
+    #[test]
+    fn unknown_requests_are_refused() {
+        let admin = admin(None);
+        assert_eq!(ask(&admin, &["GET /nowhere HTTP/1.1"]).0, "404 Not Found");
+        assert_eq!(ask(&admin, &["GET /bans/ip/192.0.2.1 HTTP/1.1"]).0, "405 Method Not Allowed");
+        assert_eq!(ask(&admin, &["POST /metrics HTTP/1.1"]).0, "405 Method Not Allowed");
+        assert_eq!(ask(&admin, &["GET"]).0, "400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn answers_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = AdminConfig { address: addr, token: Some("s3cr3t".to_string()) };
+        tokio::spawn(serve(listener, config, admin(None).lockout));
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"\r\nDELETE /bans/ip/192.0.2.1 HTTP/1.1\r\n\
+                         Authorization: Bearer s3cr3t\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\nContent-Length: 12\r\nConnection: close\r\n\r\n\
+                                    {\"lifted\":1}"));
+    }
+}
@@ -0,0 +1,499 @@
+//! Checking the credentials of clients.
+//!
+//! SOCKS5 listeners with an authenticator require the username/password
+//! method of RFC 1929 from their clients, and sessions carry the name of the
+//! user from then on.
+//!
+//! Users are given in the configuration, or kept in an htpasswd file or a
+//! SQLite database as bcrypt or argon2 hashes, or checked by a helper
+//! program. Hashes are checked and databases queried on the blocking
+//! threads of the runtime, and their answers (as well as those of helpers)
+//! are remembered for a while by `Cached`. See `AuthConfig`.
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::AuthConfig;
+#[cfg(feature = "sqlite")]
+use crate::config::SqliteUsersConfig;
+#[cfg(feature = "sqlite")]
+use crate::utilities::sql_error;
+use crate::utilities::{digests_match, other, BoxFuture, Swept, SWEEP_EVERY};
+
+/// Decides whether clients are who they say they are.
+pub trait Authenticator: Send + Sync {
+    /// Whether `password` is the password of `user`.
+    fn verify<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, io::Result<bool>>;
+}
+
+/// The authenticator of `config`.
+pub fn open(config: &AuthConfig) -> io::Result<Arc<dyn Authenticator>> {
+    let ttl = Duration::from_secs(config.cache);
+    if let Some(ref path) = config.htpasswd {
+        return Ok(cached(Htpasswd::open(path)?, ttl));
+    }
+    if let Some(ref sqlite) = config.sqlite {
+        #[cfg(feature = "sqlite")]
+        return Ok(cached(SqliteUsers::open(sqlite)?, ttl));
+        #[cfg(not(feature = "sqlite"))]
+        unreachable!("SQLite is refused by Config::validate: {}", sqlite.path.display());
+    }
+    if let Some(ref command) = config.helper {
+        return Ok(cached(Helper::new(command.clone()), ttl));
+    }
+    Ok(Arc::new(Users::new(&config.users)))
+}
+
+fn cached(authenticator: impl Authenticator + 'static, ttl: Duration) -> Arc<dyn Authenticator> {
+    if ttl.is_zero() {
+        Arc::new(authenticator)
+    } else {
+        Arc::new(Cached::new(authenticator, ttl))
+    }
+}
+
+/// A fixed list of users and their passwords.
+pub struct Users {
+    // Digests of the passwords, compared instead of the passwords so that
+    // all comparisons take equally long.
+    digests: HashMap<String, [u8; 32]>
+}
+
+impl Users {
+    pub fn new<I, U, P>(users: I) -> Users
+        where I: IntoIterator<Item = (U, P)>, U: Into<String>, P: AsRef<str>
+    {
+        let digests = users.into_iter()
+            .map(|(user, password)| (user.into(), digest(password.as_ref())))
+            .collect();
+        Users { digests }
+    }
+}
+
+impl Authenticator for Users {
+    fn verify<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        let given = digest(password);
+        let matches = self.digests.get(user).is_some_and(|d| digests_match(d, &given));
+        Box::pin(async move { Ok(matches) })
+    }
+}
+
+pub(crate) fn digest(password: &str) -> [u8; 32] {
+    let mut d = [0u8; 32];
+    d.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, password.as_bytes()).as_ref());
+    d
+}
+
+// Whether `password` is the one of `hash`, made by bcrypt or argon2.
+fn verify_hash(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()
+        })
+    } else {
+        false
+    }
+}
+
+// Whether `password` is the one of `hash`. Users without a hash are
+// checked against `decoy` instead, so that they take just as long.
+fn verify_or_decoy(password: &str, hash: Option<String>, decoy: String) -> bool {
+    let known = hash.is_some();
+    let matches = verify_hash(password, &hash.unwrap_or(decoy));
+    known && matches
+}
+
+// A hash nobody knows the password of, for unknown users to be checked
+// against until there is a hash of a real user.
+fn make_decoy() -> io::Result<String> {
+    bcrypt::hash("decoy", bcrypt::DEFAULT_COST).map_err(|e| other(&e.to_string()))
+}
+
+// Runs `f` on a blocking thread, as bcrypt and argon2 take their time on
+// purpose, and files and databases are read with blocking calls.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> io::Result<T> + Send + 'static)
+    -> io::Result<T>
+{
+    tokio::task::spawn_blocking(f).await.map_err(|e| other(&e.to_string()))?
+}
+
+/// Users of an htpasswd file, which holds a line of `user:hash` for each
+/// of them. The file is read again once it changed.
+pub struct Htpasswd(Arc<HtpasswdFile>);
+
+struct HtpasswdFile {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+    // For unknown users while the file holds no usable hash.
+    decoy: String
+}
+
+struct Loaded {
+    modified: Option<SystemTime>,
+    hashes: HashMap<String, String>
+}
+
+impl Htpasswd {
+    pub fn open(path: &Path) -> io::Result<Htpasswd> {
+        let loaded = HtpasswdFile::load(path)?;
+        let file = HtpasswdFile { path: path.to_path_buf(), loaded: Mutex::new(loaded),
+                                  decoy: make_decoy()? };
+        Ok(Htpasswd(Arc::new(file)))
+    }
+}
+
+impl HtpasswdFile {
+    fn load(path: &Path) -> io::Result<Loaded> {
+        let modified = fs::metadata(path)?.modified().ok();
+        let mut hashes = HashMap::new();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                warn!("{}: line without a hash", path.display());
+                continue;
+            };
+            // MD5, SHA-1 and crypt(3) hashes are too weak to be worth it.
+            if !hash.starts_with("$2") && !hash.starts_with("$argon2") {
+                warn!("{}: {} has neither a bcrypt nor an argon2 hash", path.display(), user);
+                continue;
+            }
+            hashes.insert(user.to_string(), hash.to_string());
+        }
+        Ok(Loaded { modified, hashes })
+    }
+
+    // The hash of `user`, and one of somebody else to check against if
+    // there is none, from the file as it is now.
+    fn lookup(&self, user: &str) -> (Option<String>, String) {
+        let mut loaded = self.loaded.lock().unwrap();
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified != loaded.modified {
+            match HtpasswdFile::load(&self.path) {
+                Ok(reloaded) => {
+                    info!("read {} again", self.path.display());
+                    *loaded = reloaded;
+                }
+                // The users read last time are kept until the file is fixed.
+                Err(e) => error!("reading {} failed: {}", self.path.display(), e),
+            }
+        }
+        let decoy = loaded.hashes.values().next().unwrap_or(&self.decoy);
+        (loaded.hashes.get(user).cloned(), decoy.clone())
+    }
+}
+
+impl Authenticator for Htpasswd {
+    fn verify<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        let (file, user, password) = (self.0.clone(), user.to_string(), password.to_string());
+        Box::pin(blocking(move || {
+            let (hash, decoy) = file.lookup(&user);
+            Ok(verify_or_decoy(&password, hash, decoy))
+        }))
+    }
+}
+
+/// Users of a SQLite database, whose query gives the hash of the user bound
+/// to `?1`, as in an htpasswd file.
+#[cfg(feature = "sqlite")]
+pub struct SqliteUsers(Arc<SqliteDb>);
+
+#[cfg(feature = "sqlite")]
+struct SqliteDb {
+    db: Mutex<rusqlite::Connection>,
+    query: String,
+    // A hash for those who don't exist: one made up when the database is
+    // opened, and that of the last user who did exist from then on.
+    decoy: Mutex<String>
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteUsers {
+    pub fn open(config: &SqliteUsersConfig) -> io::Result<SqliteUsers> {
+        let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY;
+        let db = rusqlite::Connection::open_with_flags(&config.path, flags).map_err(sql_error)?;
+        // A query which doesn't make sense fails now rather than at the
+        // first login.
+        db.prepare_cached(&config.query).map_err(sql_error)?;
+        let db = SqliteDb { db: Mutex::new(db), query: config.query.clone(),
+                            decoy: Mutex::new(make_decoy()?) };
+        Ok(SqliteUsers(Arc::new(db)))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteDb {
+    fn lookup(&self, user: &str) -> io::Result<(Option<String>, String)> {
+        use rusqlite::OptionalExtension;
+        let hash = {
+            let db = self.db.lock().unwrap();
+            let mut query = db.prepare_cached(&self.query).map_err(sql_error)?;
+            query.query_row([user], |row| row.get::<_, String>(0)).optional().map_err(sql_error)?
+        };
+        let mut decoy = self.decoy.lock().unwrap();
+        if let Some(ref hash) = hash {
+            decoy.clone_from(hash);
+        }
+        Ok((hash, decoy.clone()))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Authenticator for SqliteUsers {
+    fn verify<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        let (db, user, password) = (self.0.clone(), user.to_string(), password.to_string());
+        Box::pin(blocking(move || {
+            let (hash, decoy) = db.lookup(&user)?;
+            Ok(verify_or_decoy(&password, hash, decoy))
+        }))
+    }
+}
+
+/// A helper program as run by Squid for basic auth. It is given a line of
+/// `user password` (both URL-encoded) for every login, and answers it with a
+/// line starting with `OK` or `ERR` (or `BH` if it is broken itself).
+///
+/// The program is started at the first login and again whenever it exited,
+/// and is asked about one login at a time.
+pub struct Helper {
+    command: Vec<String>,
+    process: tokio::sync::Mutex<Option<Process>>
+}
+
+struct Process {
+    // Killed once dropped.
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>
+}
+
+// Longest a helper may take to answer before it is restarted.
+const HELPER_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Helper {
+    pub fn new(command: Vec<String>) -> Helper {
+        Helper { command, process: tokio::sync::Mutex::new(None) }
+    }
+
+    fn start(&self) -> io::Result<Process> {
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| other(&format!("auth helper {}: {}", self.command[0], e)))?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        Ok(Process { _child: child, stdin, stdout })
+    }
+}
+
+impl Process {
+    async fn ask(&mut self, request: &str) -> io::Result<String> {
+        let mut answer = String::new();
+        let exchange = async {
+            self.stdin.write_all(request.as_bytes()).await?;
+            self.stdin.flush().await?;
+            match self.stdout.read_line(&mut answer).await? {
+                0 => Err(other("auth helper exited")),
+                _ => Ok(()),
+            }
+        };
+        tokio::time::timeout(HELPER_TIMEOUT, exchange).await
+            .unwrap_or_else(|_| Err(other("auth helper timed out")))?;
+        Ok(answer)
+    }
+}
+
+impl Authenticator for Helper {
+    fn verify<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move {
+            let request = format!("{} {}\n", url_encode(user), url_encode(password));
+            let mut process = self.process.lock().await;
+            // A helper which broke down since the last login is started
+            // again, and asked once more. It is taken out while it is asked,
+            // so if this is dropped halfway it goes with it rather than
+            // leaving its answer for the next login.
+            let answer = loop {
+                let (mut p, fresh) = match process.take() {
+                    Some(p) => (p, false),
+                    None => (self.start()?, true),
+                };
+                match p.ask(&request).await {
+                    Ok(answer) => {
+                        *process = Some(p);
+                        break answer;
+                    }
+                    Err(e) if fresh => return Err(e),
+                    Err(_) => {}
+                }
+            };
+            let answer = answer.trim_end();
+            match answer.split(' ').next() {
+                Some("OK") => Ok(true),
+                Some("ERR") => Ok(false),
+                _ => Err(other(&format!("auth helper answered {:?}", answer))),
+            }
+        })
+    }
+}
+
+// Escapes everything but letters, digits and `-._~`, as Squid does.
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// The passwords another authenticator accepted, remembered for a while by
+/// user and digest of the password. Passwords it turned down are asked
+/// about every time, so a password which was just set works right away,
+/// and errors aren't remembered either.
+pub struct Cached<A> {
+    inner: A,
+    ttl: Duration,
+    accepted: Mutex<Accepted>
+}
+
+// When each user and digest of a password was accepted.
+type Accepted = Swept<(String, [u8; 32]), Instant>;
+
+// Passwords remembered at most, past which the oldest one is forgotten.
+const MAX_ACCEPTED: usize = 1024;
+
+impl<A: Authenticator> Cached<A> {
+    pub fn new(inner: A, ttl: Duration) -> Cached<A> {
+        Cached { inner, ttl, accepted: Mutex::new(Swept::new(SWEEP_EVERY)) }
+    }
+}
+
+impl<A: Authenticator> Authenticator for Cached<A> {
+    fn verify<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move {
+            let key = (user.to_string(), digest(password));
+            if let Some(at) = self.accepted.lock().unwrap().get(&key) {
+                if at.elapsed() < self.ttl {
+                    return Ok(true);
+                }
+            }
+            let ok = self.inner.verify(user, password).await?;
+            let mut accepted = self.accepted.lock().unwrap();
+            if !ok {
+                accepted.remove(&key);
+                return Ok(false);
+            }
+            // Passwords which ran out are as good as gone.
+            accepted.sweep(|_, at| at.elapsed() < self.ttl);
+            if accepted.len() >= MAX_ACCEPTED && !accepted.contains_key(&key) {
+                let oldest = accepted.iter().min_by_key(|&(_, at)| *at).map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    accepted.remove(&oldest);
+                }
+            }
+            accepted.insert(key, Instant::now());
+            Ok(ok)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Accepts `password` for every user, and counts how often it was asked.
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl Authenticator for Counting {
+        fn verify<'a>(&'a self, _: &'a str, password: &'a str)
+            -> BoxFuture<'a, io::Result<bool>>
+        {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move { Ok(password == "password") })
+        }
+    }
+
+    #[tokio::test]
+    async fn only_accepted_passwords_are_remembered() {
+        let cached = Cached::new(Counting::default(), Duration::from_secs(60));
+        assert!(cached.verify("alice", "password").await.unwrap());
+        assert!(cached.verify("alice", "password").await.unwrap());
+        assert_eq!(cached.inner.0.load(Ordering::Relaxed), 1);
+        assert!(!cached.verify("alice", "guess").await.unwrap());
+        assert!(!cached.verify("alice", "guess").await.unwrap());
+        assert_eq!(cached.inner.0.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn the_oldest_passwords_make_room() {
+        let cached = Cached::new(Counting::default(), Duration::from_secs(60));
+        for i in 0..MAX_ACCEPTED + 10 {
+            cached.verify(&format!("user{}", i), "password").await.unwrap();
+        }
+        assert_eq!(cached.accepted.lock().unwrap().len(), MAX_ACCEPTED);
+        // The first users were forgotten, the last ones weren't.
+        let asked = cached.inner.0.load(Ordering::Relaxed);
+        cached.verify(&format!("user{}", MAX_ACCEPTED + 9), "password").await.unwrap();
+        assert_eq!(cached.inner.0.load(Ordering::Relaxed), asked);
+        cached.verify("user0", "password").await.unwrap();
+        assert_eq!(cached.inner.0.load(Ordering::Relaxed), asked + 1);
+    }
+
+    #[tokio::test]
+    async fn helpers_dropped_halfway_leave_no_answer_behind() {
+        let script = "while read user password; do sleep 0.3; \
+                      if [ \"$password\" = good ]; then echo OK; else echo ERR; fi; done";
+        let helper = Helper::new(vec!["sh".into(), "-c".into(), script.into()]);
+        let verify = helper.verify("alice", "good");
+        assert!(tokio::time::timeout(Duration::from_millis(100), verify).await.is_err());
+        // The answer for alice must not be taken for that of mallory.
+        assert!(!helper.verify("mallory", "bad").await.unwrap());
+        assert!(helper.verify("alice", "good").await.unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn unknown_users_of_a_new_database_have_a_decoy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.db");
+        let db = rusqlite::Connection::open(&path).unwrap();
+        db.execute("CREATE TABLE users (name TEXT PRIMARY KEY, hash TEXT NOT NULL)", []).unwrap();
+        drop(db);
+        let config = SqliteUsersConfig { path,
+                                         query: "SELECT hash FROM users WHERE name = ?1".into() };
+        let users = SqliteUsers::open(&config).unwrap();
+        let (hash, decoy) = users.0.lookup("nobody").unwrap();
+        assert!(hash.is_none());
+        assert!(decoy.starts_with("$2"));
+    }
+
+    #[test]
+    fn unknown_users_of_an_empty_htpasswd_file_have_a_decoy() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "alice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+        let htpasswd = Htpasswd::open(file.path()).unwrap();
+        let (hash, decoy) = htpasswd.0.lookup("nobody");
+        assert!(hash.is_none());
+        assert!(decoy.starts_with("$2"));
+    }
+}
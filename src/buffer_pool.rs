@@ -0,0 +1,169 @@
+//! Relay buffers shared by all sessions.
+//!
+//! Every buffer handed out counts against a global memory limit. When the
+//! limit is reached new sessions wait for a buffer instead of allocating
+//! more, and buffers given back are kept around to be reused, unless a
+//! session is waiting for the memory they take up.
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use std::collections::HashMap;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::utilities::other;
+
+// A buffer together with the share of the memory limit it takes up.
+type Block = (Box<[u8]>, OwnedSemaphorePermit);
+
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>
+}
+
+struct Inner {
+    limit: usize,
+    memory: Arc<Semaphore>,
+    free: Mutex<HashMap<usize, Vec<Block>>>,
+    // Sessions waiting for memory, which buffers given back go to rather
+    // than to `free`.
+    waiting: AtomicUsize
+}
+
+pub struct Buffer {
+    data: Option<Block>,
+    pool: Arc<Inner>
+}
+
+impl BufferPool {
+    /// Creates a pool which never holds more than `limit` bytes.
+    pub fn new(limit: usize) -> BufferPool {
+        BufferPool {
+            inner: Arc::new(Inner {
+                limit,
+                memory: Arc::new(Semaphore::new(limit)),
+                free: Mutex::new(HashMap::new()),
+                waiting: AtomicUsize::new(0)
+            })
+        }
+    }
+
+    /// Takes a buffer of `size` bytes out of the pool, waiting for other
+    /// sessions to give theirs back if the memory limit has been reached.
+    pub async fn get(&self, size: usize) -> io::Result<Buffer> {
+        if size > self.inner.limit || size > u32::MAX as usize {
+            return Err(other("relay buffer is larger than the memory limit"));
+        }
+        let cached = self.inner.free.lock().unwrap().get_mut(&size).and_then(|l| l.pop());
+        if let Some(data) = cached {
+            return Ok(Buffer { data: Some(data), pool: self.inner.clone() });
+        }
+        let permit = match self.inner.memory.clone().try_acquire_many_owned(size as u32) {
+            Ok(permit) => permit,
+            Err(_) => {
+                let _waiting = Waiting::new(&self.inner.waiting);
+                {
+                    // Idle buffers of other sizes may be holding the memory
+                    // we need, so release them before waiting. One of ours
+                    // may have come back since we looked.
+                    let mut free = self.inner.free.lock().unwrap();
+                    if let Some(data) = free.get_mut(&size).and_then(|l| l.pop()) {
+                        return Ok(Buffer { data: Some(data), pool: self.inner.clone() });
+                    }
+                    free.retain(|&s, _| s == size);
+                }
+                self.inner.memory.clone().acquire_many_owned(size as u32).await
+                    .map_err(|_| other("buffer pool closed"))?
+            }
+        };
+        let data = vec![0u8; size].into_boxed_slice();
+        Ok(Buffer { data: Some((data, permit)), pool: self.inner.clone() })
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.data.as_ref().unwrap().0
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data.as_mut().unwrap().0
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            let mut free = self.pool.free.lock().unwrap();
+            // Dropping the buffer gives its memory to whoever waits for it.
+            if self.pool.waiting.load(Ordering::SeqCst) == 0 {
+                free.entry(data.0.len()).or_default().push(data);
+            }
+        }
+    }
+}
+
+// Counts a session as waiting for as long as it is held.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Waiting<'_> {
+    fn new(count: &AtomicUsize) -> Waiting<'_> {
+        count.fetch_add(1, Ordering::SeqCst);
+        Waiting(count)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn buffers_are_reused() {
+        let pool = BufferPool::new(4096);
+        let buffer = pool.get(1024).await.unwrap();
+        drop(buffer);
+        let _buffer = pool.get(1024).await.unwrap();
+        assert_eq!(pool.inner.memory.available_permits(), 3072);
+    }
+
+    #[tokio::test]
+    async fn the_limit_holds_back_more_buffers() {
+        let pool = BufferPool::new(2048);
+        let _a = pool.get(1024).await.unwrap();
+        let _b = pool.get(1024).await.unwrap();
+        assert!(timeout(Duration::from_millis(50), pool.get(1024)).await.is_err());
+        assert!(pool.get(4096).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn waiters_get_buffers_given_back() {
+        let pool = BufferPool::new(2048);
+        let a = pool.get(1024).await.unwrap();
+        let _b = pool.get(1024).await.unwrap();
+        let waiter = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get(1024).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(a);
+        timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn idle_buffers_of_other_sizes_make_room() {
+        let pool = BufferPool::new(2048);
+        drop(pool.get(2048).await.unwrap());
+        timeout(Duration::from_secs(1), pool.get(1024)).await.unwrap().unwrap();
+    }
+}
@@ -1,292 +1,483 @@
-use tokio_io::io::{read_exact, write_all, Window};
-use futures::Future;
-use tokio_core::net::TcpStream;
-use tokio_core::reactor::Handle;
-use std::net::{SocketAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use std::net::SocketAddr;
 use std::io::{self};
-use futures::future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use utilities::{EitherFuture::{Left,Right},other,name_port,timeout};
+use crate::access_log::{self, AccessLog};
+use crate::auth::Authenticator;
+use crate::codec::{parse_addr, reply_code, v5, write_addr};
+use crate::conn_limit::{ConnectionLimiter, Permit};
+use crate::config::Timeouts;
+use crate::connector::{transfer_outbound,Outbound,Router,TargetAddr};
+use crate::hooks::SessionHooks;
+use crate::lockout::Lockout;
+use crate::quota::{Account, Quotas};
+use crate::rate_limit::Limiter;
+use crate::shadowsocks;
+use crate::tunnel;
+use crate::utilities::{other,timeout,Cidr,TimeoutKind};
+pub use crate::shadowsocks::Cipher;
+pub use crate::tunnel::Key;
+pub use crate::utilities::BindRange;
 
-use endpoint::{transfer,new_tcpendpoint};
+use crate::endpoint::{Connection,Direction,Endpoint,RelayOptions};
+
+/// What clients of a listener speak.
+pub enum Protocol {
+    Socks5,
+    /// The remote end of a tunnel, see the `tunnel` module. Clients may
+    /// expose services on the addresses in `reverse`, see the `reverse`
+    /// module.
+    Tunnel { key: Key, reverse: Vec<BindRange> },
+    /// A shadowsocks server.
+    Shadowsocks(Arc<Cipher>),
+    /// Connections redirected by the firewall, whose targets are known
+    /// once they are accepted. See the `transparent` module.
+    Transparent,
+    /// Clients which all go to the same target.
+    Forward(TargetAddr)
+}
+
+/// How a listener serves its clients, shared by all of them.
+pub struct Settings {
+    pub protocol: Protocol,
+    /// Picks the connector of each session, see `Connector`.
+    pub router: Arc<dyn Router>,
+    pub timeouts: Timeouts,
+    pub relay: RelayOptions,
+    /// Peers whose connections start with a PROXY protocol header.
+    pub(crate) trusted_proxies: Vec<Cidr>,
+    /// Checks the credentials of SOCKS5 clients, which don't need any
+    /// without one.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    pub hooks: Option<Arc<dyn SessionHooks>>,
+    /// Where a record of each session goes, see the `access_log` module.
+    pub(crate) access_log: Option<Arc<AccessLog>>,
+    /// Holds clients to the rates of the listener, see the `rate_limit`
+    /// module.
+    pub(crate) limiter: Option<Limiter>,
+    /// Bytes users may relay, see the `quota` module.
+    pub(crate) quotas: Option<Arc<Quotas>>,
+    /// Caps on the sessions of clients, see the `conn_limit` module.
+    pub(crate) connection_limiter: Option<ConnectionLimiter>,
+    /// Failed logins and the bans they led to, see the `lockout` module.
+    pub(crate) lockout: Option<Arc<Lockout>>
+}
+
+impl Settings {
+    /// Settings without any of the optional parts.
+    pub fn new(protocol: Protocol, router: Arc<dyn Router>, timeouts: Timeouts,
+               relay: RelayOptions) -> Settings
+    {
+        Settings {
+            protocol,
+            router,
+            timeouts,
+            relay,
+            trusted_proxies: Vec::new(),
+            authenticator: None,
+            hooks: None,
+            access_log: None,
+            limiter: None,
+            quotas: None,
+            connection_limiter: None,
+            lockout: None
+        }
+    }
+}
+
+/// Who a session is for, as far as its target is told.
+#[derive(Clone)]
+pub struct Origin {
+    pub addr: SocketAddr,
+    /// The user the client logged in as.
+    pub user: Option<String>
+}
 
 // Data used to when processing a client to perform various operations over its
 // lifetime.
-pub struct Client {
-    conn: Option<TcpStream>,
-    //buffer: RcBuffer,
-    //dns: BasicClientHandle,
-    handle: Handle,
-    addr: SocketAddr
+pub struct Client<S = TcpStream> {
+    conn: S,
+    addr: SocketAddr,
+    // Where the client goes, if that was settled without asking it.
+    target: Option<TargetAddr>,
+    // Counts the session against the caps of the listener while it lasts.
+    permit: Option<Permit>,
+    settings: Arc<Settings>
 }
 
-impl Client {
+impl<S: Connection> Client<S> {
     pub fn get_addr(&self) -> SocketAddr{
         self.addr
     }
-    pub fn new(s: TcpStream, h: &Handle, a: SocketAddr) -> Client {
-        Client { conn:Some(s), handle: h.clone(), addr: a }
+    pub fn settings(&self) -> &Arc<Settings> {
+        &self.settings
+    }
+    pub fn new(s: S, a: SocketAddr, settings: Arc<Settings>) -> Client<S> {
+        Client { conn: s, addr: a, target: None, permit: None, settings }
+    }
+    /// The same client over a connection layered on top of this one.
+    pub fn map<T: Connection>(self, f: impl FnOnce(S) -> T) -> Client<T> {
+        Client {
+            conn: f(self.conn),
+            addr: self.addr,
+            target: self.target,
+            permit: self.permit,
+            settings: self.settings
+        }
+    }
+    /// The same client, counted against the caps of the listener until it
+    /// is done.
+    pub(crate) fn with_permit(self, permit: Option<Permit>) -> Client<S> {
+        Client { permit, ..self }
+    }
+    /// The same client, going to `target` without any handshake.
+    pub fn with_target(self, target: TargetAddr) -> Client<S> {
+        Client { target: Some(target), ..self }
     }
     /// This is the main entry point for starting a SOCKS proxy connection.
     ///
-    /// This function is responsible for constructing the future which
-    /// represents the final result of the proxied connection. In this case
-    /// we're going to return an `IoFuture<T>`, an alias for
-    /// `Future<Item=T, Error=io::Error>`, which indicates how many bytes were
-    /// proxied on each half of the connection.
+    /// This function drives the whole life of the proxied connection and
+    /// resolves to how many bytes were proxied on each half of the
+    /// connection.
     ///
     /// The first part of the SOCKS protocol with a remote connection is for the
     /// server to read one byte, indicating the version of the protocol. The
-    /// `read_exact` combinator is used here to entirely fill the specified
+    /// `read_exact` method is used here to entirely fill the specified
     /// buffer, and we can use it to conveniently read off one byte here.
     ///
     /// Once we've got the version byte, we then delegate to the below
     /// `serve_vX` methods depending on which version we found.
-    pub fn serve(mut self)
-              -> impl Future<Item=(u64, u64), Error=io::Error> {
-        let conn = self.conn.unwrap();
-        self.conn = None;
-        read_exact(conn, [0u8]).and_then(move |(conn, buf)| {
-            match buf[0] {
-                v5::VERSION => Left(self.serve_v5(conn)),
+    ///
+    /// The hooks of the listener, if any, are told about the session as it
+    /// goes, and how it ended.
+    pub async fn serve(self) -> io::Result<(u64, u64)> {
+        let mut origin = Origin { addr: self.addr, user: None };
+        let serve = serve_client(self.conn, self.target, &mut origin, &self.settings);
+        let (res, mut record) = access_log::collect(serve).await;
+        if let Some(ref hooks) = self.settings.hooks {
+            hooks.on_close(&origin, &res);
+        }
+        if let Some(ref log) = self.settings.access_log {
+            record.close(&origin, &res);
+            log.write(&record);
+        }
+        drop(self.permit);
+        res
+    }
+}
 
-                // If we hit an unknown version, we return a "terminal future"
-                // which represents that this future has immediately failed. In
-                // this case the type of the future is `io::Error`, so we use a
-                // helper function, `other`, to create an error quickly.
-                //
-                // As version 4 was not supported, we change the word to "unsupported version".
-                _ => Right(future::err(other("unsupported version"))),
-            }
-        })
+async fn serve_client<S: Connection>(mut conn: S, target: Option<TargetAddr>, origin: &mut Origin,
+                                     settings: &Arc<Settings>)
+    -> io::Result<(u64, u64)>
+{
+    match settings.protocol {
+        Protocol::Tunnel { ref key, ref reverse } => {
+            return tunnel::serve(conn, origin, key, reverse, settings).await;
+        }
+        Protocol::Shadowsocks(ref cipher) => {
+            return shadowsocks::serve(conn, origin, cipher, settings).await;
+        }
+        Protocol::Transparent => {
+            let target = target.ok_or_else(|| other("no original destination"))?;
+            return serve_fixed(conn, origin, target, settings).await;
+        }
+        Protocol::Forward(ref target) => {
+            return serve_fixed(conn, origin, target.clone(), settings).await;
+        }
+        Protocol::Socks5 => {}
     }
+    let mut buf = [0u8];
+    let version = conn.read_exact(&mut buf);
+    timeout(settings.timeouts.handshake(), version, TimeoutKind::Handshake).await?;
+    match buf[0] {
+        v5::VERSION => serve_v5(conn, origin, settings).await,
 
-    /// The meat of a SOCKSv5 handshake.
-    ///
-    /// This method will construct a future chain that will perform the entire
-    /// suite of handshakes, and at the end if we've successfully gotten that
-    /// far we'll initiate the proxying between the two sockets.
-    ///
-    /// As a side note, you'll notice a number of `.boxed()` annotations here to
-    /// box up intermediate futures. From a library perspective, this is not
-    /// necessary, but without them the compiler is pessimistically slow!
-    /// Essentially, the `.boxed()` annotations here improve compile times, but
-    /// are otherwise not necessary.
-    fn serve_v5(self, conn: TcpStream)
-                -> impl Future<Item=(u64, u64), Error=io::Error> {
-        // First part of the SOCKSv5 protocol is to negotiate a number of
-        // "methods". These methods can typically be used for various kinds of
-        // proxy authentication and such, but for this server we only implement
-        // the `METH_NO_AUTH` method, indicating that we only implement
-        // connections that work with no authentication.
+        // If we hit an unknown version, we immediately fail. The error
+        // type is `io::Error`, so we use a helper function, `other`, to
+        // create an error quickly.
         //
-        // First here we do the same thing as reading the version byte, we read
-        // a byte indicating how many methods. Afterwards we then read all the
-        // methods into a temporary buffer.
+        // As version 4 was not supported, we change the word to "unsupported version".
+        _ => Err(other("unsupported version")),
+    }
+}
+
+/// The meat of a SOCKSv5 handshake.
+///
+/// This function performs the entire suite of handshakes, and at the end if
+/// we've successfully gotten that far we'll initiate the proxying between
+/// the two sockets.
+async fn serve_v5<S: Connection>(mut conn: S, origin: &mut Origin, settings: &Settings)
+    -> io::Result<(u64, u64)>
+{
+    debug!("connected! SOCKS5");
+    let timeouts = &settings.timeouts;
+
+    let request = async {
+        // First part of the SOCKSv5 protocol is to negotiate a number of
+        // "methods". These methods can typically be used for various kinds
+        // of proxy authentication and such, but for this server we only
+        // implement the `METH_NO_AUTH` method, indicating that we only
+        // implement connections that work with no authentication, and the
+        // `METH_USER_PASS` method, which is required instead if the listener
+        // has an authenticator.
         //
-        // Note that we use `and_then` here to chain computations after one
-        // another, but it also serves to simply have fallible computations,
-        // such as checking whether the list of methods contains `METH_NO_AUTH`.
-        debug!("connected! SOCKS5");
+        // First here we do the same thing as reading the version byte, we
+        // read a byte indicating how many methods. Afterwards we then read
+        // all the methods into a temporary buffer.
+        let mut buf = [0u8];
+        conn.read_exact(&mut buf).await?;
+        debug!("number of methods: {}", buf[0]);
+        let mut methods = vec![0u8; buf[0] as usize];
+        conn.read_exact(&mut methods).await?;
+        let method = match settings.authenticator {
+            Some(_) => v5::METH_USER_PASS,
+            None => v5::METH_NO_AUTH,
+        };
+        if !methods.contains(&method) {
+            conn.write_all(&[v5::VERSION, v5::METH_NO_ACCEPTABLE]).await?;
+            return Err(other("no supported method given"));
+        }
 
-        let num_methods = read_exact(conn, [0u8]);
-        let authenticated = num_methods.and_then(|(conn, buf)| {
-            debug!("number of methods: {}", buf[0]);
-            read_exact(conn, vec![0u8; buf[0] as usize])
-        }).and_then(|(conn, buf)| {
-            if buf.contains(&v5::METH_NO_AUTH) {
-                debug!("authenticated!");
-                Ok(conn)
-            } else {
-                Err(other("no supported method given"))
-            }
-        });
+        // After we've concluded that one of the client's supported methods
+        // is the one we want, we "ack" this to the client by sending back
+        // that information.
+        conn.write_all(&[v5::VERSION, method]).await?;
+        if let Some(ref authenticator) = settings.authenticator {
+            let user = authenticate(&mut conn, origin.addr, &**authenticator, settings).await?;
+            origin.user = Some(user);
+        }
+        debug!("authenticated!");
+        // A user over its caps is told so in the reply to its request.
+        let permit = match (&settings.connection_limiter, &origin.user) {
+            (Some(limiter), Some(user)) => Some(limiter.admit_user(user)),
+            _ => None,
+        };
 
-        // After we've concluded that one of the client's supported methods is
-        // `METH_NO_AUTH`, we "ack" this to the client by sending back that
-        // information. Here we make use of the `write_all` combinator which
-        // works very similarly to the `read_exact` combinator.
-        let part1 = authenticated.and_then(|conn| {
-            write_all(conn, [v5::VERSION, v5::METH_NO_AUTH])
-        });
+        // Next up, we get a selected protocol version back from the client,
+        // as well as a command indicating what they'd like to do. We just
+        // verify that the version is still v5, and then we only implement
+        // the "connect" command so we ensure the proxy sends that.
+        confirm_v5(&mut conn).await?;
+        Ok((parse_command(&mut conn).await?, permit))
+    };
 
-        // Next up, we get a selected protocol version back from the client, as
-        // well as a command indicating what they'd like to do. We just verify
-        // that the version is still v5, and then we only implement the
-        // "connect" command so we ensure the proxy sends that.
-        //
-        // As above, we're using `and_then` not only for chaining "blocking
-        // computations", but also to perform fallible computations.
-        let ack = part1.and_then(|(conn, _)| confirm_v5(conn));
-        let addr = ack.and_then(parse_command);
-        let handle = self.handle.clone();
-        let connected = addr.and_then(move |(c, addr)| connect_target(c, addr, handle));
-        let handshake_finish = connected.and_then(|(c1,c2,addr)| final_response(c1,c2,addr));
-        
+    // In order to handle ill-behaved clients, however, we have an added
+    // feature here where we'll time out any client and any connect
+    // operation which take too long. A target which can't be reached in
+    // time still gets a reply sent back to the client.
+    let (addr, permit) = timeout(timeouts.handshake(), request, TimeoutKind::Handshake).await?;
+    let (c2, _permit) = match permit {
+        Some(Err(e)) => (Err(e), None),
+        permit => (connect_target(settings, origin, addr).await, permit),
+    };
+    let reply = final_response(&mut conn, c2);
+    let c2 = timeout(timeouts.handshake(), reply, TimeoutKind::Handshake).await?;
 
-        // Phew! If you've gotten this far, then we're now entirely done with
-        // the entire SOCKSv5 handshake!
-        //
-        // In order to handle ill-behaved clients, however, we have an added
-        // feature here where we'll time out any initial connect operations
-        // which take too long.
-        //
-        let pair = timeout(&self.handle, handshake_finish, "timeout during handshake");
+    // Phew! If you've gotten this far, then we're now entirely done with
+    // the entire SOCKSv5 handshake!
 
-        // At this point we've *actually* finished the handshake. Not only have
-        // we read/written all the relevant bytes, but we've also managed to
-        // complete in under our allotted timeout.
-        //
-        // At this point the remainder of the SOCKSv5 proxy is shuttle data back
-        // and for between the two connections. That is, data is read from `c1`
-        // and written to `c2`, and vice versa.
-        //
-        // To accomplish this, we put both sockets into their own `Rc` and then
-        // create two independent `Transfer` futures representing each half of
-        // the connection. These two futures are `join`ed together to represent
-        // the proxy operation happening.
-        let result = pair.and_then(|(c1, c2)| 
-            transfer(new_tcpendpoint(c1), new_tcpendpoint(c2)));
-        //print_type_info("result", &result);
-        result
-    }    
+    // At this point we've *actually* finished the handshake. Not only have
+    // we read/written all the relevant bytes, but we've also managed to
+    // complete in under our allotted timeout.
+    //
+    // At this point the remainder of the SOCKSv5 proxy is shuttle data back
+    // and for between the two connections. That is, data is read from `conn`
+    // and written to `c2`, and vice versa.
+    relay_outbound(conn.into_endpoint(), c2, origin, settings).await
 }
 
-fn parse_command(conn:TcpStream)
-    -> impl Future<Item=(TcpStream, SocketAddr), Error=io::Error>
+// The username/password negotiation of RFC 1929, which resolves to the name
+// of the user. The hooks have the last word on whether it logged in.
+async fn authenticate<S>(conn: &mut S, addr: SocketAddr, authenticator: &dyn Authenticator,
+                         settings: &Settings)
+    -> io::Result<String>
+    where S: AsyncRead + AsyncWrite + Unpin
 {
-    let command = read_exact(conn, [0u8]).and_then(|(conn, buf)| {
-        debug!("cmd {}", buf[0]);
-        if buf[0] == v5::CMD_CONNECT {
-            Ok(conn)
-        } else {
-            Err(other("unsupported command"))
+    let mut buf = [0u8];
+    conn.read_exact(&mut buf).await?;
+    if buf[0] != v5::USER_PASS_VERSION {
+        return Err(other("unsupported username/password version"));
+    }
+    conn.read_exact(&mut buf).await?;
+    let mut user = vec![0u8; buf[0] as usize];
+    conn.read_exact(&mut user).await?;
+    conn.read_exact(&mut buf).await?;
+    let mut password = vec![0u8; buf[0] as usize];
+    conn.read_exact(&mut password).await?;
+
+    let user = String::from_utf8(user).map_err(|_| other("username is not valid utf-8"))?;
+    let password = String::from_utf8_lossy(&password);
+    // Banned clients fail without their password being checked.
+    let banned = settings.lockout.as_ref().map_or(Ok(()), |l| l.check(addr.ip(), &user));
+    let mut ok = false;
+    if banned.is_ok() {
+        ok = authenticator.verify(&user, &password).await?;
+        if let Some(ref hooks) = settings.hooks {
+            ok = hooks.on_auth(addr, &user, ok);
         }
-    });
+        match settings.lockout {
+            Some(ref lockout) if ok => lockout.succeeded(&user),
+            Some(ref lockout) => sleep(lockout.failed(addr.ip(), &user)).await,
+            None => {}
+        }
+    }
+    conn.write_all(&[v5::USER_PASS_VERSION, if ok { 0 } else { 1 }]).await?;
+    conn.flush().await?;
+    banned?;
+    if !ok {
+        return Err(other(&format!("authentication failed for {}", user)));
+    }
+    Ok(user)
+}
 
-    let resv = command.and_then(|c| read_exact(c, [0u8]).map(|c| c.0));
-    let atyp = resv.and_then(|c| read_exact(c, [0u8]));
-    atyp.and_then(move |(c, buf)| parse_addr(c, buf))
+async fn parse_command<S>(conn: &mut S) -> io::Result<TargetAddr>
+    where S: AsyncRead + Unpin
+{
+    let mut buf = [0u8];
+    conn.read_exact(&mut buf).await?;
+    debug!("cmd {}", buf[0]);
+    if buf[0] != v5::CMD_CONNECT {
+        return Err(other("unsupported command"));
+    }
+
+    // reserved byte
+    conn.read_exact(&mut buf).await?;
+    // address type
+    conn.read_exact(&mut buf).await?;
+    parse_addr(conn, buf[0]).await
 }
 
-fn confirm_v5(conn:TcpStream)
-    -> impl Future<Item=TcpStream, Error=io::Error>
+async fn confirm_v5<S>(conn: &mut S) -> io::Result<()>
+    where S: AsyncRead + Unpin
 {
-    read_exact(conn, [0u8]).and_then(|(conn, buf)| {
-        debug!("ack {}", buf[0]);
-        if buf[0] == v5::VERSION {
-            Ok(conn)
-        } else {
-            Err(other("didn't confirm with v5 version"))
-        }
-    })
+    let mut buf = [0u8];
+    conn.read_exact(&mut buf).await?;
+    debug!("ack {}", buf[0]);
+    if buf[0] == v5::VERSION {
+        Ok(())
+    } else {
+        Err(other("didn't confirm with v5 version"))
+    }
 }
 
-// After we've negotiated a command, there's one byte which is reserved
-// for future use, so we read it and discard it. The next part of the
-// protocol is to read off the address that we're going to proxy to.
-// This address can come in a number of forms, so we read off a byte
-// which indicates the address type (ATYP).
-//
-// Depending on the address type, we then delegate to different futures
-// to implement that particular address format.
-//let mut dns = self.dns.clone();
-fn parse_addr(c:TcpStream, buf:[u8; 1])
-    -> impl Future<Item=(TcpStream, SocketAddr), Error=io::Error>
+/// Relays a client whose target was known before it said anything.
+async fn serve_fixed<S: Connection>(conn: S, origin: &Origin, target: TargetAddr,
+                                    settings: &Settings)
+    -> io::Result<(u64, u64)>
 {
-    debug!("addr type: {}", buf[0]);
-    match buf[0] {
-        // For IPv4 addresses, we read the 4 bytes for the address as
-        // well as 2 bytes for the port.
-        v5::ATYP_IPV4 => {
-            Left(Left(read_exact(c, [0u8; 6]).map(|(c, buf)| {
-                let addr = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
-                let port = ((buf[4] as u16) << 8) | (buf[5] as u16);
-                let addr = SocketAddrV4::new(addr, port);
-                (c, SocketAddr::V4(addr))
-            })))
-        }
+    let c2 = connect_target(settings, origin, target).await?;
+    relay_outbound(conn.into_endpoint(), c2, origin, settings).await
+}
 
-        // For IPv6 addresses there's 16 bytes of an address plus two
-        // bytes for a port, so we read that off and then keep going.
-        v5::ATYP_IPV6 => {
-            Left(Right(read_exact(c, [0u8; 18]).map(|(conn, buf)| {
-                let a = ((buf[0] as u16) << 8) | (buf[1] as u16);
-                let b = ((buf[2] as u16) << 8) | (buf[3] as u16);
-                let c = ((buf[4] as u16) << 8) | (buf[5] as u16);
-                let d = ((buf[6] as u16) << 8) | (buf[7] as u16);
-                let e = ((buf[8] as u16) << 8) | (buf[9] as u16);
-                let f = ((buf[10] as u16) << 8) | (buf[11] as u16);
-                let g = ((buf[12] as u16) << 8) | (buf[13] as u16);
-                let h = ((buf[14] as u16) << 8) | (buf[15] as u16);
-                let addr = Ipv6Addr::new(a, b, c, d, e, f, g, h);
-                let port = ((buf[16] as u16) << 8) | (buf[17] as u16);
-                let addr = SocketAddrV6::new(addr, port, 0, 0);
-                (conn, SocketAddr::V6(addr))
-            })))
+/// Connects to `addr` for a client from `origin` within the connect
+/// timeout, and tells the target who that is if the listener is meant to.
+///
+/// Users over their quota are refused. The hooks of the listener may refuse
+/// the target or send the client elsewhere first, and are told how it went.
+pub async fn connect_target(settings: &Settings, origin: &Origin, mut addr: TargetAddr)
+    -> io::Result<Outbound>
+{
+    let hooks = settings.hooks.as_deref();
+    let allowed = match account(settings, origin) {
+        Some(account) if account.exceeded() => {
+            let msg = format!("{} is over quota", origin.user.as_deref().unwrap_or_default());
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, msg))
         }
-
-        // The SOCKSv5 protocol not only supports proxying to specific
-        // IP addresses, but also arbitrary hostnames. This allows
-        // clients to perform hostname lookups within the context of the
-        // proxy server rather than the client itself.
-        //
-        // Since the first publication of this code, several
-        // futures-based DNS libraries appeared, and as a demonstration
-        // of integrating third-party asynchronous code into our chain,
-        // we will use one of them, TRust-DNS.
-        //
-        // The protocol here is to have the next byte indicate how many
-        // bytes the hostname contains, followed by the hostname and two
-        // bytes for the port. To read this data, we execute two
-        // respective `read_exact` operations to fill up a buffer for
-        // the hostname.
-        //
-        // Finally, to perform the "interesting" part, we process the
-        // buffer and pass the retrieved hostname to a query future if
-        // it wasn't already recognized as an IP address. The query is
-        // very basic: it asks for an IPv4 address with a timeout of
-        // five seconds. We're using TRust-DNS at the protocol level,
-        // so we don't have the functionality normally expected from a
-        // stub resolver, such as sorting of answers according to RFC
-        // 6724, more robust timeout handling, or resolving CNAME
-        // lookups.
-        v5::ATYP_DOMAIN => {
-            debug!("domain!");
-            Right(Left(read_exact(c, [0u8]).and_then(|(conn, buf)| {
-                read_exact(conn, vec![0u8; buf[0] as usize + 2])
-            }).and_then(move |(conn, buf)| {                        
-                match name_port(&buf) {
-                    Ok(ad) => Left(future::ok((conn,ad))),
-                    Err(e) => Right(future::err(e)),
-                }
-            })))
+        _ => hooks.map_or(Ok(()), |hooks| hooks.on_target(origin, &mut addr)),
+    };
+    let res = match allowed {
+        Ok(()) => {
+            let connect = connect_routed(settings, origin, &addr);
+            timeout(settings.timeouts.connect(), connect, TimeoutKind::Connect).await
         }
-
-        n => {
-            let msg = format!("unknown ATYP received: {}", n);
-            Right(Right(future::err(other(&msg))))
+        Err(e) => Err(e),
+    };
+    if let Some(hooks) = hooks {
+        match res {
+            Ok(_) => hooks.on_connected(origin, &addr),
+            Err(ref e) => hooks.on_connect_failed(origin, &addr, e),
         }
     }
+    access_log::note(|r| {
+        r.target_addr = res.as_ref().ok().and_then(|out| out.peer_addr());
+        r.reply = Some(reply_code(&res));
+        r.target = Some(addr);
+    });
+    res
 }
 
-// Now that we've got a socket address to connect to, let's actually
-// create a connection to that socket!
+// Now that we've got an address to connect to, let's actually create a
+// connection to it!
 //
-// To do this, we use our `handle` field, a handle to the event loop, to
-// issue a connection to the address we've figured out we're going to
-// connect to. Note that this `tcp_connect` method itself returns a
-// future resolving to a `TcpStream`, representing how long it takes to
-// initiate a TCP connection to the remote.
+// To do this, we hand the address to the connector the router picks for
+// it, which either dials it itself or asks an upstream server to do so.
+// The future resolves once the outbound connection is established.
 //
-// We wait for the TCP connect to get fully resolved before progressing
+// We wait for the connect to get fully resolved before progressing
 // to the next stage of the SOCKSv5 handshake, but we keep ahold of any
 // possible error in the connection phase to handle it in a moment.
-fn connect_target(c:TcpStream, addr:SocketAddr, handle: Handle)
-    -> impl Future<Item=(TcpStream, Result<TcpStream,io::Error>, SocketAddr), Error=io::Error>
+async fn connect_routed(settings: &Settings, origin: &Origin, addr: &TargetAddr)
+    -> io::Result<Outbound>
 {
-    debug!("proxying to {}", addr);
-    TcpStream::connect(&addr, &handle).then(move |c2| Ok((c, c2, addr)))
+    debug!("connecting to {}", addr);
+    let connector = settings.router.route(origin, addr)?;
+    access_log::note(|r| r.route = Some(connector.name()));
+    connector.connect(origin, addr).await
+}
+
+// The quota of the user of a session, if it has one.
+fn account(settings: &Settings, origin: &Origin) -> Option<Arc<Account>> {
+    settings.quotas.as_ref()?.account(origin.user.as_deref()?)
+}
+
+/// Relays between the client `ep` and its target `out` within the rate
+/// limits of the listener, counting the bytes against the quota of the
+/// user and telling the hooks about them as they go.
+pub async fn relay_outbound(ep: impl Endpoint, out: Outbound, origin: &Origin, settings: &Settings)
+    -> io::Result<(u64, u64)>
+{
+    let account = account(settings, origin);
+    let (up, down) = (AtomicU64::new(0), AtomicU64::new(0));
+    let progress = |direction, n| {
+        match direction {
+            Direction::Up => up.fetch_add(n, Ordering::Relaxed),
+            Direction::Down => down.fetch_add(n, Ordering::Relaxed),
+        };
+        if let Some(ref account) = account {
+            account.add(n);
+        }
+        if let Some(ref hooks) = settings.hooks {
+            hooks.on_bytes(origin, direction, n);
+        }
+    };
+    let relay = async {
+        match settings.limiter {
+            Some(ref limiter) => {
+                let ep = limiter.limit(ep, origin);
+                transfer_outbound(ep, out, &settings.relay, Some(&progress)).await
+            }
+            None => transfer_outbound(ep, out, &settings.relay, Some(&progress)).await,
+        }
+    };
+    let res = match account {
+        Some(ref account) if settings.quotas.as_ref().is_some_and(|q| q.cut()) => {
+            tokio::select! {
+                res = relay => res,
+                _ = account.exhausted() => Err(other("quota exceeded")),
+            }
+        }
+        _ => relay.await,
+    };
+    // Counted as they go, as sessions which fail don't tell.
+    access_log::note(|r| {
+        r.bytes_up += up.into_inner();
+        r.bytes_down += down.into_inner();
+    });
+    res
 }
 
 // Once we've gotten to this point, we're ready for the final part of
@@ -294,27 +485,11 @@ fn connect_target(c:TcpStream, addr:SocketAddr, handle: Handle)
 // going to proxy data to, so we write out relevant information to the
 // original client (c1) the "response packet" which is the final part of
 // this handshake.
-fn final_response(c1:TcpStream, c2:Result<TcpStream,io::Error>, addr:SocketAddr) 
-    -> impl Future<Item=(TcpStream, TcpStream), Error=io::Error> 
+async fn final_response<S>(c1: &mut S, c2: io::Result<Outbound>) -> io::Result<Outbound>
+    where S: AsyncWrite + Unpin
 {
-    let mut resp = [0u8; 32];
-
-    // VER - protocol version
-    resp[0] = 5;
-
-    // REP - "reply field" -- what happened with the actual connect.
-    //
-    // In theory this should reply back with a bunch more kinds of
-    // errors if possible, but for now we just recognize a few concrete
-    // errors.
-    resp[1] = match c2 {
-        Ok(..) => 0,
-        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => 5,
-        Err(..) => 1,
-    };
-
-    // RSV - reserved
-    resp[2] = 0;
+    // VER - protocol version, REP - reply field and RSV - reserved
+    let mut resp = vec![v5::VERSION, reply_code(&c2), 0];
 
     // ATYP, BND.ADDR, and BND.PORT
     //
@@ -323,61 +498,15 @@ fn final_response(c1:TcpStream, c2:Result<TcpStream,io::Error>, addr:SocketAddr)
     // connection was bound to remotely. There's a variable length
     // encoding of what's actually written depending on whether we're
     // using an IPv4 or IPv6 address, but otherwise it's pretty
-    // standard.
-    let addr = match c2.as_ref().map(|r| r.local_addr()) {
-        Ok(Ok(addr)) => addr,
-        Ok(Err(..)) |
-        Err(..) => addr,
-    };
-    let pos = match addr {
-        SocketAddr::V4(ref a) => {
-            resp[3] = 1;
-            resp[4..8].copy_from_slice(&a.ip().octets()[..]);
-            8
-        }
-        SocketAddr::V6(ref a) => {
-            resp[3] = 4;
-            let mut pos = 4;
-            for &segment in a.ip().segments().iter() {
-                resp[pos] = (segment >> 8) as u8;
-                resp[pos + 1] = segment as u8;
-                pos += 2;
-            }
-            pos
-        }
-    };
-    resp[pos] = (addr.port() >> 8) as u8;
-    resp[pos + 1] = addr.port() as u8;
+    // standard. When the connection was made by somebody else we don't
+    // know it, and all zeroes are sent.
+    let bound = c2.as_ref().ok().and_then(|c2| c2.local_addr())
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    write_addr(&mut resp, &TargetAddr::Ip(bound))?;
 
-    // Slice our 32-byte `resp` buffer to the actual size, as it's
-    // variable depending on what address we just encoding. Once that's
-    // done, write out the whole buffer to our client.
-    //
-    // The returned type of the future here will be `(TcpStream,
-    // TcpStream)` representing the client half and the proxy half of
-    // the connection.
-    let mut w = Window::new(resp);
-    w.set_end(pos + 2);
-    write_all(c1, w).and_then(|(c1, _)| {
-        c2.map(|c2| (c1, c2))
-    })
+    // Write out the whole buffer to our client. The returned value is the
+    // proxy half of the connection.
+    c1.write_all(&resp).await?;
+    c1.flush().await?;
+    c2
 }
-
-// Various constants associated with the SOCKS protocol
-
-#[allow(dead_code)]
-mod v5 {
-    pub const VERSION: u8 = 5;
-
-    pub const METH_NO_AUTH: u8 = 0;
-    pub const METH_GSSAPI: u8 = 1;
-    pub const METH_USER_PASS: u8 = 2;
-
-    pub const CMD_CONNECT: u8 = 1;
-    pub const CMD_BIND: u8 = 2;
-    pub const CMD_UDP_ASSOCIATE: u8 = 3;
-
-    pub const ATYP_IPV4: u8 = 1;
-    pub const ATYP_IPV6: u8 = 4;
-    pub const ATYP_DOMAIN: u8 = 3;
-}
\ No newline at end of file
@@ -1,53 +1,315 @@
-use tokio_core::reactor::Handle;
-use futures::Async::{Ready,NotReady};
-use futures::Async;
-use client::Client;
-use futures::Stream;
-use tokio_core::net::Incoming;
+use crate::client::{Client, Settings};
+use crate::config::{TlsConfig, WebSocketConfig};
+use crate::conn_limit::Permit;
+#[cfg(target_os = "linux")]
+use crate::connector::TargetAddr;
+use crate::endpoint::Connection;
+use crate::metrics::{self, METRICS};
+use crate::tls::{self, TlsConnection};
+#[cfg(target_os = "linux")]
+use crate::transparent;
+use crate::proxy_protocol;
+use crate::utilities::{other, timeout, timeout_kind, TimeoutKind};
+use crate::websocket::WsConnection;
+use std::future::Future;
 use std::net::SocketAddr;
-use tokio_core::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 use std::io;
+use std::sync::Arc;
 
 pub trait ClientChannel {
-    type OutputStream: Stream<Item=Client, Error=io::Error>;
-    fn clients(self, handle:&Handle) -> Self::OutputStream;
+    type Connection: Connection;
+    fn accept(&mut self) -> impl Future<Output=io::Result<Client<Self::Connection>>> + Send;
 }
 
-pub fn listen_tcp(addr: &SocketAddr, handle:&Handle) -> io::Result<impl ClientChannel> {
-    TcpListenerChannel::new(addr, handle)
+pub async fn listen_tcp(addr: &SocketAddr, settings: Arc<Settings>)
+    -> io::Result<impl ClientChannel>
+{
+    TcpListenerChannel::new(addr, settings).await
 }
 
-struct TcpClientStream {
-    s: Incoming,
-    h: Handle
+/// Clients of a listener which is already bound.
+pub fn tcp_channel(listener: TcpListener, settings: Arc<Settings>) -> impl ClientChannel + Send {
+    TcpListenerChannel::with_listener(listener, settings)
 }
 
 struct TcpListenerChannel {
-    listener: TcpListener
+    listener: TcpListener,
+    settings: Arc<Settings>,
+    // Connections of trusted proxies whose PROXY header is still being
+    // read, each in a task of its own so a slow one holds up nobody else.
+    pending: JoinSet<io::Result<(TcpStream, SocketAddr)>>
 }
 
 impl TcpListenerChannel {
-    fn new(addr: &SocketAddr, handle:&Handle) -> io::Result<TcpListenerChannel> {
-        TcpListener::bind(addr, handle).map(|l| TcpListenerChannel { listener: l })
+    async fn new(addr: &SocketAddr, settings: Arc<Settings>) -> io::Result<TcpListenerChannel> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(TcpListenerChannel::with_listener(listener, settings))
+    }
+
+    fn with_listener(listener: TcpListener, settings: Arc<Settings>) -> TcpListenerChannel {
+        TcpListenerChannel { listener, settings, pending: JoinSet::new() }
+    }
+
+    /// The next connection within the caps of the listener, the address of
+    /// the client behind it and what counts it against the caps.
+    async fn accept_tcp(&mut self) -> io::Result<(TcpStream, SocketAddr, Option<Permit>)> {
+        loop {
+            let (c, a) = tokio::select! {
+                res = self.listener.accept() => {
+                    let (mut c, a) = res?;
+                    if !self.settings.trusted_proxies.iter().any(|n| n.contains(a.ip())) {
+                        (c, a)
+                    } else {
+                        let limit = self.settings.timeouts.handshake();
+                        self.pending.spawn(async move {
+                            let header = proxy_protocol::read_header(&mut c);
+                            let source = timeout(limit, header, TimeoutKind::Handshake).await
+                                .map_err(|e| other(&format!("{} from proxy {}", e, a)))?;
+                            debug!("proxy {} relays {:?}", a, source);
+                            Ok((c, source.unwrap_or(a)))
+                        });
+                        continue;
+                    }
+                }
+                Some(res) = self.pending.join_next() => {
+                    res.map_err(|e| other(&e.to_string()))??
+                }
+            };
+            if let Some(permit) = self.admit(&c, a) {
+                return Ok((c, a, permit));
+            }
+        }
     }
-}
 
-impl Stream for TcpClientStream {
-    type Item = Client;
-    type Error = io::Error;
-    fn poll(&mut self) -> io::Result<Async<Option<Client>>> {
-        match self.s.poll() {
-            Ok(Ready(Some((c,a)))) => Ok(Ready(Some(Client::new(c,&self.h,a)))),
-            Ok(Ready(None)) => Ok(Ready(None)),
-            Ok(NotReady) => Ok(NotReady),
-            Err(e) => Err(e)
+    /// Counts the connection of the client from `a` against the caps of
+    /// the listener, or resets it if it is over them or the address is
+    /// banned.
+    fn admit(&self, c: &TcpStream, a: SocketAddr) -> Option<Option<Permit>> {
+        let lockout = self.settings.lockout.as_ref();
+        let res = lockout.map_or(Ok(()), |l| l.check_addr(a.ip())).and_then(|()| {
+            self.settings.connection_limiter.as_ref().map(|l| l.admit(a.ip())).transpose()
+        });
+        match res {
+            Ok(permit) => Some(permit),
+            Err(e) => {
+                debug!("reset {}: {}", a, e);
+                metrics::add(&METRICS.refused_connections, 1);
+                // Closing it without lingering sends a RST rather than a FIN.
+                let _ = c.set_zero_linger();
+                None
+            }
         }
     }
 }
 
 impl ClientChannel for TcpListenerChannel {
-    type OutputStream = TcpClientStream;
-    fn clients(self, handle:&Handle) -> TcpClientStream {
-        TcpClientStream { s: self.listener.incoming(), h:handle.clone() }
+    type Connection = TcpStream;
+    async fn accept(&mut self) -> io::Result<Client> {
+        let (c, a, permit) = self.accept_tcp().await?;
+        Ok(Client::new(c, a, self.settings.clone()).with_permit(permit))
+    }
+}
+
+// This is our server loop. For all incoming connections, those received
+// from `channel`, we get an instance of `Client` and convert it to a
+// future representing the completion of handling that client. This
+// future itself is then *spawned* onto the runtime to ensure that it can
+// progress concurrently with all other connections.
+pub async fn serve(mut channel: impl ClientChannel + Send + 'static) {
+    loop {
+        let client = match channel.accept().await {
+            Ok(client) => client,
+            Err(e) => {
+                error!("error accepting client: {}", e);
+                continue;
+            }
+        };
+        let addr = client.get_addr();
+        let hooks = client.settings().hooks.clone();
+        if hooks.as_ref().is_some_and(|hooks| !hooks.on_accept(addr)) {
+            debug!("turned away {}", addr);
+            continue;
+        }
+        tokio::spawn(async move {
+            metrics::add(&METRICS.sessions, 1);
+            let res = client.serve().await;
+            match res {
+                Ok((a, b)) => {
+                    metrics::add(&METRICS.bytes_up, a);
+                    metrics::add(&METRICS.bytes_down, b);
+                    info!("proxied {}/{} bytes for {}", a, b, addr)
+                }
+                Err(e) => {
+                    metrics::add(&METRICS.failed_sessions, 1);
+                    if let Some(kind) = timeout_kind(&e) {
+                        metrics::add(METRICS.timeouts(kind), 1);
+                    }
+                    error!("error for {}: {}", addr, e)
+                }
+            }
+        });
+    }
+}
+
+/// A listener for connections redirected by the firewall, which go where
+/// they were meant to without any handshake.
+#[cfg(target_os = "linux")]
+pub(crate) fn listen_transparent(addr: &SocketAddr, mode: transparent::Mode,
+                                 settings: Arc<Settings>)
+    -> io::Result<impl ClientChannel>
+{
+    let listener = transparent::bind(addr, mode)?;
+    let inner = TcpListenerChannel::with_listener(listener, settings);
+    Ok(TransparentListenerChannel { inner, mode })
+}
+
+#[cfg(target_os = "linux")]
+struct TransparentListenerChannel {
+    inner: TcpListenerChannel,
+    mode: transparent::Mode
+}
+
+#[cfg(target_os = "linux")]
+impl ClientChannel for TransparentListenerChannel {
+    type Connection = TcpStream;
+    async fn accept(&mut self) -> io::Result<Client> {
+        let (c, a, permit) = loop {
+            let (c, a) = self.inner.listener.accept().await?;
+            let a = transparent::canonical(a);
+            if let Some(permit) = self.inner.admit(&c, a) {
+                break (c, a, permit);
+            }
+        };
+        let port = self.inner.listener.local_addr()?.port();
+        let target = transparent::original_dst(&c, self.mode, port)
+            .map_err(|e| other(&format!("{} from {}", e, a)))?;
+        let client = Client::new(c, a, self.inner.settings.clone()).with_permit(permit);
+        Ok(client.with_target(TargetAddr::Ip(target)))
+    }
+}
+
+/// A listener whose clients run their handshake inside TLS.
+pub async fn listen_tls(addr: &SocketAddr, tls: &TlsConfig, settings: Arc<Settings>)
+    -> io::Result<impl ClientChannel>
+{
+    let acceptor = TlsAcceptor::from(tls::server_config(tls)?);
+    let inner = TcpListenerChannel::new(addr, settings).await?;
+    Ok(TlsListenerChannel { inner, acceptor })
+}
+
+/// Clients of a listener which is already bound, with their handshake
+/// inside TLS.
+pub fn tls_channel(listener: TcpListener, tls: &TlsConfig, settings: Arc<Settings>)
+    -> io::Result<impl ClientChannel + Send>
+{
+    let acceptor = TlsAcceptor::from(tls::server_config(tls)?);
+    let inner = TcpListenerChannel::with_listener(listener, settings);
+    Ok(TlsListenerChannel { inner, acceptor })
+}
+
+struct TlsListenerChannel {
+    inner: TcpListenerChannel,
+    acceptor: TlsAcceptor
+}
+
+impl ClientChannel for TlsListenerChannel {
+    type Connection = TlsConnection;
+    async fn accept(&mut self) -> io::Result<Client<TlsConnection>> {
+        let (c, a, permit) = self.inner.accept_tcp().await?;
+        let conn = TlsConnection::new(self.acceptor.accept(c));
+        Ok(Client::new(conn, a, self.inner.settings.clone()).with_permit(permit))
+    }
+}
+
+/// Clients of `inner` which upgrade to a WebSocket before their handshake.
+pub fn websocket<C>(inner: C, config: &WebSocketConfig) -> impl ClientChannel + Send
+    where C: ClientChannel + Send
+{
+    WsListenerChannel { inner, config: config.clone() }
+}
+
+struct WsListenerChannel<C> {
+    inner: C,
+    config: WebSocketConfig
+}
+
+impl<C: ClientChannel + Send> ClientChannel for WsListenerChannel<C> {
+    type Connection = WsConnection<C::Connection>;
+    async fn accept(&mut self) -> io::Result<Client<Self::Connection>> {
+        let client = self.inner.accept().await?;
+        let config = self.config.clone();
+        Ok(client.map(|conn| WsConnection::new(conn, config)))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::buffer_pool::BufferPool;
+    use crate::client::Protocol;
+    use crate::config::Timeouts;
+    use crate::connector::Connector;
+    use crate::endpoint::RelayOptions;
+    use crate::proxy_protocol::Header;
+
+    // A listener which trusts the PROXY headers of `trusted`.
+    async fn channel(trusted: &str) -> (TcpListenerChannel, SocketAddr) {
+        let relay = RelayOptions { buffers: BufferPool::new(1 << 20), buffer_size: 4096,
+                                   idle_timeout: None, lifetime: None };
+        let mut settings = Settings::new(Protocol::Socks5, Arc::new(Connector::direct()),
+                                         Timeouts::default(), relay);
+        settings.trusted_proxies = vec![trusted.parse().unwrap()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (TcpListenerChannel::with_listener(listener, Arc::new(settings)), addr)
+    }
+
+    fn header() -> Vec<u8> {
+        let mut bytes = Header { source: "192.0.2.1:56324".parse().unwrap(),
+                                 destination: "198.51.100.1:443".parse().unwrap(),
+                                 authority: None, username: None }.encode(2);
+        bytes.extend_from_slice(b"hello");
+        bytes
+    }
+
+    #[tokio::test]
+    async fn headers_of_trusted_peers_name_the_client() {
+        let (mut channel, addr) = channel("127.0.0.0/8").await;
+        let mut peer = TcpStream::connect(addr).await.unwrap();
+        peer.write_all(&header()).await.unwrap();
+        let (mut c, a, _) = channel.accept_tcp().await.unwrap();
+        assert_eq!(a, "192.0.2.1:56324".parse().unwrap());
+        let mut rest = [0u8; 5];
+        c.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn headers_of_untrusted_peers_are_left_alone() {
+        let (mut channel, addr) = channel("10.0.0.0/8").await;
+        let mut peer = TcpStream::connect(addr).await.unwrap();
+        peer.write_all(&header()).await.unwrap();
+        let (mut c, a, _) = channel.accept_tcp().await.unwrap();
+        assert_eq!(a, peer.local_addr().unwrap());
+        // The header reaches the protocol of the listener as it is.
+        let mut got = vec![0u8; header().len()];
+        c.read_exact(&mut got).await.unwrap();
+        assert_eq!(got, header());
+    }
+
+    #[tokio::test]
+    async fn trusted_peers_without_a_header_are_dropped() {
+        let (mut channel, addr) = channel("127.0.0.0/8").await;
+        let mut bad = TcpStream::connect(addr).await.unwrap();
+        bad.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(channel.accept_tcp().await.is_err());
+        let mut good = TcpStream::connect(addr).await.unwrap();
+        good.write_all(&header()).await.unwrap();
+        let (_, a, _) = channel.accept_tcp().await.unwrap();
+        assert_eq!(a, "192.0.2.1:56324".parse().unwrap());
+    }
+}
@@ -0,0 +1,175 @@
+//! The SOCKS5 encoding of addresses and reply codes, which the tunnel and
+//! shadowsocks protocols borrow as well.
+use tokio::io::{AsyncRead, AsyncReadExt};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use crate::connector::TargetAddr;
+use crate::utilities::{name_port, other};
+
+// After we've negotiated a command, there's one byte which is reserved
+// for future use, so we read it and discard it. The next part of the
+// protocol is to read off the address that we're going to proxy to.
+// This address can come in a number of forms, so we read off a byte
+// which indicates the address type (ATYP).
+//
+// Depending on the address type, we then read off that particular address
+// format.
+pub async fn parse_addr<S>(c: &mut S, atyp: u8) -> io::Result<TargetAddr>
+    where S: AsyncRead + Unpin
+{
+    debug!("addr type: {}", atyp);
+    match atyp {
+        // For IPv4 addresses, we read the 4 bytes for the address as
+        // well as 2 bytes for the port.
+        v5::ATYP_IPV4 => {
+            let mut buf = [0u8; 6];
+            c.read_exact(&mut buf).await?;
+            let addr = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+            let port = ((buf[4] as u16) << 8) | (buf[5] as u16);
+            Ok(TargetAddr::Ip(SocketAddr::V4(SocketAddrV4::new(addr, port))))
+        }
+
+        // For IPv6 addresses there's 16 bytes of an address plus two
+        // bytes for a port, so we read that off and then keep going.
+        v5::ATYP_IPV6 => {
+            let mut buf = [0u8; 18];
+            c.read_exact(&mut buf).await?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[..16]);
+            let addr = Ipv6Addr::from(octets);
+            let port = ((buf[16] as u16) << 8) | (buf[17] as u16);
+            Ok(TargetAddr::Ip(SocketAddr::V6(SocketAddrV6::new(addr, port, 0, 0))))
+        }
+
+        // The SOCKSv5 protocol not only supports proxying to specific
+        // IP addresses, but also arbitrary hostnames. This allows
+        // clients to perform hostname lookups within the context of the
+        // proxy server rather than the client itself.
+        //
+        // The protocol here is to have the next byte indicate how many
+        // bytes the hostname contains, followed by the hostname and two
+        // bytes for the port. To read this data, we execute two
+        // respective `read_exact` operations to fill up a buffer for
+        // the hostname.
+        //
+        // Finally, we process the buffer. The hostname is only resolved
+        // when the target is dialed, which may well not be by us.
+        v5::ATYP_DOMAIN => {
+            debug!("domain!");
+            let mut len = [0u8];
+            c.read_exact(&mut len).await?;
+            let mut buf = vec![0u8; len[0] as usize + 2];
+            c.read_exact(&mut buf).await?;
+            name_port(&buf)
+        }
+
+        n => {
+            let msg = format!("unknown ATYP received: {}", n);
+            Err(other(&msg))
+        }
+    }
+}
+
+// The reverse of `parse_addr`: appends ATYP, the address and the port.
+// Fails for domain names too long to encode.
+pub fn write_addr(buf: &mut Vec<u8>, addr: &TargetAddr) -> io::Result<()> {
+    match addr {
+        TargetAddr::Ip(SocketAddr::V4(a)) => {
+            buf.push(v5::ATYP_IPV4);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+        TargetAddr::Ip(SocketAddr::V6(a)) => {
+            buf.push(v5::ATYP_IPV6);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+        TargetAddr::Domain(host, _) => {
+            let len = u8::try_from(host.len()).map_err(|_| {
+                let msg = format!("domain name too long: {} bytes", host.len());
+                io::Error::new(io::ErrorKind::InvalidInput, msg)
+            })?;
+            buf.push(v5::ATYP_DOMAIN);
+            buf.push(len);
+            buf.extend_from_slice(host.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    Ok(())
+}
+
+// REP - "reply field" -- what happened with the actual connect.
+//
+// In theory this should reply back with a bunch more kinds of
+// errors if possible, but for now we just recognize a few concrete
+// errors.
+pub fn reply_code<T>(res: &io::Result<T>) -> u8 {
+    match res {
+        Ok(..) => 0,
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => 2,
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => 5,
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => 6,
+        Err(..) => 1,
+    }
+}
+
+// The reverse of `reply_code`, for replies from an upstream server.
+pub fn reply_error(rep: u8) -> io::Error {
+    match rep {
+        2 => io::Error::new(io::ErrorKind::PermissionDenied, "upstream: connection not allowed"),
+        5 => io::Error::new(io::ErrorKind::ConnectionRefused, "upstream: connection refused"),
+        6 => io::Error::new(io::ErrorKind::TimedOut, "upstream: timed out"),
+        rep => other(&format!("upstream: connect failed with reply {}", rep)),
+    }
+}
+
+// Various constants associated with the SOCKS protocol
+
+#[allow(dead_code)]
+pub mod v5 {
+    pub const VERSION: u8 = 5;
+
+    pub const METH_NO_AUTH: u8 = 0;
+    pub const METH_GSSAPI: u8 = 1;
+    pub const METH_USER_PASS: u8 = 2;
+    pub const METH_NO_ACCEPTABLE: u8 = 0xff;
+
+    // Version of the username/password negotiation of RFC 1929.
+    pub const USER_PASS_VERSION: u8 = 1;
+
+    pub const CMD_CONNECT: u8 = 1;
+    pub const CMD_BIND: u8 = 2;
+    pub const CMD_UDP_ASSOCIATE: u8 = 3;
+
+    pub const ATYP_IPV4: u8 = 1;
+    pub const ATYP_IPV6: u8 = 4;
+    pub const ATYP_DOMAIN: u8 = 3;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(addr: TargetAddr) -> TargetAddr {
+        let mut buf = Vec::new();
+        write_addr(&mut buf, &addr).unwrap();
+        let mut rest = &buf[1..];
+        let parsed = parse_addr(&mut rest, buf[0]).await.unwrap();
+        assert!(rest.is_empty());
+        parsed
+    }
+
+    #[tokio::test]
+    async fn addresses_round_trip() {
+        for addr in [TargetAddr::Ip("192.0.2.1:80".parse().unwrap()),
+                     TargetAddr::Ip("[2001:db8::1]:443".parse().unwrap()),
+                     TargetAddr::Domain("a".repeat(255), 8080)] {
+            assert_eq!(round_trip(addr.clone()).await, addr);
+        }
+    }
+
+    #[test]
+    fn overlong_domains_are_refused() {
+        let mut buf = Vec::new();
+        let e = write_addr(&mut buf, &TargetAddr::Domain("a".repeat(256), 80)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
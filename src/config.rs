@@ -0,0 +1,1048 @@
+//! The configuration file.
+//!
+//! A configuration is a TOML document like this:
+//!
+//! ```toml
+//! # Upper bound of memory used by relay buffers of all sessions.
+//! memory_limit = 67108864
+//!
+//! [[listener]]
+//! address = "127.0.0.1:8083"
+//! buffer_size = 16384
+//!
+//! # All timeouts are in seconds. `idle` and `lifetime` are unlimited unless
+//! # they are given.
+//! [listener.timeouts]
+//! handshake = 10
+//! connect = 10
+//! idle = 300
+//! lifetime = 86400
+//!
+//! # Clients of this listener speak TLS, and the SOCKS handshake happens
+//! # inside of it. If `client_ca` is given clients have to present a
+//! # certificate signed by one of the CAs in that file.
+//! [listener.tls]
+//! cert = "server.crt"
+//! key = "server.key"
+//! client_ca = "ca.crt"
+//!
+//! # Clients upgrade to a WebSocket first (inside of TLS, if there is
+//! # that too), and speak the protocol of the listener in its messages.
+//! # See `WebSocketConfig`.
+//! [listener.websocket]
+//! path = "/ws"
+//! ```
+//!
+//! To run a tunnel (see the `tunnel` module) the local instance hands its
+//! sessions to an upstream:
+//!
+//! ```toml
+//! [[listener]]
+//! address = "127.0.0.1:1080"
+//!
+//! [listener.upstream]
+//! protocol = "tunnel"
+//! password = "secret"
+//! # Carry all sessions over a single connection.
+//! multiplex = true
+//!
+//! # See `TransportConfig`.
+//! [listener.upstream.transport]
+//! address = "remote.example.com:8443"
+//! [listener.upstream.transport.tls]
+//! ca = "ca.crt"
+//! ```
+//!
+//! and the remote instance serves the tunnel protocol over TLS:
+//!
+//! ```toml
+//! [[listener]]
+//! address = "0.0.0.0:8443"
+//! protocol = "tunnel"
+//! password = "secret"
+//!
+//! [listener.tls]
+//! cert = "server.crt"
+//! key = "server.key"
+//! ```
+//!
+//! Connections which the firewall redirects to a listener (see the
+//! `transparent` module) are relayed to where they were meant to go, on
+//! Linux only:
+//!
+//! ```toml
+//! [[listener]]
+//! address = "0.0.0.0:12345"
+//! # "redirect" for REDIRECT or DNAT rules, "tproxy" for TPROXY ones.
+//! protocol = "redirect"
+//! ```
+//!
+//! A forwarding listener relays every client to one fixed target, through
+//! the upstream if there is one:
+//!
+//! ```toml
+//! [[listener]]
+//! address = "0.0.0.0:5432"
+//! protocol = "forward"
+//! target = "db.internal:5432"
+//! ```
+//!
+//! Another SOCKS5 server can be an upstream too:
+//!
+//! ```toml
+//! [listener.upstream]
+//! protocol = "socks5"
+//! # Only if the server wants them.
+//! username = "alice"
+//! password = "secret"
+//! [listener.upstream.transport]
+//! address = "socks.example.com:1080"
+//! ```
+//!
+//! Shadowsocks clients are served by a listener with `protocol =
+//! "shadowsocks"`, and a shadowsocks server is used as an upstream the same
+//! way as a tunnel, without the `tls` table:
+//!
+//! ```toml
+//! [[listener]]
+//! address = "0.0.0.0:8388"
+//! protocol = "shadowsocks"
+//! # One of "chacha20-ietf-poly1305", "aes-256-gcm",
+//! # "2022-blake3-aes-256-gcm" or "2022-blake3-chacha20-poly1305".
+//! method = "chacha20-ietf-poly1305"
+//! # The 2022 methods take a base64 encoded 32 byte key instead.
+//! password = "secret"
+//! ```
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::connector::TargetAddr;
+use crate::shadowsocks::Cipher;
+use crate::utilities::{other, BindRange, Cidr};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_memory_limit")]
+    pub memory_limit: usize,
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<Listener>,
+    /// Services exposed through remote instances, see `ReverseConfig`.
+    #[serde(default, rename = "reverse")]
+    pub reverses: Vec<ReverseConfig>,
+    /// Where a record of every session goes, see `AccessLogConfig`.
+    pub access_log: Option<AccessLogConfig>,
+    /// The rates of all clients of all listeners together, see
+    /// `RateLimitConfig`.
+    pub rate_limit: Option<RateConfig>,
+    /// Bytes users may relay a day or a month, see `QuotaConfig`.
+    pub quota: Option<QuotaConfig>,
+    /// Protection of the passwords of SOCKS5 listeners against guessing,
+    /// see `LockoutConfig`.
+    pub lockout: Option<LockoutConfig>,
+    /// Where the admin API is served, see `AdminConfig`.
+    pub admin: Option<AdminConfig>
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Listener {
+    pub address: SocketAddr,
+    /// Size of the buffer used by each direction of a relayed session.
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    pub tls: Option<TlsConfig>,
+    pub websocket: Option<WebSocketConfig>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Shared secret of the `tunnel` and `shadowsocks` protocols.
+    pub password: Option<String>,
+    /// Cipher of the `shadowsocks` protocol.
+    pub method: Option<CipherMethod>,
+    /// Where the `forward` protocol relays clients to, as `host:port`.
+    pub target: Option<String>,
+    /// Whether clients of the `tunnel` protocol may expose services on this
+    /// instance, see `ReverseConfig`.
+    #[serde(default)]
+    pub reverse: bool,
+    /// Where they may do so, as addresses with a port or a range of ports
+    /// like `"0.0.0.0:2000-2999"`. Anywhere else is refused.
+    #[serde(default)]
+    pub reverse_bind: Vec<String>,
+    /// Where sessions are handed to instead of dialing targets directly.
+    pub upstream: Option<UpstreamConfig>,
+    /// Load balancers in front of the listener, see `ProxyProtocolConfig`.
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    /// Tells targets dialed from here who the clients are, see
+    /// `SendProxyProtocolConfig`. An upstream has its own.
+    pub send_proxy_protocol: Option<SendProxyProtocolConfig>,
+    /// Users of a `socks5` listener, see `AuthConfig`.
+    pub auth: Option<AuthConfig>,
+    /// Upload and download rates of clients, see `RateLimitConfig`.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Caps on the sessions of clients, see `ConnectionLimitConfig`.
+    pub connection_limit: Option<ConnectionLimitConfig>
+}
+
+impl Listener {
+    /// A SOCKS5 listener on `address`, with everything else left at its
+    /// default.
+    pub fn new(address: SocketAddr) -> Listener {
+        Listener {
+            address,
+            buffer_size: default_buffer_size(),
+            timeouts: Timeouts::default(),
+            tls: None,
+            websocket: None,
+            protocol: Protocol::Socks5,
+            password: None,
+            method: None,
+            target: None,
+            reverse: false,
+            reverse_bind: Vec::new(),
+            upstream: None,
+            proxy_protocol: None,
+            send_proxy_protocol: None,
+            auth: None,
+            rate_limit: None,
+            connection_limit: None
+        }
+    }
+
+    pub fn reverse_bind(&self) -> io::Result<Vec<BindRange>> {
+        self.reverse_bind.iter().map(|s| s.parse()).collect()
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Socks5,
+    Tunnel,
+    Shadowsocks,
+    Redirect,
+    Tproxy,
+    Forward
+}
+
+impl Protocol {
+    /// Whether clients are redirected by the firewall instead of speaking
+    /// to the listener.
+    pub fn is_transparent(self) -> bool {
+        matches!(self, Protocol::Redirect | Protocol::Tproxy)
+    }
+}
+
+/// Peers which start their connections with a PROXY protocol header (see
+/// the `proxy_protocol` module), such as load balancers. Clients are then
+/// known by the address in the header instead of the address of the peer.
+///
+/// ```toml
+/// [listener.proxy_protocol]
+/// # Addresses or blocks of them. Connections from anywhere else are
+/// # served as they are, without a header.
+/// trusted = ["10.0.0.0/8", "fd00::/8"]
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    pub trusted: Vec<String>
+}
+
+impl ProxyProtocolConfig {
+    pub(crate) fn trusted(&self) -> io::Result<Vec<Cidr>> {
+        self.trusted.iter().map(|s| s.parse()).collect()
+    }
+}
+
+/// A PROXY protocol header sent ahead of the bytes of each client on the
+/// connections to its target. It goes with the connector which makes them:
+/// the table of a listener covers the targets it dials itself, and the
+/// table of an upstream those reached through that upstream.
+///
+/// ```toml
+/// # Or [listener.upstream.send_proxy_protocol].
+/// [listener.send_proxy_protocol]
+/// # 1 for the text version, 2 for the binary one.
+/// version = 2
+/// # TLVs of version 2 with the host name the client asked for, and the
+/// # user it logged in as, if there are any.
+/// authority = true
+/// username = true
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SendProxyProtocolConfig {
+    pub version: u8,
+    #[serde(default)]
+    pub authority: bool,
+    #[serde(default)]
+    pub username: bool
+}
+
+impl SendProxyProtocolConfig {
+    fn validate(&self, what: &str) -> io::Result<()> {
+        match self.version {
+            1 if self.authority || self.username => {
+                Err(other(&format!("{} needs version 2 for TLVs", what)))
+            }
+            1 | 2 => Ok(()),
+            v => Err(other(&format!("{} has unknown version {}", what, v))),
+        }
+    }
+}
+
+/// The users SOCKS5 clients have to log in as with a password (RFC 1929),
+/// from exactly one of the places below (see the `auth` module).
+///
+/// ```toml
+/// [listener.auth]
+/// # Right here.
+/// users = { alice = "secret", bob = "hunter2" }
+/// # An htpasswd file of bcrypt (`htpasswd -B`) or argon2 hashes, which is
+/// # read again once it changed.
+/// htpasswd = "/etc/rustoxy/htpasswd"
+/// # A SQLite database with the `sqlite` feature, whose query gives the
+/// # hash of the user bound to ?1, as in the htpasswd file.
+/// sqlite = { path = "/var/lib/rustoxy/users.db", query = "SELECT hash FROM users WHERE name = ?1" }
+/// # A helper program as run by Squid for basic auth, which is given lines of
+/// # `user password` (both URL-encoded) and answers each with OK or ERR.
+/// helper = ["/usr/lib/squid/basic_ncsa_auth", "/etc/squid/passwd"]
+///
+/// # Seconds the passwords the htpasswd file, the database or the helper
+/// # accepted are remembered for, 0 to ask them every time. Passwords they
+/// # turned down are asked about every time.
+/// cache = 60
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub users: HashMap<String, String>,
+    pub htpasswd: Option<PathBuf>,
+    pub sqlite: Option<SqliteUsersConfig>,
+    pub helper: Option<Vec<String>>,
+    #[serde(default = "default_auth_cache")]
+    pub cache: u64
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SqliteUsersConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_users_query")]
+    pub query: String
+}
+
+fn default_auth_cache() -> u64 {
+    60
+}
+
+fn default_users_query() -> String {
+    "SELECT hash FROM users WHERE name = ?1".to_string()
+}
+
+/// Limits of how fast the clients of a listener upload and download (see
+/// the `rate_limit` module), on top of the limit of all listeners together
+/// given at the top of the configuration:
+///
+/// ```toml
+/// [rate_limit]
+/// download = 104857600
+///
+/// [[listener]]
+/// address = "0.0.0.0:1080"
+///
+/// # Rates are in bytes per second, and either one is unlimited unless it
+/// # is given.
+/// [listener.rate_limit]
+/// # All clients of the listener together.
+/// total = { upload = 10485760, download = 52428800 }
+/// # The sessions from each source address together.
+/// per_ip = { download = 10485760 }
+/// # The sessions of each user together.
+/// per_user = { upload = 1048576, download = 5242880 }
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub total: Option<RateConfig>,
+    pub per_ip: Option<RateConfig>,
+    pub per_user: Option<RateConfig>
+}
+
+/// Caps on how many sessions the clients of a listener have at once, and
+/// on how many they start a second (see the `conn_limit` module). Either
+/// one is unlimited unless it is given.
+///
+/// ```toml
+/// [listener.connection_limit]
+/// # All clients of the listener together.
+/// total = { concurrent = 10000, rate = 500 }
+/// # The clients from each source address.
+/// per_ip = { concurrent = 64, rate = 20 }
+/// # The clients logged in as each user.
+/// per_user = { concurrent = 32 }
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionLimitConfig {
+    pub total: Option<SessionLimitConfig>,
+    pub per_ip: Option<SessionLimitConfig>,
+    pub per_user: Option<SessionLimitConfig>
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SessionLimitConfig {
+    /// Sessions at once.
+    pub concurrent: Option<usize>,
+    /// New sessions a second.
+    pub rate: Option<u64>
+}
+
+/// Bytes per second from the client to its target, and back.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    pub upload: Option<u64>,
+    pub download: Option<u64>
+}
+
+impl RateConfig {
+    fn validate(&self, what: &str) -> io::Result<()> {
+        if self.upload == Some(0) || self.download == Some(0) {
+            return Err(other(&format!("{} has a rate of 0", what)));
+        }
+        Ok(())
+    }
+}
+
+/// The bytes each user who logs in may relay (up and down together) in a
+/// day and in a month, both counted in UTC. Once a user is over its quota
+/// its new sessions are refused, see the `quota` module.
+///
+/// ```toml
+/// [quota]
+/// daily = 1073741824
+/// monthly = 21474836480
+/// # Whether sessions in progress are cut as well once their user goes
+/// # over.
+/// cut = true
+/// # Where usage is kept across restarts, either a file or a SQLite
+/// # database: store = { sqlite = "/var/lib/rustoxy/usage.db" }
+/// store = { file = "/var/lib/rustoxy/usage" }
+///
+/// # Users with quotas of their own. Whatever isn't given here is
+/// # unlimited.
+/// [quota.users.alice]
+/// monthly = 107374182400
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+    #[serde(default)]
+    pub cut: bool,
+    pub store: Option<QuotaStore>,
+    #[serde(default)]
+    pub users: HashMap<String, QuotaLimits>
+}
+
+impl QuotaConfig {
+    /// The quotas of `user`.
+    pub fn limits(&self, user: &str) -> QuotaLimits {
+        let all = QuotaLimits { daily: self.daily, monthly: self.monthly };
+        self.users.get(user).copied().unwrap_or(all)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaLimits {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+pub enum QuotaStore {
+    File(PathBuf),
+    Sqlite(PathBuf)
+}
+
+/// Failed logins counted against the source address of the client and the
+/// user it tried, which hold back the replies to further attempts and get
+/// either one banned once there were too many (see the `lockout` module).
+/// An empty `[lockout]` table takes the defaults given here.
+///
+/// ```toml
+/// [lockout]
+/// # Failed logins of an address or a user before it is banned.
+/// max_failures = 5
+/// # Seconds after which failed logins are forgotten.
+/// window = 600
+/// # Seconds a ban lasts.
+/// ban = 900
+/// # Seconds the reply to a failed login is held back, doubled for every
+/// # earlier one up to `max_delay`, which is to be shorter than the
+/// # handshake timeout of the SOCKS5 listeners.
+/// delay = 1
+/// max_delay = 8
+/// ```
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields, default)]
+pub struct LockoutConfig {
+    pub max_failures: u32,
+    pub window: u64,
+    pub ban: u64,
+    pub delay: u64,
+    pub max_delay: u64
+}
+
+impl Default for LockoutConfig {
+    fn default() -> LockoutConfig {
+        LockoutConfig { max_failures: 5, window: 600, ban: 900, delay: 1, max_delay: 8 }
+    }
+}
+
+/// A small HTTP API to look after a running server (see the `admin`
+/// module). Without a token it may only listen on a loopback address.
+///
+/// ```toml
+/// [admin]
+/// address = "127.0.0.1:9090"
+/// # Asked for as `Authorization: Bearer <token>`.
+/// token = "s3cr3t"
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub address: SocketAddr,
+    pub token: Option<String>
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub protocol: UpstreamProtocol,
+    /// Shared secret of the `tunnel` and `shadowsocks` protocols, or the
+    /// password of `username`.
+    #[serde(default)]
+    pub password: String,
+    /// User a `socks5` upstream is logged in as, if it wants one.
+    pub username: Option<String>,
+    pub method: Option<CipherMethod>,
+    /// Whether the sessions of a tunnel share one connection.
+    #[serde(default)]
+    pub multiplex: bool,
+    pub transport: TransportConfig,
+    /// Tells targets reached through the upstream who the clients are, see
+    /// `SendProxyProtocolConfig`.
+    pub send_proxy_protocol: Option<SendProxyProtocolConfig>
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    Tunnel,
+    Shadowsocks,
+    Socks5
+}
+
+/// The AEAD ciphers of the shadowsocks protocol.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherMethod {
+    #[serde(rename = "chacha20-ietf-poly1305")]
+    Chacha20IetfPoly1305,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "2022-blake3-aes-256-gcm")]
+    Blake3Aes256Gcm,
+    #[serde(rename = "2022-blake3-chacha20-poly1305")]
+    Blake3Chacha20Poly1305
+}
+
+impl CipherMethod {
+    /// Whether this is a method of the 2022 edition.
+    pub fn is_2022(self) -> bool {
+        matches!(self, CipherMethod::Blake3Aes256Gcm | CipherMethod::Blake3Chacha20Poly1305)
+    }
+}
+
+/// A local service exposed on a remote instance, which accepts connections
+/// for it and relays them back over a tunnel this instance keeps open,
+/// like `ssh -R`. The remote instance needs a tunnel listener with
+/// `reverse = true`, and `bind` among its `reverse_bind`:
+///
+/// ```toml
+/// [[listener]]
+/// address = "0.0.0.0:8443"
+/// protocol = "tunnel"
+/// password = "secret"
+/// reverse = true
+/// reverse_bind = ["0.0.0.0:2000-2999", "127.0.0.1:8080"]
+/// ```
+///
+/// and on this side
+///
+///
+/// ```toml
+/// [[reverse]]
+/// password = "secret"
+/// # Where the remote instance accepts connections.
+/// bind = "0.0.0.0:2222"
+/// # Where they are relayed to from here.
+/// target = "127.0.0.1:22"
+///
+/// # See `TransportConfig`.
+/// [reverse.transport]
+/// address = "public.example.com:8443"
+/// [reverse.transport.tls]
+/// ca = "ca.crt"
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ReverseConfig {
+    pub password: String,
+    pub bind: SocketAddr,
+    pub target: String,
+    pub transport: TransportConfig,
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    #[serde(default)]
+    pub timeouts: Timeouts
+}
+
+/// A record of every session of every listener (see the `access_log`
+/// module), which is written once the session is over.
+///
+/// ```toml
+/// [access_log]
+/// # "json", "logfmt" or "clf".
+/// format = "json"
+/// # "stderr", "file", or "syslog" for the syslog socket.
+/// sink = "file"
+/// # The file, or the socket of syslog, "/dev/log" by default.
+/// path = "/var/log/rustoxy/access.log"
+/// # The file is moved to access.log.1 (and that one to access.log.2 and so
+/// # on, up to `keep` of them) once it would grow past `max_size` bytes. It
+/// # grows without limit if no size is given.
+/// max_size = 104857600
+/// keep = 5
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub sink: LogSink,
+    pub path: Option<PathBuf>,
+    pub max_size: Option<u64>,
+    #[serde(default = "default_keep")]
+    pub keep: usize
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Logfmt,
+    Clf
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogSink {
+    #[default]
+    Stderr,
+    File,
+    Syslog
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain of the server.
+    pub cert: PathBuf,
+    /// PEM file with the private key of the server.
+    pub key: PathBuf,
+    /// PEM file with the CAs client certificates are verified against.
+    pub client_ca: Option<PathBuf>
+}
+
+/// How to reach an upstream server, such as the remote end of a tunnel.
+///
+/// ```toml
+/// address = "proxy.example.com:443"
+///
+/// # Without this table the connection is plain TCP.
+/// [tls]
+/// # Name sent in SNI and checked against the certificate, the host of
+/// # `address` by default. `sni = false` leaves SNI out of the handshake.
+/// server_name = "proxy.example.com"
+/// sni = true
+/// # CAs the server certificate is checked against instead of the
+/// # well-known ones.
+/// ca = "ca.crt"
+/// # SHA-256 digests (hex) of the DER encoding of accepted server
+/// # certificates. When set, only these are accepted, and they need not
+/// # chain to a CA, so self-signed ones work; `ca` is then unused.
+/// pins = ["9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"]
+/// # Certificate presented to servers which ask for one.
+/// cert = "client.crt"
+/// key = "client.key"
+///
+/// # Upgrade the connection to a WebSocket (after TLS, if any).
+/// [websocket]
+/// path = "/ws"
+/// host = "cdn.example.com"
+/// ```
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TransportConfig {
+    pub address: String,
+    pub tls: Option<ClientTlsConfig>,
+    pub websocket: Option<WebSocketConfig>
+}
+
+/// The upgrade request of a WebSocket.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Path of the request, `/` by default.
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Host header. Sent when dialing, `address` by default, and required
+    /// of clients by a listener if given.
+    pub host: Option<String>
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ClientTlsConfig {
+    pub server_name: Option<String>,
+    #[serde(default = "default_sni")]
+    pub sni: bool,
+    pub ca: Option<PathBuf>,
+    #[serde(default)]
+    pub pins: Vec<String>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>
+}
+
+fn default_sni() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields, default)]
+pub struct Timeouts {
+    /// Time allowed for the client to finish its part of the handshake.
+    pub handshake: u64,
+    /// Time allowed for connecting to the target.
+    pub connect: u64,
+    /// Time a session may go without a byte in either direction.
+    pub idle: Option<u64>,
+    /// Time a session may last in total.
+    pub lifetime: Option<u64>
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts { handshake: 10, connect: 10, idle: None, lifetime: None }
+    }
+}
+
+impl Timeouts {
+    pub fn handshake(&self) -> Duration {
+        Duration::from_secs(self.handshake)
+    }
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect)
+    }
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle.map(Duration::from_secs)
+    }
+    pub fn lifetime(&self) -> Option<Duration> {
+        self.lifetime.map(Duration::from_secs)
+    }
+}
+
+fn default_memory_limit() -> usize {
+    64 << 20
+}
+
+fn default_buffer_size() -> usize {
+    8 << 10
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            memory_limit: default_memory_limit(),
+            listeners: Vec::new(),
+            reverses: Vec::new(),
+            access_log: None,
+            rate_limit: None,
+            quota: None,
+            lockout: None,
+            admin: None
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&text).map_err(|e| other(&e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The configuration used when only a listening address is given.
+    pub fn with_address(address: SocketAddr) -> Config {
+        Config { listeners: vec![Listener::new(address)], ..Config::default() }
+    }
+
+    /// Checks what can't be told from the types alone.
+    pub fn validate(&self) -> io::Result<()> {
+        for l in &self.listeners {
+            // Each session takes one buffer for each direction at once.
+            let both = l.buffer_size.checked_mul(2);
+            if l.buffer_size == 0 || both.is_none_or(|b| b > self.memory_limit) {
+                let msg = format!("buffer_size of listener {} doesn't fit in memory_limit",
+                                  l.address);
+                return Err(other(&msg));
+            }
+            // The tunnel protocol relies on TLS for its encryption.
+            if l.protocol == Protocol::Tunnel && (l.password.is_none() || l.tls.is_none()) {
+                let msg = format!("tunnel listener {} needs a password and tls", l.address);
+                return Err(other(&msg));
+            }
+            if l.protocol.is_transparent() {
+                if cfg!(not(target_os = "linux")) {
+                    let msg = format!("transparent listener {} needs Linux", l.address);
+                    return Err(other(&msg));
+                }
+                if l.tls.is_some() || l.websocket.is_some() || l.proxy_protocol.is_some() {
+                    let msg = format!("transparent listener {} can't have tls, websocket or \
+                                       proxy_protocol", l.address);
+                    return Err(other(&msg));
+                }
+            }
+            if let Some(ref proxy) = l.proxy_protocol {
+                let trusted = proxy.trusted().map_err(|e| {
+                    other(&format!("proxy_protocol of listener {}: {}", l.address, e))
+                })?;
+                if trusted.is_empty() {
+                    let msg = format!("proxy_protocol of listener {} trusts nobody", l.address);
+                    return Err(other(&msg));
+                }
+            }
+            if let Some(ref send) = l.send_proxy_protocol {
+                if l.upstream.is_some() {
+                    let msg = format!("send_proxy_protocol of listener {} belongs in the table \
+                                       of its upstream", l.address);
+                    return Err(other(&msg));
+                }
+                send.validate(&format!("send_proxy_protocol of listener {}", l.address))?;
+            }
+            if l.auth.is_some() && l.protocol != Protocol::Socks5 {
+                let msg = format!("only socks5 listeners can have auth, not {}", l.address);
+                return Err(other(&msg));
+            }
+            if let Some(ref auth) = l.auth {
+                let given = [!auth.users.is_empty(), auth.htpasswd.is_some(), auth.sqlite.is_some(),
+                             auth.helper.is_some()];
+                if given.iter().filter(|&&g| g).count() != 1 {
+                    let msg = format!("auth of listener {} needs exactly one of users, htpasswd, \
+                                       sqlite and helper", l.address);
+                    return Err(other(&msg));
+                }
+                if auth.helper.as_ref().is_some_and(|h| h.is_empty()) {
+                    let msg = format!("auth helper of listener {} has no program", l.address);
+                    return Err(other(&msg));
+                }
+                if auth.sqlite.is_some() && cfg!(not(feature = "sqlite")) {
+                    let msg = format!("auth of listener {} in SQLite needs the sqlite feature",
+                                      l.address);
+                    return Err(other(&msg));
+                }
+            }
+            if let Some(ref limit) = l.rate_limit {
+                let what = format!("rate_limit of listener {}", l.address);
+                for rate in [&limit.total, &limit.per_ip, &limit.per_user].into_iter().flatten() {
+                    rate.validate(&what)?;
+                }
+            }
+            if let Some(ref limit) = l.connection_limit {
+                for cap in [&limit.total, &limit.per_ip, &limit.per_user].into_iter().flatten() {
+                    if cap.concurrent == Some(0) || cap.rate == Some(0) {
+                        let msg = format!("connection_limit of listener {} lets nobody in",
+                                          l.address);
+                        return Err(other(&msg));
+                    }
+                }
+            }
+            if l.reverse && l.protocol != Protocol::Tunnel {
+                let msg = format!("listener {} can't take reverse tunnels", l.address);
+                return Err(other(&msg));
+            }
+            if l.reverse == l.reverse_bind.is_empty() {
+                let msg = format!("listener {} needs both reverse and reverse_bind, or neither",
+                                  l.address);
+                return Err(other(&msg));
+            }
+            l.reverse_bind().map_err(|e| {
+                other(&format!("reverse_bind of listener {}: {}", l.address, e))
+            })?;
+            if l.protocol == Protocol::Forward {
+                let target = l.target.as_deref().unwrap_or("");
+                target.parse::<TargetAddr>().map_err(|e| {
+                    other(&format!("forward listener {} needs a target: {}", l.address, e))
+                })?;
+            }
+            if l.protocol == Protocol::Shadowsocks {
+                match (l.method, &l.password) {
+                    (Some(method), Some(password)) => {
+                        Cipher::new(method, password).map_err(|e| {
+                            other(&format!("shadowsocks listener {}: {}", l.address, e))
+                        })?;
+                    }
+                    _ => {
+                        let msg = format!("shadowsocks listener {} needs a method and a password",
+                                          l.address);
+                        return Err(other(&msg));
+                    }
+                }
+            }
+            if let Some(ref upstream) = l.upstream {
+                if let Some(ref send) = upstream.send_proxy_protocol {
+                    send.validate(&format!("send_proxy_protocol of the upstream of listener {}",
+                                           l.address))?;
+                }
+                if upstream.protocol == UpstreamProtocol::Tunnel && upstream.transport.tls.is_none() {
+                    let msg = format!("tunnel upstream of listener {} needs tls", l.address);
+                    return Err(other(&msg));
+                }
+                if upstream.protocol != UpstreamProtocol::Socks5 && upstream.password.is_empty() {
+                    let msg = format!("upstream of listener {} needs a password", l.address);
+                    return Err(other(&msg));
+                }
+                if upstream.username.is_some() && upstream.protocol != UpstreamProtocol::Socks5 {
+                    let msg = format!("only socks5 upstreams take a username, not the one of \
+                                       listener {}", l.address);
+                    return Err(other(&msg));
+                }
+                if upstream.multiplex && upstream.protocol != UpstreamProtocol::Tunnel {
+                    let msg = format!("upstream of listener {} can't be multiplexed", l.address);
+                    return Err(other(&msg));
+                }
+                if upstream.protocol == UpstreamProtocol::Shadowsocks {
+                    let method = upstream.method.ok_or_else(|| {
+                        other(&format!("shadowsocks upstream of listener {} needs a method",
+                                       l.address))
+                    })?;
+                    Cipher::new(method, &upstream.password).map_err(|e| {
+                        other(&format!("shadowsocks upstream of listener {}: {}", l.address, e))
+                    })?;
+                }
+            }
+        }
+        if let Some(ref rate) = self.rate_limit {
+            rate.validate("rate_limit")?;
+        }
+        if let Some(QuotaStore::Sqlite(_)) = self.quota.as_ref().and_then(|q| q.store.as_ref()) {
+            if cfg!(not(feature = "sqlite")) {
+                return Err(other("quota store in SQLite needs the sqlite feature"));
+            }
+        }
+        if let Some(lockout) = self.lockout {
+            if lockout.max_failures == 0 {
+                return Err(other("lockout bans everybody with a max_failures of 0"));
+            }
+            // The reply to a failed login is held back within the handshake.
+            let mut socks5 = self.listeners.iter().filter(|l| l.protocol == Protocol::Socks5);
+            if let Some(l) = socks5.find(|l| lockout.max_delay >= l.timeouts.handshake) {
+                let msg = format!("max_delay of lockout isn't shorter than the handshake timeout \
+                                   of listener {}", l.address);
+                return Err(other(&msg));
+            }
+        }
+        if let Some(ref admin) = self.admin {
+            if admin.token.is_none() && !admin.address.ip().is_loopback() {
+                return Err(other("admin API off a loopback address needs a token"));
+            }
+        }
+        for r in &self.reverses {
+            let both = r.buffer_size.checked_mul(2);
+            if r.buffer_size == 0 || both.is_none_or(|b| b > self.memory_limit) {
+                let msg = format!("buffer_size of reverse tunnel {} doesn't fit in memory_limit",
+                                  r.bind);
+                return Err(other(&msg));
+            }
+            if r.transport.tls.is_none() {
+                return Err(other(&format!("reverse tunnel {} needs tls", r.bind)));
+            }
+            r.target.parse::<TargetAddr>().map_err(|e| {
+                other(&format!("reverse tunnel {} needs a target: {}", r.bind, e))
+            })?;
+        }
+        if let Some(ref log) = self.access_log {
+            if log.sink == LogSink::File && log.path.is_none() {
+                return Err(other("access_log to a file needs a path"));
+            }
+            if log.sink == LogSink::Syslog && cfg!(not(unix)) {
+                return Err(other("access_log to syslog needs a Unix socket"));
+            }
+            if log.sink != LogSink::File && log.max_size.is_some() {
+                return Err(other("only an access_log file has a max_size"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_buffers_are_refused_rather_than_overflowing() {
+        let mut config = Config::with_address("127.0.0.1:1080".parse().unwrap());
+        config.validate().unwrap();
+        config.listeners[0].buffer_size = usize::MAX / 2 + 1;
+        assert!(config.validate().is_err());
+        config.listeners[0].buffer_size = config.memory_limit / 2 + 1;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn lockout_delays_fit_in_the_handshake() {
+        let mut config = Config::with_address("127.0.0.1:1080".parse().unwrap());
+        config.lockout = Some(LockoutConfig::default());
+        config.validate().unwrap();
+        config.listeners[0].timeouts.handshake = config.lockout.unwrap().max_delay;
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Caps on the sessions of a listener: how many there are at once, and how
+//! many new ones there are a second.
+//!
+//! The caps of the listener as a whole and of each source address are
+//! checked as soon as a connection is accepted, and a connection over
+//! either is reset right away. The caps of each user are checked once the
+//! user logged in, and a SOCKS5 client over them is told its request isn't
+//! allowed. Either way nothing else is spent on it.
+//!
+//! New sessions are counted with a token bucket (see the `rate_limit`
+//! module), which allows bursts of up to a second worth. See
+//! `ConnectionLimitConfig`.
+use std::fmt::Display;
+use std::hash::Hash;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{ConnectionLimitConfig, SessionLimitConfig};
+use crate::rate_limit::Bucket;
+use crate::utilities::{Swept, SWEEP_EVERY};
+
+/// The caps of a listener. Clones share them.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    total: Option<Arc<Counter<()>>>,
+    per_ip: Option<Arc<Counter<IpAddr>>>,
+    per_user: Option<Arc<Counter<String>>>
+}
+
+impl ConnectionLimiter {
+    /// The caps of a listener, if there are any.
+    pub fn new(config: &ConnectionLimitConfig) -> ConnectionLimiter {
+        ConnectionLimiter {
+            total: config.total.as_ref().map(Counter::new),
+            per_ip: config.per_ip.as_ref().map(Counter::new),
+            per_user: config.per_user.as_ref().map(Counter::new)
+        }
+    }
+
+    /// Lets in a connection from `ip`, or tells why not.
+    pub fn admit(&self, ip: IpAddr) -> io::Result<Permit> {
+        let mut permit = Permit(Vec::new());
+        if let Some(ref total) = self.total {
+            total.acquire((), "the listener")?;
+            permit.0.push(Held::Total(total.clone()));
+        }
+        if let Some(ref per_ip) = self.per_ip {
+            per_ip.acquire(ip, ip)?;
+            permit.0.push(Held::Ip(per_ip.clone(), ip));
+        }
+        Ok(permit)
+    }
+
+    /// Lets in a session of `user`, or tells why not.
+    pub fn admit_user(&self, user: &str) -> io::Result<Permit> {
+        let mut permit = Permit(Vec::new());
+        if let Some(ref per_user) = self.per_user {
+            per_user.acquire(user.to_string(), user)?;
+            permit.0.push(Held::User(per_user.clone(), user.to_string()));
+        }
+        Ok(permit)
+    }
+}
+
+/// A session counted against the caps until it is dropped.
+pub struct Permit(Vec<Held>);
+
+enum Held {
+    Total(Arc<Counter<()>>),
+    Ip(Arc<Counter<IpAddr>>, IpAddr),
+    User(Arc<Counter<String>>, String)
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        match self {
+            Held::Total(counter) => counter.release(&()),
+            Held::Ip(counter, ip) => counter.release(ip),
+            Held::User(counter, user) => counter.release(user),
+        }
+    }
+}
+
+// The sessions of every address or user, or of the listener as a whole.
+struct Counter<K> {
+    concurrent: Option<usize>,
+    rate: Option<u64>,
+    entries: Mutex<Swept<K, Entry>>
+}
+
+struct Entry {
+    active: usize,
+    new: Option<Bucket>
+}
+
+impl<K: Hash + Eq> Counter<K> {
+    fn new(config: &SessionLimitConfig) -> Arc<Counter<K>> {
+        let (concurrent, rate) = (config.concurrent, config.rate);
+        Arc::new(Counter { concurrent, rate, entries: Mutex::new(Swept::new(SWEEP_EVERY)) })
+    }
+
+    fn acquire(&self, key: K, who: impl Display) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        // Addresses and users without sessions whose buckets filled up
+        // again are as good as new.
+        entries.sweep(|_, e| e.active > 0 || e.new.as_ref().is_some_and(|b| !b.is_full()));
+        let rate = self.rate;
+        let entry = entries.entry(key)
+            .or_insert_with(|| Entry { active: 0, new: rate.map(Bucket::new) });
+        let refused = |msg: String| Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+        if self.concurrent.is_some_and(|max| entry.active >= max) {
+            return refused(format!("too many sessions of {}", who));
+        }
+        if entry.new.as_ref().is_some_and(|b| !b.try_take()) {
+            return refused(format!("too many new sessions of {}", who));
+        }
+        entry.active += 1;
+        Ok(())
+    }
+
+    fn release(&self, key: &K) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.active -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::auth::Users;
+    use crate::buffer_pool::BufferPool;
+    use crate::client::{Protocol, Settings};
+    use crate::client_channel::{serve, tcp_channel};
+    use crate::config::Timeouts;
+    use crate::connector::{Connector, TargetAddr};
+    use crate::endpoint::RelayOptions;
+    use crate::socks5_client::Socks5Client;
+
+    fn caps(concurrent: Option<usize>, rate: Option<u64>) -> SessionLimitConfig {
+        SessionLimitConfig { concurrent, rate }
+    }
+
+    #[test]
+    fn concurrent_sessions_are_capped_until_released() {
+        let counter = Counter::<u8>::new(&caps(Some(2), None));
+        counter.acquire(1, "one").unwrap();
+        counter.acquire(1, "one").unwrap();
+        let e = counter.acquire(1, "one").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        // Other keys have caps of their own.
+        counter.acquire(2, "two").unwrap();
+        counter.release(&1);
+        counter.acquire(1, "one").unwrap();
+    }
+
+    #[test]
+    fn new_sessions_are_capped_a_second() {
+        let counter = Counter::<u8>::new(&caps(None, Some(2)));
+        counter.acquire(1, "one").unwrap();
+        counter.release(&1);
+        counter.acquire(1, "one").unwrap();
+        counter.release(&1);
+        // Releasing doesn't give back what was taken out of the bucket.
+        assert!(counter.acquire(1, "one").is_err());
+        std::thread::sleep(Duration::from_millis(600));
+        counter.acquire(1, "one").unwrap();
+    }
+
+    #[test]
+    fn permits_release_their_sessions_when_dropped() {
+        let config = ConnectionLimitConfig { total: Some(caps(Some(1), None)),
+                                             per_ip: Some(caps(Some(1), None)),
+                                             per_user: Some(caps(Some(1), None)) };
+        let limiter = ConnectionLimiter::new(&config);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let permit = limiter.admit(ip).unwrap();
+        assert!(limiter.admit("192.0.2.2".parse().unwrap()).is_err());
+        let user = limiter.admit_user("alice").unwrap();
+        assert!(limiter.admit_user("alice").is_err());
+        drop((permit, user));
+        limiter.admit(ip).unwrap();
+        limiter.admit_user("alice").unwrap();
+    }
+
+    #[tokio::test]
+    async fn users_over_their_caps_are_refused() {
+        let relay = RelayOptions { buffers: BufferPool::new(1 << 20), buffer_size: 4096,
+                                   idle_timeout: None, lifetime: None };
+        let mut settings = Settings::new(Protocol::Socks5, Arc::new(Connector::direct()),
+                                         Timeouts::default(), relay);
+        settings.authenticator = Some(Arc::new(Users::new([("alice", "secret")])));
+        let config = ConnectionLimitConfig { total: None, per_ip: None,
+                                             per_user: Some(caps(Some(1), None)) };
+        settings.connection_limiter = Some(ConnectionLimiter::new(&config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(tcp_channel(listener, Arc::new(settings))));
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = TargetAddr::Ip(target.local_addr().unwrap());
+        let client = Socks5Client::new(&server).credentials("alice", "secret");
+        let mut first = client.connect(&target_addr).await.unwrap();
+        let (mut accepted, _) = target.accept().await.unwrap();
+        let e = client.connect(&target_addr).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        // Once the first session is over there is room for another.
+        first.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        accepted.read_to_end(&mut rest).await.unwrap();
+        drop((first, accepted));
+        for _ in 0..100 {
+            if client.connect(&target_addr).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the first session was never released");
+    }
+}
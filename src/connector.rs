@@ -0,0 +1,305 @@
+//! Outbound connections to the targets of sessions.
+use tokio::io::AsyncWriteExt;
+use tokio::net::{lookup_host, TcpStream};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::client::Origin;
+use crate::config::{SendProxyProtocolConfig, UpstreamConfig, UpstreamProtocol};
+use crate::endpoint::{new_streamendpoint, new_tcpendpoint, transfer, Endpoint, Progress,
+                      RelayOptions};
+use crate::shadowsocks::{self, Cipher};
+use crate::socks5_client::{self, Credentials};
+use crate::transport::Transport;
+pub use crate::transport::{AsyncStream, BoxStream};
+use crate::mux::Session;
+use crate::proxy_protocol::Header;
+use crate::tunnel::{self, Key, TunnelClient};
+use crate::utilities::other;
+
+/// The address a client asked to be connected to. Domain names are kept as
+/// they are, so they can be resolved by whoever finally dials the target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16)
+}
+
+impl TargetAddr {
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
+    }
+
+    pub async fn resolve(&self) -> io::Result<SocketAddr> {
+        match self {
+            TargetAddr::Ip(addr) => Ok(*addr),
+            TargetAddr::Domain(host, port) => {
+                lookup_host((host.as_str(), *port)).await?.next()
+                .map_or(Err(other("host name didn't resolve to valid IP address")), |a| {
+                    info!("target: {}:{} = {:?}", host, port, a);
+                    Ok(a)
+                })
+            }
+        }
+    }
+}
+
+/// Parses `host:port`, where an IPv6 host is in brackets.
+impl FromStr for TargetAddr {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<TargetAddr> {
+        if let Ok(addr) = s.parse() {
+            return Ok(TargetAddr::Ip(addr));
+        }
+        let (host, port) = s.rsplit_once(':').ok_or_else(|| other("address without a port"))?;
+        let port = port.parse().map_err(|_| other("invalid port"))?;
+        if host.is_empty() || host.contains(':') {
+            return Err(other("invalid host"));
+        }
+        Ok(TargetAddr::Domain(host.to_string(), port))
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// An established outbound connection.
+pub enum Outbound {
+    Tcp(TcpStream),
+    Stream(BoxStream)
+}
+
+impl Outbound {
+    /// The local address of the connection to the target, if this instance
+    /// made it itself.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Outbound::Tcp(s) => s.local_addr().ok(),
+            Outbound::Stream(_) => None,
+        }
+    }
+
+    /// The address of the target, if this instance connected to it itself.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Outbound::Tcp(s) => s.peer_addr().ok(),
+            Outbound::Stream(_) => None,
+        }
+    }
+
+    /// Sends `buf` to the target ahead of anything relayed.
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Outbound::Tcp(s) => s.write_all(buf).await,
+            Outbound::Stream(s) => {
+                s.write_all(buf).await?;
+                s.flush().await
+            }
+        }
+    }
+}
+
+/// Relays between `ep` and the outbound connection `out`.
+pub async fn transfer_outbound(ep: impl Endpoint, out: Outbound, relay: &RelayOptions,
+                               progress: Option<Progress<'_>>)
+    -> io::Result<(u64, u64)>
+{
+    match out {
+        Outbound::Tcp(s) => transfer(ep, new_tcpendpoint(s), relay, progress).await,
+        Outbound::Stream(s) => transfer(ep, new_streamendpoint(s), relay, progress).await,
+    }
+}
+
+/// Picks how each session reaches its target.
+pub trait Router: Send + Sync {
+    /// The connector for a session of `origin` to `target`. A
+    /// `PermissionDenied` error refuses the session.
+    fn route(&self, origin: &Origin, target: &TargetAddr) -> io::Result<&Connector>;
+}
+
+/// A connector routes every session through itself.
+impl Router for Connector {
+    fn route(&self, _: &Origin, _: &TargetAddr) -> io::Result<&Connector> {
+        Ok(self)
+    }
+}
+
+/// How the outbound connections of a listener are made, and what their
+/// targets are told about the clients.
+pub struct Connector {
+    via: Via,
+    send_proxy_protocol: Option<SendProxyProtocolConfig>
+}
+
+enum Via {
+    /// Dial the target from this instance.
+    Direct,
+    /// Ask a remote instance to dial the target.
+    Tunnel(TunnelClient),
+    /// Ask a shadowsocks server to dial the target.
+    Shadowsocks(Transport, Arc<Cipher>),
+    /// Ask the instance at the other end of a reverse tunnel to dial the
+    /// target.
+    Reverse(Arc<Session>),
+    /// Ask another SOCKS5 server to dial the target.
+    Socks5(Transport, Option<Credentials>)
+}
+
+impl Connector {
+    /// Dials targets from this instance.
+    pub fn direct() -> Connector {
+        Connector { via: Via::Direct, send_proxy_protocol: None }
+    }
+
+    /// Hands sessions to `upstream`, or dials targets directly without one.
+    pub fn new(upstream: Option<&UpstreamConfig>) -> io::Result<Connector> {
+        let upstream = match upstream {
+            Some(upstream) => upstream,
+            None => return Ok(Connector::direct()),
+        };
+        let transport = Transport::new(&upstream.transport)?;
+        let via = match upstream.protocol {
+            UpstreamProtocol::Tunnel => {
+                Via::Tunnel(TunnelClient::new(transport, Key::new(&upstream.password), upstream.multiplex))
+            }
+            UpstreamProtocol::Shadowsocks => {
+                let method = upstream.method.ok_or_else(|| other("shadowsocks needs a method"))?;
+                let cipher = Cipher::new(method, &upstream.password)?;
+                Via::Shadowsocks(transport, Arc::new(cipher))
+            }
+            UpstreamProtocol::Socks5 => {
+                let credentials = upstream.username.as_ref().map(|username| Credentials {
+                    username: username.clone(),
+                    password: upstream.password.clone()
+                });
+                Via::Socks5(transport, credentials)
+            }
+        };
+        Ok(Connector { via, send_proxy_protocol: upstream.send_proxy_protocol.clone() })
+    }
+
+    /// Asks the instance at the other end of a reverse tunnel to dial
+    /// targets.
+    pub(crate) fn reverse(session: Arc<Session>) -> Connector {
+        Connector { via: Via::Reverse(session), send_proxy_protocol: None }
+    }
+
+    /// Sends a PROXY protocol header to every target, see
+    /// `SendProxyProtocolConfig`.
+    pub fn send_proxy_protocol(mut self, send: Option<SendProxyProtocolConfig>) -> Connector {
+        self.send_proxy_protocol = send;
+        self
+    }
+
+    /// What the connector is called in the access log.
+    pub fn name(&self) -> &'static str {
+        match self.via {
+            Via::Direct => "direct",
+            Via::Tunnel(_) => "tunnel",
+            Via::Shadowsocks(..) => "shadowsocks",
+            Via::Reverse(_) => "reverse",
+            Via::Socks5(..) => "socks5",
+        }
+    }
+
+    /// Connects a client from `origin` to `target`, and tells the target
+    /// who that is if the connector is meant to.
+    pub async fn connect(&self, origin: &Origin, target: &TargetAddr) -> io::Result<Outbound> {
+        let mut out = self.dial(target).await?;
+        if let Some(ref send) = self.send_proxy_protocol {
+            // Where a target behind an upstream is isn't known, unless it
+            // was asked for by its address.
+            let destination = out.peer_addr().unwrap_or_else(|| match target {
+                TargetAddr::Ip(a) => *a,
+                TargetAddr::Domain(_, port) => {
+                    let any = match origin.addr {
+                        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                    };
+                    SocketAddr::new(any, *port)
+                }
+            });
+            let authority = match target {
+                TargetAddr::Domain(host, _) if send.authority => Some(host.as_str()),
+                _ => None,
+            };
+            let header = Header {
+                source: origin.addr,
+                destination,
+                authority,
+                username: origin.user.as_deref().filter(|_| send.username)
+            };
+            out.write_all(&header.encode(send.version)).await?;
+        }
+        Ok(out)
+    }
+
+    async fn dial(&self, target: &TargetAddr) -> io::Result<Outbound> {
+        match self.via {
+            Via::Direct => {
+                let addr = target.resolve().await?;
+                debug!("proxying to {}", addr);
+                TcpStream::connect(&addr).await.map(Outbound::Tcp)
+            }
+            Via::Tunnel(ref tunnel) => tunnel.connect(target).await.map(Outbound::Stream),
+            Via::Shadowsocks(ref transport, ref cipher) => {
+                let stream = transport.connect().await?;
+                let stream = shadowsocks::connect(stream, cipher.clone(), target).await?;
+                Ok(Outbound::Stream(Box::new(stream)))
+            }
+            Via::Reverse(ref session) => {
+                let stream = tunnel::open_stream(session, target).await?;
+                Ok(Outbound::Stream(Box::new(stream)))
+            }
+            Via::Socks5(ref transport, ref credentials) => {
+                let mut stream = transport.connect().await?;
+                socks5_client::connect(&mut stream, target, credentials.as_ref()).await?;
+                Ok(Outbound::Stream(stream))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    // What a target gets from `connector` for alice at 192.0.2.1:56324.
+    async fn received(connector: Connector) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(listener.local_addr().unwrap());
+        let origin = Origin { addr: "192.0.2.1:56324".parse().unwrap(),
+                              user: Some("alice".to_string()) };
+        let mut out = connector.connect(&origin, &target).await.unwrap();
+        out.write_all(b"hello").await.unwrap();
+        drop(out);
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut got = Vec::new();
+        conn.read_to_end(&mut got).await.unwrap();
+        got
+    }
+
+    #[tokio::test]
+    async fn connectors_send_their_own_proxy_header() {
+        assert_eq!(received(Connector::direct()).await, b"hello");
+        let send = SendProxyProtocolConfig { version: 1, authority: false, username: false };
+        let got = received(Connector::direct().send_proxy_protocol(Some(send))).await;
+        let text = String::from_utf8(got).unwrap();
+        assert!(text.starts_with("PROXY TCP4 192.0.2.1 127.0.0.1 56324 "), "{}", text);
+        assert!(text.ends_with("\r\nhello"), "{}", text);
+    }
+}
@@ -1,78 +1,325 @@
-use tokio_core::net::TcpStream;
-use tokio_io::{io::copy,AsyncRead,AsyncWrite};
-use std::rc::Rc;
-use std::io::{self,Read,Write};
-use std::net::Shutdown;
-use futures::Poll;
-use futures::{Future,future::{result,ok}};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{self as tio, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep, sleep_until, Instant};
+use std::future::pending;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-pub trait Endpoint {
-    type ReadHalf: AsyncRead;
-    type WriteHalf: AsyncWrite;
-    fn split(self) -> (Self::ReadHalf, Self::WriteHalf);
+use crate::buffer_pool::BufferPool;
+use crate::utilities::{timeout_error, TimeoutKind};
+#[cfg(target_os = "linux")]
+use crate::splice;
+
+/// How the bytes of a session are relayed once the handshake is done.
+#[derive(Clone)]
+pub struct RelayOptions {
+    pub buffers: BufferPool,
+    /// Size of the buffer used by each direction.
+    pub buffer_size: usize,
+    /// Time a session may go without a byte in either direction.
+    pub idle_timeout: Option<Duration>,
+    /// Time a session may last in total.
+    pub lifetime: Option<Duration>
 }
 
-pub fn new_tcpendpoint(s: TcpStream) -> impl Endpoint {
-    let mut ds = "unknown address".to_string();
-    if let Ok(add) = s.peer_addr() {
-        ds = format!("{:?}", add);
-    }
-    #[derive(Clone)]
-    struct TcpEndpoint {
-        stream: Rc<TcpStream>,
-        debug_string: String
+/// Which way bytes of a session go: from the client to the target, or
+/// back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down
+}
+
+/// Told about the bytes of a session as they are relayed.
+pub type Progress<'a> = &'a (dyn Fn(Direction, u64) + Sync);
+
+/// When bytes were last seen moving in either direction of a session.
+pub struct Activity<'a> {
+    start: Instant,
+    // milliseconds since `start`
+    last: AtomicU64,
+    progress: Option<Progress<'a>>
+}
+
+impl<'a> Activity<'a> {
+    fn new(progress: Option<Progress<'a>>) -> Activity<'a> {
+        Activity { start: Instant::now(), last: AtomicU64::new(0), progress }
     }
-    impl TcpEndpoint {
-        fn as_async<'a>(&'a self) -> impl AsyncRead + AsyncWrite + 'a {
-            &*self.stream
+
+    /// `n` bytes went the `direction` way.
+    pub fn moved(&self, direction: Direction, n: u64) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+        if let Some(progress) = self.progress {
+            progress(direction, n);
         }
     }
-    impl Read for TcpEndpoint {
-        fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {        
-            self.as_async().read(buf)
+
+    // Resolves once nothing has happened for `limit`.
+    async fn idle(&self, limit: Duration) {
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            if last + limit <= Instant::now() {
+                return;
+            }
+            sleep_until(last + limit).await;
         }
     }
-    impl AsyncRead for TcpEndpoint {
-        fn poll_read(&mut self, buf: &mut [u8]) -> Poll<usize, io::Error> {
-            info!("received {} bytes ({})", buf.len(), self.debug_string);
-            self.as_async().poll_read(buf)
-        }
+}
+
+pub trait Endpoint {
+    type ReadHalf: AsyncRead + Unpin + Send;
+    type WriteHalf: AsyncWrite + Unpin + Send;
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf);
+    /// The raw TCP socket behind this endpoint, if there is one and bytes
+    /// may be moved to and from it without any processing.
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
     }
-    impl Write for TcpEndpoint {
-        fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-            if buf.len()==0 {
-                self.as_async().shutdown()?;
-                Ok(0)
-            } else {
-                self.as_async().write(buf)
-            }
-        }
-        fn flush(&mut self) -> Result<(), io::Error> {
-            self.as_async().flush()
-        }
+}
+
+/// A connection accepted from a client, which is relayed as an `Endpoint`
+/// once the handshake is done.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn into_endpoint(self) -> impl Endpoint + Send;
+}
+
+impl Connection for TcpStream {
+    fn into_endpoint(self) -> impl Endpoint + Send {
+        new_tcpendpoint(self)
     }
-    impl AsyncWrite for TcpEndpoint {
-        fn shutdown(&mut self) -> Poll<(), io::Error> {
-            result(self.as_async().flush().and_then(|()|
-                (&*self.stream).shutdown(Shutdown::Write))).poll()
+}
+
+/// An endpoint for any stream, such as one which has to be decrypted.
+pub fn new_streamendpoint<S>(s: S) -> impl Endpoint + Send
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    struct StreamEndpoint<S>(S);
+    impl<S> Endpoint for StreamEndpoint<S>
+        where S: AsyncRead + AsyncWrite + Send + 'static
+    {
+        type ReadHalf = tio::ReadHalf<S>;
+        type WriteHalf = tio::WriteHalf<S>;
+        fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+            tio::split(self.0)
         }
     }
+    StreamEndpoint(s)
+}
+
+pub fn new_tcpendpoint(s: TcpStream) -> impl Endpoint + Send {
+    struct TcpEndpoint(TcpStream);
     impl Endpoint for TcpEndpoint {
-        type ReadHalf = Self;
-        type WriteHalf = Self;
+        type ReadHalf = OwnedReadHalf;
+        // Shutting down an `OwnedWriteHalf` shuts down the write side of the
+        // socket, so it can be handed out as is.
+        type WriteHalf = OwnedWriteHalf;
         fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
-            (self.clone(), self)
+            self.0.into_split()
+        }
+        fn as_tcp(&self) -> Option<&TcpStream> {
+            Some(&self.0)
         }
     }
-    TcpEndpoint { stream: Rc::new(s),  debug_string: ds }
+    TcpEndpoint(s)
 }
 
-pub fn transfer(ep1: impl Endpoint, ep2: impl Endpoint)
-    -> impl Future<Item=(u64,u64), Error=io::Error>
+/// Relays between the client `ep1` and the target `ep2`, telling `progress`
+/// about the bytes as they go.
+pub async fn transfer(ep1: impl Endpoint, ep2: impl Endpoint, opts: &RelayOptions,
+                      progress: Option<Progress<'_>>)
+    -> io::Result<(u64, u64)>
 {
-    let (ep1r, ep1w) = ep1.split();
-    let (ep2r, ep2w) = ep2.split();
-    copy(ep1r, ep2w).join(copy(ep2r, ep1w))
-        .and_then(|(v1,v2)| ok((v1.0, v2.0)))
+    let activity = Activity::new(progress);
+    let idle = async {
+        match opts.idle_timeout {
+            Some(limit) => activity.idle(limit).await,
+            None => pending().await,
+        }
+    };
+    let lifetime = async {
+        match opts.lifetime {
+            Some(limit) => sleep(limit).await,
+            None => pending().await,
+        }
+    };
+    tokio::select! {
+        res = relay(ep1, ep2, opts, &activity) => res,
+        _ = idle => Err(timeout_error(TimeoutKind::Idle)),
+        _ = lifetime => Err(timeout_error(TimeoutKind::Lifetime)),
+    }
 }
 
+async fn relay(ep1: impl Endpoint, ep2: impl Endpoint, opts: &RelayOptions, activity: &Activity<'_>)
+    -> io::Result<(u64, u64)>
+{
+    // When both sides are plain TCP sockets the bytes never need to pass
+    // through userspace.
+    #[cfg(target_os = "linux")]
+    if let (Some(s1), Some(s2)) = (ep1.as_tcp(), ep2.as_tcp()) {
+        return splice::transfer(s1, s2, activity).await;
+    }
+
+    // Both directions are served from a single buffer of the pool, so a
+    // session never holds on to one half while waiting for the other. Each
+    // direction runs until its own EOF, so a half-closed connection keeps
+    // going in the other direction.
+    let mut buffer = opts.buffers.get(opts.buffer_size * 2).await?;
+    let (buf1, buf2) = buffer.split_at_mut(opts.buffer_size);
+    let (mut ep1r, mut ep1w) = ep1.split();
+    let (mut ep2r, mut ep2w) = ep2.split();
+    tokio::try_join!(copy(&mut ep1r, &mut ep2w, buf1, activity, Direction::Up),
+                     copy(&mut ep2r, &mut ep1w, buf2, activity, Direction::Down))
+}
+
+async fn copy<R, W>(r: &mut R, w: &mut W, buf: &mut [u8], activity: &Activity<'_>,
+                    direction: Direction)
+    -> io::Result<u64>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+{
+    let mut total = 0u64;
+    loop {
+        let n = r.read(buf).await?;
+        if n == 0 {
+            // Pass the EOF on to the peer as a FIN, while the other
+            // direction keeps flowing until it reaches its own EOF.
+            shutdown_write(w).await?;
+            return Ok(total);
+        }
+        activity.moved(direction, n as u64);
+        w.write_all(&buf[..n]).await?;
+        w.flush().await?;
+        total += n as u64;
+    }
+}
+
+// The peer may have closed the whole connection already, in which case
+// there is nobody left to tell about the EOF.
+async fn shutdown_write<W: AsyncWrite + Unpin>(w: &mut W) -> io::Result<()> {
+    match w.shutdown().await {
+        Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::timeout_kind;
+    use tokio::net::TcpListener;
+
+    // Both ends of a loopback TCP connection.
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connect, listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    fn options() -> RelayOptions {
+        RelayOptions { buffers: BufferPool::new(1 << 20), buffer_size: 4096, idle_timeout: None,
+                       lifetime: None }
+    }
+
+    // The client half-closes first, the target goes on answering, and the
+    // session ends once the target half-closes too.
+    async fn half_close<E1, E2>(wrap1: impl FnOnce(TcpStream) -> E1,
+                                wrap2: impl FnOnce(TcpStream) -> E2)
+        where E1: Endpoint + Send + 'static, E2: Endpoint + Send + 'static
+    {
+        let (mut client, proxy1) = pair().await;
+        let (proxy2, mut target) = pair().await;
+        let (ep1, ep2) = (wrap1(proxy1), wrap2(proxy2));
+        let session = tokio::spawn(async move { transfer(ep1, ep2, &options(), None).await });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut got = Vec::new();
+        target.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"request");
+
+        for _ in 0..3 {
+            target.write_all(b"answer").await.unwrap();
+            let mut buf = [0u8; 6];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"answer");
+        }
+        sleep(Duration::from_millis(50)).await;
+        assert!(!session.is_finished());
+
+        target.shutdown().await.unwrap();
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert_eq!(session.await.unwrap().unwrap(), (7, 18));
+    }
+
+    #[tokio::test]
+    async fn copy_passes_on_half_close() {
+        half_close(new_streamendpoint, new_streamendpoint).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn splice_passes_on_half_close() {
+        half_close(new_tcpendpoint, new_tcpendpoint).await;
+    }
+
+    // Runs a session with `opts` whose client sends a byte every 20 ms for
+    // `busy`, or until the session is over, and then nothing. Tells how the session ended and after how
+    // long.
+    async fn timed_out<E1, E2>(wrap1: impl FnOnce(TcpStream) -> E1,
+                               wrap2: impl FnOnce(TcpStream) -> E2, opts: RelayOptions,
+                               busy: Duration)
+        -> (Option<TimeoutKind>, Duration)
+        where E1: Endpoint + Send + 'static, E2: Endpoint + Send + 'static
+    {
+        let (mut client, proxy1) = pair().await;
+        let (proxy2, mut target) = pair().await;
+        let (ep1, ep2) = (wrap1(proxy1), wrap2(proxy2));
+        let start = Instant::now();
+        let session = tokio::spawn(async move { transfer(ep1, ep2, &opts, None).await });
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while target.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        });
+        while start.elapsed() < busy {
+            if client.write_all(b"x").await.is_err() {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        let e = session.await.unwrap().unwrap_err();
+        (timeout_kind(&e), start.elapsed())
+    }
+
+    async fn timeouts_fire<E1, E2>(wrap1: impl Fn(TcpStream) -> E1,
+                                   wrap2: impl Fn(TcpStream) -> E2)
+        where E1: Endpoint + Send + 'static, E2: Endpoint + Send + 'static
+    {
+        let ms = Duration::from_millis;
+        // Bytes going through keep an idle session open.
+        let idle = RelayOptions { idle_timeout: Some(ms(100)), ..options() };
+        let (kind, took) = timed_out(&wrap1, &wrap2, idle, ms(300)).await;
+        assert_eq!(kind, Some(TimeoutKind::Idle));
+        assert!(took >= ms(370) && took < ms(1000), "{:?}", took);
+
+        // ... but not one past its lifetime.
+        let lifetime = RelayOptions { idle_timeout: Some(ms(100)), lifetime: Some(ms(200)),
+                                      ..options() };
+        let (kind, took) = timed_out(&wrap1, &wrap2, lifetime, ms(300)).await;
+        assert_eq!(kind, Some(TimeoutKind::Lifetime));
+        assert!(took >= ms(200) && took < ms(1000), "{:?}", took);
+    }
+
+    #[tokio::test]
+    async fn copy_times_out() {
+        timeouts_fire(new_streamendpoint, new_streamendpoint).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn splice_times_out() {
+        timeouts_fire(new_tcpendpoint, new_tcpendpoint).await;
+    }
+}
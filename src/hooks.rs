@@ -0,0 +1,79 @@
+//! Callbacks into the application embedding the proxy.
+//!
+//! The hooks of a listener are told about every session as it goes: when
+//! the client is accepted and has logged in, which target it asked for,
+//! whether that could be reached, the bytes relayed and how it ended. Some
+//! of them have a say in what happens next:
+//!
+//! ```no_run
+//! use std::io;
+//! use rustoxy::client::Origin;
+//! use rustoxy::connector::TargetAddr;
+//! use rustoxy::hooks::SessionHooks;
+//!
+//! // Keeps clients from sending mail, and sends the ones asking for the
+//! // old site to the new one.
+//! struct Policy;
+//!
+//! impl SessionHooks for Policy {
+//!     fn on_target(&self, _origin: &Origin, target: &mut TargetAddr) -> io::Result<()> {
+//!         if target.port() == 25 {
+//!             return Err(io::Error::new(io::ErrorKind::PermissionDenied, "no mail"));
+//!         }
+//!         if let TargetAddr::Domain(host, _) = target {
+//!             if host == "old.example.com" {
+//!                 *host = "new.example.com".to_string();
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//! ```
+use std::io;
+use std::net::SocketAddr;
+
+use crate::client::Origin;
+use crate::connector::TargetAddr;
+use crate::endpoint::Direction;
+
+/// Told about each session of the listeners it is given to. All methods do
+/// nothing by default.
+///
+/// They are called from the tasks of the sessions, so they had better not
+/// block.
+pub trait SessionHooks: Send + Sync {
+    /// A client from `addr` was accepted. Returning `false` turns it away
+    /// before it gets to say anything.
+    fn on_accept(&self, _addr: SocketAddr) -> bool {
+        true
+    }
+
+    /// The client from `addr` logged in as `user`, and the authenticator
+    /// `verified` the password or not. The returned value is what counts.
+    fn on_auth(&self, _addr: SocketAddr, _user: &str, verified: bool) -> bool {
+        verified
+    }
+
+    /// The client from `origin` asked for `target`, which may be rewritten
+    /// to send it elsewhere. An error refuses the target, and an error of
+    /// kind `PermissionDenied` tells the client it isn't allowed.
+    ///
+    /// Multiplexed tunnels ask for a target once for every stream.
+    fn on_target(&self, _origin: &Origin, _target: &mut TargetAddr) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The connection to `target` is up.
+    fn on_connected(&self, _origin: &Origin, _target: &TargetAddr) {}
+
+    /// `target` couldn't be reached, or was refused by `on_target`.
+    fn on_connect_failed(&self, _origin: &Origin, _target: &TargetAddr, _error: &io::Error) {}
+
+    /// `n` bytes were relayed the `direction` way. Called for every read, so
+    /// it'd better be cheap.
+    fn on_bytes(&self, _origin: &Origin, _direction: Direction, _n: u64) {}
+
+    /// The session of the client from `origin` is over, with the bytes
+    /// relayed each way or what went wrong.
+    fn on_close(&self, _origin: &Origin, _result: &io::Result<(u64, u64)>) {}
+}
@@ -0,0 +1,124 @@
+//! A [SOCKSv5] proxy server, which can also be the remote end of a tunnel,
+//! a shadowsocks server, a transparent proxy or a plain port forward.
+//!
+//! [SOCKSv5]: https://www.ietf.org/rfc/rfc1928.txt
+//!
+//! The binary serves what a configuration file asks for, see the `config`
+//! module:
+//!
+//! ```text
+//! cargo run -- -c rustoxy.toml
+//! ```
+//!
+//! The same server can be embedded into other applications. A
+//! `ProxyServer` is put together with `ProxyServer::builder`, from a
+//! `Config` or listener by listener, and takes a few extension points:
+//!
+//! * An [`auth::Authenticator`] checks the passwords of SOCKS5 clients, in
+//!   place of the `[auth]` table of the configuration.
+//! * A [`connector::Router`] picks the `Connector` each session goes out
+//!   through: straight to its target (`Connector::direct`), or through an
+//!   upstream (`Connector::new`). It may refuse a session as well.
+//! * [`hooks::SessionHooks`] follow each session, from the moment it is
+//!   accepted until it is done, and may turn it away or send it elsewhere.
+//!
+//! `start` binds every listener and hands back a `ServerHandle`, which shuts
+//! them down again:
+//!
+//! ```
+//! use std::io;
+//! use rustoxy::auth::Authenticator;
+//! use rustoxy::client::Origin;
+//! use rustoxy::config::Listener;
+//! use rustoxy::connector::{Connector, Router, TargetAddr};
+//! use rustoxy::hooks::SessionHooks;
+//! use rustoxy::{BoxFuture, ProxyServer};
+//!
+//! // Lets in anyone who knows the word.
+//! struct Password;
+//!
+//! impl Authenticator for Password {
+//!     fn verify<'a>(&'a self, _user: &'a str, password: &'a str)
+//!         -> BoxFuture<'a, io::Result<bool>>
+//!     {
+//!         Box::pin(async move { Ok(password == "swordfish") })
+//!     }
+//! }
+//!
+//! // Keeps clients off the loopback interface of the proxy.
+//! struct NoLoopback(Connector);
+//!
+//! impl Router for NoLoopback {
+//!     fn route(&self, _: &Origin, target: &TargetAddr) -> io::Result<&Connector> {
+//!         match target {
+//!             TargetAddr::Ip(a) if a.ip().is_loopback() => {
+//!                 Err(io::Error::new(io::ErrorKind::PermissionDenied, "loopback"))
+//!             }
+//!             _ => Ok(&self.0),
+//!         }
+//!     }
+//! }
+//!
+//! struct Log;
+//!
+//! impl SessionHooks for Log {
+//!     fn on_connected(&self, origin: &Origin, target: &TargetAddr) {
+//!         println!("{} is connected to {}", origin.addr, target);
+//!     }
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() -> io::Result<()> {
+//!     let server = ProxyServer::builder()
+//!         .listener(Listener::new("127.0.0.1:0".parse().unwrap()))
+//!         .authenticator(Password)
+//!         .router(NoLoopback(Connector::direct()))
+//!         .hooks(Log)
+//!         .build()?
+//!         .start().await?;
+//!     // Later on:
+//!     server.shutdown();
+//!     server.wait().await;
+//!     Ok(())
+//! }
+//! ```
+#[macro_use]
+extern crate log;
+
+mod access_log;
+mod admin;
+pub mod auth;
+pub mod buffer_pool;
+pub mod client;
+pub mod client_channel;
+pub mod codec;
+pub mod config;
+mod conn_limit;
+pub mod connector;
+mod utilities;
+pub mod endpoint;
+pub mod hooks;
+mod lockout;
+pub mod metrics;
+mod mux;
+mod proxy_protocol;
+mod quota;
+mod rate_limit;
+#[cfg(target_os = "linux")]
+mod splice;
+mod reverse;
+pub mod server;
+mod shadowsocks;
+pub mod socks5_client;
+mod tls;
+#[cfg(target_os = "linux")]
+mod transparent;
+mod transport;
+mod tunnel;
+mod websocket;
+
+pub use client::Client;
+pub use client_channel::ClientChannel;
+pub use endpoint::Endpoint;
+pub use server::{ProxyServer, ServerHandle};
+pub use utilities::{BoxFuture, TimeoutKind};
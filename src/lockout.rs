@@ -0,0 +1,244 @@
+//! Protection of passwords against guessing.
+//!
+//! Failed logins are counted for the source address of the client and for
+//! the user it tried. The reply to a failed login is held back, longer for
+//! every failure before it, and an address or user with too many failures
+//! is banned for a while: its logins fail without the password being
+//! checked, and connections from a banned address are reset as soon as
+//! they are accepted. Failures are forgotten after a while without any.
+//!
+//! Bans are logged as warnings starting with `security:`, to the `security`
+//! target, and can be listed and lifted through the admin API. See
+//! `LockoutConfig`.
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::LockoutConfig;
+use crate::utilities::{Swept, SWEEP_EVERY};
+
+/// The failed logins of all SOCKS5 listeners.
+pub struct Lockout {
+    config: LockoutConfig,
+    entries: Mutex<Swept<Key, Entry>>
+}
+
+/// What failed logins are counted against.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Ip(IpAddr),
+    User(String)
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Ip(ip) => write!(f, "address {}", ip),
+            Key::User(user) => write!(f, "user {}", user),
+        }
+    }
+}
+
+/// A ban in force.
+pub struct Ban {
+    pub key: Key,
+    pub failures: u32,
+    pub until: SystemTime
+}
+
+struct Entry {
+    failures: u32,
+    last: Instant,
+    banned_until: Option<Instant>
+}
+
+impl Entry {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+impl Lockout {
+    pub fn new(config: LockoutConfig) -> Lockout {
+        Lockout { config, entries: Mutex::new(Swept::new(SWEEP_EVERY)) }
+    }
+
+    /// Fails if connections from `ip` are to be turned away.
+    pub fn check_addr(&self, ip: IpAddr) -> io::Result<()> {
+        self.check_key(&self.entries.lock().unwrap(), &Key::Ip(ip))
+    }
+
+    /// Fails if `user` may not try to log in from `ip`.
+    pub fn check(&self, ip: IpAddr, user: &str) -> io::Result<()> {
+        let entries = self.entries.lock().unwrap();
+        self.check_key(&entries, &Key::Ip(ip))?;
+        self.check_key(&entries, &Key::User(user.to_string()))
+    }
+
+    fn check_key(&self, entries: &HashMap<Key, Entry>, key: &Key) -> io::Result<()> {
+        match entries.get(key) {
+            Some(entry) if entry.is_banned(Instant::now()) => {
+                let msg = format!("{} is banned", key);
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, msg))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A login of `user` from `ip` failed. Tells how long to hold back the
+    /// reply to it.
+    pub fn failed(&self, ip: IpAddr, user: &str) -> Duration {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window);
+        let mut entries = self.entries.lock().unwrap();
+        // Entries which are neither banned nor remembered are as good as
+        // gone.
+        entries.sweep(|_, e| e.is_banned(now) || now.duration_since(e.last) < window);
+        let mut most = 0;
+        for key in [Key::Ip(ip), Key::User(user.to_string())] {
+            let entry = entries.entry(key.clone())
+                .or_insert(Entry { failures: 0, last: now, banned_until: None });
+            if now.duration_since(entry.last) >= window
+                || entry.banned_until.is_some_and(|until| until <= now)
+            {
+                *entry = Entry { failures: 0, last: now, banned_until: None };
+            }
+            entry.failures += 1;
+            entry.last = now;
+            most = most.max(entry.failures);
+            if entry.failures >= self.config.max_failures && entry.banned_until.is_none() {
+                entry.banned_until = Some(now + Duration::from_secs(self.config.ban));
+                warn!(target: "security", "security: banned {} for {} s after {} failed logins, \
+                                           the last one as {} from {}",
+                      key, self.config.ban, entry.failures, user, ip);
+            }
+        }
+        let doubled = self.config.delay.saturating_mul(1 << (most - 1).min(32));
+        Duration::from_secs(doubled.min(self.config.max_delay))
+    }
+
+    /// `user` logged in, which makes up for its failures so far.
+    pub fn succeeded(&self, user: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let key = Key::User(user.to_string());
+        if entries.get(&key).is_some_and(|e| !e.is_banned(Instant::now())) {
+            entries.remove(&key);
+        }
+    }
+
+    /// The bans in force.
+    pub fn bans(&self) -> Vec<Ban> {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let entries = self.entries.lock().unwrap();
+        entries.iter().filter_map(|(key, e)| {
+            let until = e.banned_until.filter(|&until| until > now)?;
+            Some(Ban { key: key.clone(), failures: e.failures, until: wall + (until - now) })
+        }).collect()
+    }
+
+    /// Lifts the ban of `key`, or every ban, along with the failures which
+    /// led to it. Tells how many bans there were.
+    pub fn lift(&self, key: Option<&Key>) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut lifted = 0;
+        entries.retain(|k, e| {
+            if !e.is_banned(now) || key.is_some_and(|key| key != k) {
+                return true;
+            }
+            warn!(target: "security", "security: lifted the ban of {}", k);
+            lifted += 1;
+            false
+        });
+        lifted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    fn lockout(max_failures: u32, window: u64, ban: u64) -> Lockout {
+        Lockout::new(LockoutConfig { max_failures, window, ban, delay: 1, max_delay: 4 })
+    }
+
+    #[test]
+    fn delays_double_up_to_the_most() {
+        let lockout = lockout(100, 600, 900);
+        let delays: Vec<u64> = (0..5).map(|_| lockout.failed(IP, "alice").as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 4, 4]);
+        // The address has failed before, whichever user it tries.
+        assert_eq!(lockout.failed(IP, "bob"), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn too_many_failures_ban_the_address_and_the_user() {
+        let lockout = lockout(3, 600, 900);
+        let other_ip = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+        for _ in 0..2 {
+            lockout.failed(IP, "alice");
+        }
+        lockout.check(IP, "alice").unwrap();
+        lockout.failed(IP, "alice");
+
+        let denied = |res: io::Result<()>| res.unwrap_err().kind() == io::ErrorKind::PermissionDenied;
+        assert!(denied(lockout.check_addr(IP)));
+        assert!(denied(lockout.check(IP, "bob")));
+        assert!(denied(lockout.check(other_ip, "alice")));
+        lockout.check(other_ip, "bob").unwrap();
+
+        let mut bans = lockout.bans();
+        bans.sort_by_key(|ban| matches!(ban.key, Key::User(_)));
+        assert!(bans.iter().all(|ban| ban.failures == 3 && ban.until > SystemTime::now()));
+        let keys: Vec<Key> = bans.into_iter().map(|ban| ban.key).collect();
+        assert!(keys == [Key::Ip(IP), Key::User("alice".to_string())]);
+
+        // Logging in doesn't make up for failures which led to a ban.
+        lockout.succeeded("alice");
+        assert!(denied(lockout.check(other_ip, "alice")));
+        assert_eq!(lockout.lift(Some(&Key::User("alice".to_string()))), 1);
+        lockout.check(other_ip, "alice").unwrap();
+        assert_eq!(lockout.lift(None), 1);
+        lockout.check_addr(IP).unwrap();
+    }
+
+    #[test]
+    fn logging_in_makes_up_for_failures() {
+        let lockout = lockout(3, 600, 900);
+        let other_ip = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+        lockout.failed(IP, "alice");
+        lockout.failed(other_ip, "alice");
+        lockout.succeeded("alice");
+        lockout.failed(IP, "alice");
+        lockout.check(IP, "alice").unwrap();
+        assert!(lockout.bans().is_empty());
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_the_window() {
+        let lockout = lockout(2, 0, 900);
+        for _ in 0..3 {
+            assert_eq!(lockout.failed(IP, "alice"), Duration::from_secs(1));
+        }
+        lockout.check(IP, "alice").unwrap();
+    }
+
+    #[test]
+    fn bans_run_out() {
+        let lockout = lockout(1, 600, 1);
+        lockout.failed(IP, "alice");
+        assert!(lockout.check_addr(IP).is_err());
+        sleep(Duration::from_millis(1100));
+        lockout.check(IP, "alice").unwrap();
+        assert!(lockout.bans().is_empty());
+        // Failing again starts over rather than piling onto the old ban.
+        assert_eq!(lockout.failed(IP, "alice"), Duration::from_secs(1));
+        assert!(lockout.check_addr(IP).is_err());
+    }
+}
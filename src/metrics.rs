@@ -0,0 +1,61 @@
+//! Process wide counters.
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::utilities::TimeoutKind;
+
+pub struct Metrics {
+    pub sessions: AtomicU64,
+    pub failed_sessions: AtomicU64,
+    /// Connections reset for being over the caps of their listener, or for
+    /// coming from a banned address.
+    pub refused_connections: AtomicU64,
+    /// Access log records the writer fell too far behind on.
+    pub dropped_records: AtomicU64,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub handshake_timeouts: AtomicU64,
+    pub connect_timeouts: AtomicU64,
+    pub idle_timeouts: AtomicU64,
+    pub lifetime_timeouts: AtomicU64
+}
+
+pub static METRICS: Metrics = Metrics {
+    sessions: AtomicU64::new(0),
+    failed_sessions: AtomicU64::new(0),
+    refused_connections: AtomicU64::new(0),
+    dropped_records: AtomicU64::new(0),
+    bytes_up: AtomicU64::new(0),
+    bytes_down: AtomicU64::new(0),
+    handshake_timeouts: AtomicU64::new(0),
+    connect_timeouts: AtomicU64::new(0),
+    idle_timeouts: AtomicU64::new(0),
+    lifetime_timeouts: AtomicU64::new(0)
+};
+
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub fn timeouts(&self, kind: TimeoutKind) -> &AtomicU64 {
+        match kind {
+            TimeoutKind::Handshake => &self.handshake_timeouts,
+            TimeoutKind::Connect => &self.connect_timeouts,
+            TimeoutKind::Idle => &self.idle_timeouts,
+            TimeoutKind::Lifetime => &self.lifetime_timeouts,
+        }
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        write!(f, "sessions={} failed={} refused={} bytes_up={} bytes_down={} \
+                   dropped_records={} timeouts(handshake={} connect={} idle={} lifetime={})",
+               get(&self.sessions), get(&self.failed_sessions), get(&self.refused_connections),
+               get(&self.bytes_up), get(&self.bytes_down), get(&self.dropped_records),
+               get(&self.handshake_timeouts), get(&self.connect_timeouts),
+               get(&self.idle_timeouts), get(&self.lifetime_timeouts))
+    }
+}
@@ -0,0 +1,701 @@
+//! Many streams over a single connection.
+//!
+//! The framing follows yamux. Every frame starts with
+//!
+//! ```text
+//! +---------+------+-------+-----------+--------+
+//! | VERSION | TYPE | FLAGS | STREAM ID | LENGTH |
+//! +---------+------+-------+-----------+--------+
+//! |    1    |  1   |   2   |     4     |   4    |
+//! +---------+------+-------+-----------+--------+
+//! ```
+//!
+//! in network byte order. Data frames carry `LENGTH` bytes of a stream,
+//! window updates give the peer `LENGTH` more bytes of credit on a stream,
+//! and pings are echoed back with the `ACK` flag. A stream is opened by the
+//! `SYN` flag on its first frame, half-closed by `FIN` and aborted by `RST`.
+//! Streams opened by the client side have odd ids, those of the server side
+//! even ones.
+//!
+//! Each direction of a stream may have at most a window of bytes in flight,
+//! which the reader gives back as it consumes them, so one slow stream
+//! never holds up the others.
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::AbortHandle;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::endpoint::{Connection, Endpoint};
+use crate::transport::AsyncStream;
+use crate::utilities::other;
+
+const VERSION: u8 = 0;
+const HEADER_LEN: usize = 12;
+
+const DATA: u8 = 0;
+const WINDOW_UPDATE: u8 = 1;
+const PING: u8 = 2;
+const GO_AWAY: u8 = 3;
+
+const SYN: u16 = 1;
+const ACK: u16 = 2;
+const FIN: u16 = 4;
+const RST: u16 = 8;
+
+const INITIAL_WINDOW: u32 = 256 << 10;
+const MAX_FRAME: usize = 16 << 10;
+// Streams opened by the peer and not accepted yet; more are reset.
+const ACCEPT_BACKLOG: usize = 256;
+// Frames the reader may have queued in reply to the peer, such as PING ACKs
+// and RSTs, before it stops reading. A peer which floods us while never
+// reading runs into backpressure rather than filling our memory.
+const MAX_REPLIES: usize = 64;
+// A ping which isn't answered before the next one is due ends the session.
+const KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Which end of the connection a session is, which decides the ids of
+/// the streams it opens.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Client,
+    Server
+}
+
+struct StreamState {
+    recv: VecDeque<u8>,
+    // Bytes the peer may still send.
+    recv_window: u32,
+    // Bytes read since the last window update.
+    consumed: u32,
+    // Bytes we may still send.
+    send_window: u32,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    local_fin: bool,
+    remote_fin: bool,
+    reset: bool
+}
+
+impl StreamState {
+    fn new() -> StreamState {
+        StreamState {
+            recv: VecDeque::new(), recv_window: INITIAL_WINDOW, consumed: 0,
+            send_window: INITIAL_WINDOW, read_waker: None, write_waker: None,
+            local_fin: false, remote_fin: false, reset: false
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+        if let Some(w) = self.write_waker.take() {
+            w.wake();
+        }
+    }
+}
+
+struct Inner {
+    streams: HashMap<u32, StreamState>,
+    next_id: u32,
+    closed: bool,
+    ping_outstanding: bool
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    closed: Notify,
+    // Frames for the writer task, with the share of `replies` they hold;
+    // an empty one stops it.
+    frames: mpsc::UnboundedSender<Queued>,
+    replies: Arc<Semaphore>
+}
+
+type Queued = (Vec<u8>, Option<OwnedSemaphorePermit>);
+
+impl Shared {
+    fn send(&self, ty: u8, flags: u16, id: u32, length: u32, payload: &[u8]) {
+        // Nobody is left to tell if the writer is gone.
+        let _ = self.frames.send((frame(ty, flags, id, length, payload), None));
+    }
+
+    // Sends a frame the peer asked for, once fewer than `MAX_REPLIES` are
+    // waiting to be written.
+    async fn reply(&self, ty: u8, flags: u16, id: u32, length: u32) {
+        let permit = self.replies.clone().acquire_owned().await.expect("never closed");
+        let _ = self.frames.send((frame(ty, flags, id, length, &[]), Some(permit)));
+    }
+
+    fn close(&self, inner: &mut Inner) {
+        if inner.closed {
+            return;
+        }
+        inner.closed = true;
+        for stream in inner.streams.values_mut() {
+            stream.wake();
+        }
+        self.closed.notify_waiters();
+        self.send(GO_AWAY, 0, 0, 0, &[]);
+        let _ = self.frames.send((Vec::new(), None));
+    }
+}
+
+fn frame(ty: u8, flags: u16, id: u32, length: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&[VERSION, ty]);
+    frame.extend_from_slice(&flags.to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A connection carrying multiplexed streams.
+pub struct Session {
+    shared: Arc<Shared>,
+    incoming: mpsc::Receiver<MuxStream>,
+    tasks: Vec<AbortHandle>
+}
+
+impl Session {
+    pub fn new<S: AsyncStream>(io: S, mode: Mode) -> Session {
+        let (r, w) = tokio::io::split(io);
+        let (frames, rx) = mpsc::unbounded_channel();
+        let (accepted, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let next_id = match mode {
+            Mode::Client => 1,
+            Mode::Server => 2,
+        };
+        let inner = Inner { streams: HashMap::new(), next_id, closed: false, ping_outstanding: false };
+        let shared = Arc::new(Shared { inner: Mutex::new(inner), closed: Notify::new(), frames,
+                                       replies: Arc::new(Semaphore::new(MAX_REPLIES)) });
+        let tasks = vec![
+            tokio::spawn(write_frames(w, rx, shared.clone())).abort_handle(),
+            tokio::spawn(read_frames(r, shared.clone(), accepted)).abort_handle(),
+            tokio::spawn(keepalive(shared.clone())).abort_handle(),
+        ];
+        Session { shared, incoming, tasks }
+    }
+
+    /// Opens a new stream to the peer.
+    pub fn open(&self) -> io::Result<MuxStream> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if inner.closed {
+            return Err(closed());
+        }
+        let id = inner.next_id;
+        inner.next_id = match id.checked_add(2) {
+            Some(next) => next,
+            None => {
+                self.shared.close(&mut inner);
+                return Err(other("multiplexing: stream ids exhausted"));
+            }
+        };
+        inner.streams.insert(id, StreamState::new());
+        self.shared.send(WINDOW_UPDATE, SYN, id, 0, &[]);
+        Ok(MuxStream::new(id, self.shared.clone()))
+    }
+
+    /// Waits for the peer to open a stream. Resolves to `None` once the
+    /// session is over.
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.incoming.recv().await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.inner.lock().unwrap().closed
+    }
+
+    /// Resolves once the session is over.
+    pub async fn closed(&self) {
+        let closed = self.shared.closed.notified();
+        tokio::pin!(closed);
+        // Registered before looking, so the wakeup can't be missed.
+        closed.as_mut().enable();
+        if !self.is_closed() {
+            closed.await;
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        self.shared.close(&mut inner);
+        drop(inner);
+        // The writer stops by itself once it has sent the goodbye.
+        for task in &self.tasks[1..] {
+            task.abort();
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "multiplexed connection closed")
+}
+
+async fn write_frames<W>(mut w: W, mut rx: mpsc::UnboundedReceiver<Queued>, shared: Arc<Shared>)
+    where W: AsyncWrite + Unpin
+{
+    let res: io::Result<()> = async {
+        while let Some((frame, _reply)) = rx.recv().await {
+            if frame.is_empty() {
+                break;
+            }
+            w.write_all(&frame).await?;
+            // Flush only once the queue is drained, so frames get batched.
+            while let Ok((frame, _reply)) = rx.try_recv() {
+                if frame.is_empty() {
+                    return w.shutdown().await;
+                }
+                w.write_all(&frame).await?;
+            }
+            w.flush().await?;
+        }
+        w.shutdown().await
+    }.await;
+    if let Err(e) = res {
+        debug!("multiplexed connection write failed: {}", e);
+    }
+    let mut inner = shared.inner.lock().unwrap();
+    shared.close(&mut inner);
+}
+
+async fn read_frames<R>(mut r: R, shared: Arc<Shared>, accepted: mpsc::Sender<MuxStream>)
+    where R: AsyncRead + Unpin
+{
+    if let Err(e) = read_loop(&mut r, &shared, &accepted).await {
+        debug!("multiplexed connection read failed: {}", e);
+    }
+    let mut inner = shared.inner.lock().unwrap();
+    shared.close(&mut inner);
+}
+
+async fn read_loop<R>(r: &mut R, shared: &Arc<Shared>, accepted: &mpsc::Sender<MuxStream>)
+    -> io::Result<()>
+    where R: AsyncRead + Unpin
+{
+    let mut header = [0u8; HEADER_LEN];
+    loop {
+        match r.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        if header[0] != VERSION {
+            return Err(other("multiplexing: unsupported version"));
+        }
+        let ty = header[1];
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        let id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+        match ty {
+            DATA | WINDOW_UPDATE => {}
+            PING => {
+                if flags & SYN != 0 {
+                    shared.reply(PING, ACK, 0, length).await;
+                } else if flags & ACK != 0 {
+                    shared.inner.lock().unwrap().ping_outstanding = false;
+                }
+                continue;
+            }
+            GO_AWAY => return Ok(()),
+            _ => return Err(other("multiplexing: unknown frame type")),
+        }
+
+        let payload = if ty == DATA {
+            if length > INITIAL_WINDOW {
+                return Err(other("multiplexing: frame exceeds the window"));
+            }
+            let mut payload = vec![0u8; length as usize];
+            r.read_exact(&mut payload).await?;
+            payload
+        } else {
+            Vec::new()
+        };
+
+        if deliver(shared, accepted, ty, flags, id, length, payload)? {
+            shared.reply(WINDOW_UPDATE, RST, id, 0).await;
+        }
+    }
+}
+
+// Hands a frame of stream `id` to the stream, and tells whether the stream
+// is to be reset.
+fn deliver(shared: &Arc<Shared>, accepted: &mpsc::Sender<MuxStream>, ty: u8, flags: u16, id: u32,
+           length: u32, payload: Vec<u8>)
+    -> io::Result<bool>
+{
+    let mut inner = shared.inner.lock().unwrap();
+    if flags & SYN != 0 && !inner.streams.contains_key(&id) {
+        // The peer opens streams with the other parity.
+        if id == 0 || id % 2 == inner.next_id % 2 {
+            return Err(other("multiplexing: invalid stream id"));
+        }
+        inner.streams.insert(id, StreamState::new());
+        if let Err(e) = accepted.try_send(MuxStream::new(id, shared.clone())) {
+            // Nobody takes streams on this side, or not fast enough.
+            if let mpsc::error::TrySendError::Full(_) = e {
+                debug!("multiplexing: too many streams waiting, resetting {}", id);
+            }
+            // Forgotten first, so the stream doesn't reset itself too
+            // when it is dropped.
+            inner.streams.remove(&id);
+            drop(inner);
+            drop(e);
+            return Ok(true);
+        }
+    }
+    // Frames of streams we already dropped are of no interest.
+    let stream = match inner.streams.get_mut(&id) {
+        Some(stream) => stream,
+        None => return Ok(false),
+    };
+    if ty == DATA {
+        if length > stream.recv_window {
+            let first = !stream.reset;
+            stream.reset = true;
+            stream.wake();
+            return Ok(first);
+        }
+        stream.recv_window -= length;
+        stream.recv.extend(payload);
+    } else {
+        stream.send_window = stream.send_window.saturating_add(length);
+    }
+    if flags & FIN != 0 {
+        stream.remote_fin = true;
+    }
+    if flags & RST != 0 {
+        stream.reset = true;
+    }
+    stream.wake();
+    Ok(false)
+}
+
+async fn keepalive(shared: Arc<Shared>) {
+    let mut interval = tokio::time::interval(KEEPALIVE);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut inner = shared.inner.lock().unwrap();
+        if inner.closed {
+            return;
+        }
+        if inner.ping_outstanding {
+            warn!("multiplexed connection keepalive timed out");
+            shared.close(&mut inner);
+            return;
+        }
+        inner.ping_outstanding = true;
+        shared.send(PING, SYN, 0, 0, &[]);
+    }
+}
+
+struct StreamRef {
+    id: u32,
+    shared: Arc<Shared>
+}
+
+impl Drop for StreamRef {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if let Some(stream) = inner.streams.remove(&self.id) {
+            let finished = stream.local_fin && stream.remote_fin;
+            if !(inner.closed || stream.reset || finished) {
+                self.shared.send(WINDOW_UPDATE, RST, self.id, 0, &[]);
+            }
+        }
+    }
+}
+
+/// One stream of a session. Both of its halves are `MuxStream`s too.
+pub struct MuxStream {
+    inner: Arc<StreamRef>
+}
+
+impl MuxStream {
+    fn new(id: u32, shared: Arc<Shared>) -> MuxStream {
+        MuxStream { inner: Arc::new(StreamRef { id, shared }) }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&Shared, &mut StreamState, bool) -> T)
+        -> io::Result<T>
+    {
+        let shared = &self.inner.shared;
+        let mut inner = shared.inner.lock().unwrap();
+        let is_closed = inner.closed;
+        match inner.streams.get_mut(&self.inner.id) {
+            Some(stream) => Ok(f(shared, stream, is_closed)),
+            None => Err(closed()),
+        }
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        let id = self.inner.id;
+        self.with_state(|shared, stream, is_closed| {
+            if !stream.recv.is_empty() {
+                let n = buf.remaining().min(stream.recv.len());
+                let (a, b) = stream.recv.as_slices();
+                let from_a = n.min(a.len());
+                buf.put_slice(&a[..from_a]);
+                buf.put_slice(&b[..n - from_a]);
+                stream.recv.drain(..n);
+                stream.consumed += n as u32;
+                if stream.consumed >= INITIAL_WINDOW / 2 && !stream.remote_fin {
+                    shared.send(WINDOW_UPDATE, 0, id, stream.consumed, &[]);
+                    stream.recv_window += stream.consumed;
+                    stream.consumed = 0;
+                }
+                Poll::Ready(Ok(()))
+            } else if stream.remote_fin {
+                Poll::Ready(Ok(()))
+            } else if stream.reset {
+                Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+            } else if is_closed {
+                Poll::Ready(Err(closed()))
+            } else {
+                stream.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })?
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let id = self.inner.id;
+        self.with_state(|shared, stream, is_closed| {
+            if stream.reset {
+                Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+            } else if is_closed || stream.local_fin {
+                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+            } else if stream.send_window == 0 {
+                stream.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            } else {
+                let n = buf.len().min(stream.send_window as usize).min(MAX_FRAME);
+                stream.send_window -= n as u32;
+                shared.send(DATA, 0, id, n as u32, &buf[..n]);
+                Poll::Ready(Ok(n))
+            }
+        })?
+    }
+    // Frames are flushed by the writer task as soon as it gets to them.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let id = self.inner.id;
+        Poll::Ready(self.with_state(|shared, stream, is_closed| {
+            if !stream.local_fin && !is_closed {
+                stream.local_fin = true;
+                shared.send(DATA, FIN, id, 0, &[]);
+            }
+        }))
+    }
+}
+
+impl Endpoint for MuxStream {
+    type ReadHalf = MuxStream;
+    type WriteHalf = MuxStream;
+    fn split(self) -> (MuxStream, MuxStream) {
+        (MuxStream { inner: self.inner.clone() }, self)
+    }
+}
+
+impl Connection for MuxStream {
+    fn into_endpoint(self) -> impl Endpoint + Send {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::time::timeout;
+
+    fn pair() -> (Session, Session) {
+        let (a, b) = duplex(1 << 20);
+        (Session::new(a, Mode::Client), Session::new(b, Mode::Server))
+    }
+
+    // A session with a raw peer, whose frames are written and read by hand.
+    fn raw(mode: Mode) -> (Session, DuplexStream) {
+        let (a, b) = duplex(1 << 20);
+        (Session::new(a, mode), b)
+    }
+
+    async fn read_frame(r: &mut DuplexStream) -> (u8, u16, u32, u32) {
+        let mut header = [0u8; HEADER_LEN];
+        r.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], VERSION);
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        let id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        (header[1], flags, id, length)
+    }
+
+    #[test]
+    fn frames_are_encoded_in_network_order() {
+        assert_eq!(frame(DATA, SYN | FIN, 0x01020304, 3, b"abc"),
+                   [0, 0, 0, 5, 1, 2, 3, 4, 0, 0, 0, 3, b'a', b'b', b'c']);
+        assert_eq!(frame(WINDOW_UPDATE, RST, 7, 0, &[]), [0, 1, 0, 8, 0, 0, 0, 7, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn streams_round_trip() {
+        let (client, mut server) = pair();
+        let mut c = client.open().unwrap();
+        c.write_all(b"hello").await.unwrap();
+        c.shutdown().await.unwrap();
+        let mut s = server.accept().await.unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"hello");
+        // The other direction is still open.
+        s.write_all(b"world").await.unwrap();
+        s.shutdown().await.unwrap();
+        let mut got = Vec::new();
+        c.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"world");
+    }
+
+    #[tokio::test]
+    async fn windows_are_given_back_as_bytes_are_read() {
+        let (client, mut server) = pair();
+        let mut c = client.open().unwrap();
+        let data: Vec<u8> = (0..4 * INITIAL_WINDOW).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            c.write_all(&sent).await.unwrap();
+            c.shutdown().await.unwrap();
+            c
+        });
+        let mut s = server.accept().await.unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).await.unwrap();
+        assert!(got == data);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn writers_stop_at_the_window() {
+        let (client, mut server) = pair();
+        let mut c = client.open().unwrap();
+        let data = vec![0u8; INITIAL_WINDOW as usize + 1];
+        let mut written = 0;
+        while written < data.len() {
+            match timeout(Duration::from_millis(50), c.write(&data[written..])).await {
+                Ok(n) => written += n.unwrap(),
+                Err(_) => break,
+            }
+        }
+        assert_eq!(written, INITIAL_WINDOW as usize);
+        // Reading half the window gives it back, and the last byte goes out.
+        let mut s = server.accept().await.unwrap();
+        let mut buf = vec![0u8; INITIAL_WINDOW as usize / 2];
+        s.read_exact(&mut buf).await.unwrap();
+        timeout(Duration::from_secs(1), c.write_all(&data[written..])).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn ids_have_the_parity_of_their_side() {
+        let (client, mut peer) = raw(Mode::Client);
+        let mut streams = Vec::new();
+        for expected in [1, 3, 5] {
+            streams.push(client.open().unwrap());
+            assert_eq!(read_frame(&mut peer).await, (WINDOW_UPDATE, SYN, expected, 0));
+        }
+        let (server, mut peer) = raw(Mode::Server);
+        let _s = server.open().unwrap();
+        assert_eq!(read_frame(&mut peer).await, (WINDOW_UPDATE, SYN, 2, 0));
+    }
+
+    #[tokio::test]
+    async fn streams_with_the_wrong_parity_end_the_session() {
+        let (mut server, mut peer) = raw(Mode::Server);
+        peer.write_all(&frame(WINDOW_UPDATE, SYN, 1, 0, &[])).await.unwrap();
+        assert!(server.accept().await.is_some());
+        peer.write_all(&frame(WINDOW_UPDATE, SYN, 4, 0, &[])).await.unwrap();
+        timeout(Duration::from_secs(1), server.closed()).await.unwrap();
+        assert!(server.accept().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_end_the_session() {
+        let mut version = frame(PING, SYN, 0, 0, &[]);
+        version[0] = 1;
+        for bad in [frame(DATA, 0, 1, INITIAL_WINDOW + 1, &[]), frame(9, 0, 1, 0, &[]), version] {
+            let (server, mut peer) = raw(Mode::Server);
+            peer.write_all(&bad).await.unwrap();
+            timeout(Duration::from_secs(1), server.closed()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn overrunning_the_window_resets_the_stream() {
+        let (mut server, mut peer) = raw(Mode::Server);
+        let chunk = vec![0u8; INITIAL_WINDOW as usize / 2];
+        peer.write_all(&frame(DATA, SYN, 1, chunk.len() as u32, &chunk)).await.unwrap();
+        peer.write_all(&frame(DATA, 0, 1, chunk.len() as u32, &chunk)).await.unwrap();
+        peer.write_all(&frame(DATA, 0, 1, 1, &[0])).await.unwrap();
+        let mut s = server.accept().await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, (WINDOW_UPDATE, RST, 1, 0));
+        let mut got = Vec::new();
+        let e = s.read_to_end(&mut got).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered() {
+        let (_server, mut peer) = raw(Mode::Server);
+        peer.write_all(&frame(PING, SYN, 0, 42, &[])).await.unwrap();
+        assert_eq!(read_frame(&mut peer).await, (PING, ACK, 0, 42));
+    }
+
+    #[tokio::test]
+    async fn peers_which_never_read_run_into_backpressure() {
+        let (a, mut peer) = duplex(1024);
+        let _server = Session::new(a, Mode::Server);
+        // The session stops reading once its answers pile up, so writing
+        // pings comes to a halt instead of the answers growing without end.
+        let pings = frame(PING, SYN, 0, 1, &[]).repeat(10_000);
+        assert!(timeout(Duration::from_millis(200), peer.write_all(&pings)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_beyond_the_backlog_are_reset() {
+        let (mut server, mut peer) = raw(Mode::Server);
+        let last = 2 * ACCEPT_BACKLOG as u32 + 1;
+        for id in (1..=last).step_by(2) {
+            peer.write_all(&frame(WINDOW_UPDATE, SYN, id, 0, &[])).await.unwrap();
+        }
+        assert_eq!(read_frame(&mut peer).await, (WINDOW_UPDATE, RST, last, 0));
+        for _ in 0..ACCEPT_BACKLOG {
+            assert!(server.accept().await.is_some());
+        }
+        assert!(!server.is_closed());
+    }
+
+    #[tokio::test]
+    async fn running_out_of_ids_ends_the_session() {
+        let (client, _peer) = raw(Mode::Client);
+        client.shared.inner.lock().unwrap().next_id = u32::MAX;
+        assert!(client.open().is_err());
+        assert!(client.is_closed());
+    }
+}
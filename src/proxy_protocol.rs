@@ -0,0 +1,299 @@
+//! The PROXY protocol of HAProxy, by which a load balancer tells the
+//! listener behind it who its clients are.
+//!
+//! The balancer sends a header ahead of the bytes of each client, either as
+//! a line of text (version 1):
+//!
+//! ```text
+//! PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n
+//! ```
+//!
+//! or in binary (version 2): a 12 byte signature, the version and command,
+//! the address family, the length of the rest, and the addresses followed by
+//! optional TLVs. Both are accepted. See
+//! <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>.
+//!
+//! Anybody could send a header, so it is only read from the peers a
+//! listener trusts, see `ProxyProtocolConfig`.
+//!
+//! Headers are also sent to targets which want to know who the clients are,
+//! see `SendProxyProtocolConfig`. Version 2 headers can carry the host name
+//! the client asked for, as a `PP2_TYPE_AUTHORITY` TLV, and the user it
+//! logged in as, as a TLV of type 0xE0 from the range left for custom use.
+use tokio::io::{AsyncRead, AsyncReadExt};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+use crate::utilities::other;
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// The longest line of version 1, CRLF included.
+const MAX_LINE: usize = 107;
+
+const VERSION_2: u8 = 0x20;
+const CMD_LOCAL: u8 = 0;
+const CMD_PROXY: u8 = 1;
+const AF_INET: u8 = 1;
+const AF_INET6: u8 = 2;
+const TRANSPORT_STREAM: u8 = 1;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_USERNAME: u8 = 0xe0;
+
+/// What a header tells a target about a session.
+pub struct Header<'a> {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    /// Only sent by version 2, as are the other TLVs.
+    pub authority: Option<&'a str>,
+    pub username: Option<&'a str>
+}
+
+impl Header<'_> {
+    /// The header in the given version of the protocol.
+    pub fn encode(&self, version: u8) -> Vec<u8> {
+        // Both addresses have to be of the same family, and IPv6 holds
+        // both.
+        let (src, dst) = match (self.source, self.destination) {
+            (src @ SocketAddr::V4(_), dst @ SocketAddr::V4(_)) => (src, dst),
+            (src, dst) => (to_v6(src), to_v6(dst)),
+        };
+        if version == 1 {
+            let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+            return format!("PROXY {} {} {} {} {}\r\n", family, src.ip(), dst.ip(), src.port(),
+                           dst.port()).into_bytes();
+        }
+
+        let mut body = Vec::new();
+        let family = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => {
+                body.extend_from_slice(&s.octets());
+                body.extend_from_slice(&d.octets());
+                AF_INET
+            }
+            (s, d) => {
+                body.extend_from_slice(&to_v6_ip(s).octets());
+                body.extend_from_slice(&to_v6_ip(d).octets());
+                AF_INET6
+            }
+        };
+        body.extend_from_slice(&src.port().to_be_bytes());
+        body.extend_from_slice(&dst.port().to_be_bytes());
+        let tlvs = [(PP2_TYPE_AUTHORITY, self.authority), (PP2_TYPE_USERNAME, self.username)];
+        for (kind, value) in tlvs {
+            if let Some(value) = value {
+                body.push(kind);
+                body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                body.extend_from_slice(value.as_bytes());
+            }
+        }
+
+        let mut header = SIGNATURE.to_vec();
+        header.push(VERSION_2 | CMD_PROXY);
+        header.push(family << 4 | TRANSPORT_STREAM);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(&body);
+        header
+    }
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(to_v6_ip(addr.ip()).into(), addr.port())
+}
+
+fn to_v6_ip(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Reads the header at the start of `s`. Resolves to the address of the
+/// client, or `None` if the balancer connected on its own behalf, as it
+/// does for health checks.
+pub async fn read_header<S>(s: &mut S) -> io::Result<Option<SocketAddr>>
+    where S: AsyncRead + Unpin
+{
+    // Headers of either version are longer than the signature.
+    let mut start = [0u8; 12];
+    s.read_exact(&mut start).await?;
+    if start == SIGNATURE {
+        read_v2(s).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(s, &start).await
+    } else {
+        Err(other("no PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S>(s: &mut S, start: &[u8]) -> io::Result<Option<SocketAddr>>
+    where S: AsyncRead + Unpin
+{
+    // Byte by byte, so nothing after the header is consumed.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_LINE {
+            return Err(other("PROXY header too long"));
+        }
+        let mut b = [0u8];
+        s.read_exact(&mut b).await?;
+        line.push(b[0]);
+    }
+    let invalid = || other("invalid PROXY header");
+    let line = str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _, port, _] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid())?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid());
+            }
+            let port = port.parse().map_err(|_| invalid())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+async fn read_v2<S>(s: &mut S) -> io::Result<Option<SocketAddr>>
+    where S: AsyncRead + Unpin
+{
+    let mut head = [0u8; 4];
+    s.read_exact(&mut head).await?;
+    if head[0] & 0xf0 != VERSION_2 {
+        return Err(other("unsupported PROXY protocol version"));
+    }
+    let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+    let mut rest = vec![0u8; len];
+    s.read_exact(&mut rest).await?;
+    match head[0] & 0x0f {
+        CMD_LOCAL => return Ok(None),
+        CMD_PROXY => {}
+        _ => return Err(other("unknown PROXY command")),
+    }
+    // The high nibble is the address family, the low one the transport
+    // protocol, which doesn't matter here. TLVs follow the addresses.
+    let too_short = || other("PROXY header too short");
+    match head[1] >> 4 {
+        AF_INET => {
+            let a = rest.get(..12).ok_or_else(too_short)?;
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([a[8], a[9]]))))
+        }
+        AF_INET6 => {
+            let a = rest.get(..36).ok_or_else(too_short)?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&a[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([a[32], a[33]]))))
+        }
+        // Unix sockets, or an unspecified family.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(source: &str, destination: &str) -> Header<'a> {
+        Header { source: source.parse().unwrap(), destination: destination.parse().unwrap(),
+                 authority: None, username: None }
+    }
+
+    async fn read(bytes: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let mut s = bytes;
+        read_header(&mut s).await
+    }
+
+    #[test]
+    fn version_1_is_a_line_of_text() {
+        assert_eq!(header("192.0.2.1:56324", "198.51.100.1:443").encode(1),
+                   b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n");
+        // Mixed families are sent as IPv6.
+        assert_eq!(header("192.0.2.1:56324", "[2001:db8::1]:443").encode(1),
+                   b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::1 56324 443\r\n");
+    }
+
+    #[test]
+    fn version_2_is_binary() {
+        let mut h = header("192.0.2.1:56324", "198.51.100.1:443");
+        h.authority = Some("example.com");
+        h.username = Some("alice");
+        let mut expected = SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12 + 14 + 8]);
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 0xbb]);
+        expected.extend_from_slice(&[PP2_TYPE_AUTHORITY, 0, 11]);
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&[PP2_TYPE_USERNAME, 0, 5]);
+        expected.extend_from_slice(b"alice");
+        assert_eq!(h.encode(2), expected);
+    }
+
+    #[tokio::test]
+    async fn headers_round_trip() {
+        for (source, destination) in [("192.0.2.1:56324", "198.51.100.1:443"),
+                                      ("[2001:db8::1]:56324", "[2001:db8::2]:443")] {
+            let mut h = header(source, destination);
+            h.authority = Some("example.com");
+            for version in [1, 2] {
+                assert_eq!(read(&h.encode(version)).await.unwrap(), Some(h.source));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn bytes_after_the_header_are_left() {
+        for version in [1, 2] {
+            let mut bytes = header("192.0.2.1:56324", "198.51.100.1:443").encode(version);
+            bytes.extend_from_slice(b"\x05\x01\x00");
+            let mut s = &bytes[..];
+            read_header(&mut s).await.unwrap();
+            assert_eq!(s, b"\x05\x01\x00");
+        }
+    }
+
+    #[tokio::test]
+    async fn local_and_unknown_headers_carry_no_address() {
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(read(b"PROXY UNKNOWN 192.0.2.1 198.51.100.1 1 2\r\n").await.unwrap(), None);
+        let mut local = SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0, 0, 0]);
+        assert_eq!(read(&local).await.unwrap(), None);
+        let mut unspecified = SIGNATURE.to_vec();
+        unspecified.extend_from_slice(&[0x21, 0, 0, 0]);
+        assert_eq!(read(&unspecified).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_headers_are_refused() {
+        let v2 = header("192.0.2.1:56324", "198.51.100.1:443").encode(2);
+        let mut short = v2.clone();
+        short.truncate(v2.len() - 1);
+        let mut lying = SIGNATURE.to_vec();
+        lying.extend_from_slice(&[0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        let mut version = v2.clone();
+        version[12] = 0x11;
+        let mut command = v2.clone();
+        command[12] = 0x22;
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(MAX_LINE));
+        let cases: [&[u8]; 10] = [
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443",
+            long.as_bytes(),
+            &short,
+            &lying,
+            &version,
+            &command,
+        ];
+        for bytes in cases {
+            assert!(read(bytes).await.is_err(), "{:?}", String::from_utf8_lossy(bytes));
+        }
+        assert!(read(&v2[..8]).await.is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Bytes users may relay a day and a month.
+//!
+//! The bytes of a user's sessions are counted as they are relayed, so a
+//! user goes over its quota in the middle of a session rather than at its
+//! end. Its new sessions are then refused until the next day or month (in
+//! UTC), and those in progress are cut too if the configuration says so.
+//!
+//! Usage is saved every few seconds to a file, or to a SQLite database with
+//! the `sqlite` feature, and once more when the server is shut down. It is
+//! read back when the server starts. See `QuotaConfig`.
+use tokio::sync::Notify;
+use tokio::time::sleep;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{QuotaConfig, QuotaLimits, QuotaStore};
+#[cfg(feature = "sqlite")]
+use crate::utilities::sql_error;
+use crate::utilities::{civil, other};
+
+// How often usage is saved. What was counted since is lost with the process,
+// unless the server is shut down.
+const SAVE_EVERY: Duration = Duration::from_secs(10);
+
+/// What users relayed, and how much they may.
+pub struct Quotas {
+    config: QuotaConfig,
+    accounts: Mutex<HashMap<String, Arc<Account>>>,
+    store: Option<Store>
+}
+
+impl Quotas {
+    /// The quotas of `config`, with the usage it was saved with.
+    pub fn open(config: &QuotaConfig) -> io::Result<Quotas> {
+        let store = config.store.as_ref().map(Store::open).transpose()?;
+        let mut accounts = HashMap::new();
+        for (user, used) in store.as_ref().map_or(Ok(Vec::new()), |s| s.load())? {
+            let account = Account::new(config.limits(&user), used);
+            accounts.insert(user, Arc::new(account));
+        }
+        Ok(Quotas { config: config.clone(), accounts: Mutex::new(accounts), store })
+    }
+
+    /// The account of `user`, if there is any quota for it.
+    pub fn account(&self, user: &str) -> Option<Arc<Account>> {
+        let limits = self.config.limits(user);
+        if limits.daily.is_none() && limits.monthly.is_none() {
+            return None;
+        }
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(user.to_string())
+            .or_insert_with(|| Arc::new(Account::new(limits, Used::default())));
+        Some(account.clone())
+    }
+
+    /// Whether sessions are cut once their user goes over.
+    pub fn cut(&self) -> bool {
+        self.config.cut
+    }
+
+    /// Saves usage every so often, and once more when `stop` is notified.
+    pub async fn persist(self: Arc<Quotas>, stop: Arc<Notify>) {
+        if self.store.is_none() {
+            return;
+        }
+        loop {
+            let stopped = tokio::select! {
+                _ = sleep(SAVE_EVERY) => false,
+                _ = stop.notified() => true,
+            };
+            let quotas = self.clone();
+            match tokio::task::spawn_blocking(move || quotas.save()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("saving quota usage failed: {}", e),
+                Err(e) => error!("saving quota usage failed: {}", e),
+            }
+            if stopped {
+                return;
+            }
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let (dirty, usage): (Vec<_>, Vec<_>) = {
+            let accounts = self.accounts.lock().unwrap();
+            let dirty: Vec<_> = accounts.values()
+                .filter(|a| a.dirty.swap(false, Ordering::Relaxed))
+                .cloned()
+                .collect();
+            // Unless somebody relayed something, what was saved last time
+            // still holds.
+            if dirty.is_empty() {
+                return Ok(());
+            }
+            let usage = accounts.iter().map(|(user, a)| (user.clone(), *a.used.lock().unwrap()));
+            (dirty, usage.collect())
+        };
+        let res = match self.store {
+            Some(ref store) => store.save(&usage),
+            None => Ok(()),
+        };
+        // What wasn't saved is left for the next time.
+        if res.is_err() {
+            for account in dirty {
+                account.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+}
+
+/// The usage of a user.
+pub struct Account {
+    limits: QuotaLimits,
+    used: Mutex<Used>,
+    over: Notify,
+    dirty: AtomicBool
+}
+
+impl Account {
+    fn new(limits: QuotaLimits, used: Used) -> Account {
+        Account { limits, used: Mutex::new(used), over: Notify::new(), dirty: AtomicBool::new(false) }
+    }
+
+    fn is_over(&self, used: &Used) -> bool {
+        self.limits.daily.is_some_and(|limit| used.daily >= limit)
+            || self.limits.monthly.is_some_and(|limit| used.monthly >= limit)
+    }
+
+    /// Whether the user is over its quota.
+    pub fn exceeded(&self) -> bool {
+        let mut used = self.used.lock().unwrap();
+        used.roll(SystemTime::now());
+        self.is_over(&used)
+    }
+
+    /// The user relayed `n` more bytes.
+    pub fn add(&self, n: u64) {
+        let mut used = self.used.lock().unwrap();
+        used.roll(SystemTime::now());
+        used.daily += n;
+        used.monthly += n;
+        self.dirty.store(true, Ordering::Relaxed);
+        if self.is_over(&used) {
+            self.over.notify_waiters();
+        }
+    }
+
+    /// Resolves once the user is over its quota.
+    pub async fn exhausted(&self) {
+        loop {
+            let notified = self.over.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.exceeded() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+// Bytes relayed on a day and in a month, as days since the epoch and months
+// since year 0.
+#[derive(Clone, Copy, Default)]
+struct Used {
+    day: i64,
+    daily: u64,
+    month: i64,
+    monthly: u64
+}
+
+impl Used {
+    // Starts over on another day or in another month.
+    fn roll(&mut self, now: SystemTime) {
+        let day = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64 / 86400;
+        if day == self.day {
+            return;
+        }
+        let (year, month, ..) = civil(now);
+        let month = year * 12 + month as i64 - 1;
+        if month != self.month {
+            self.month = month;
+            self.monthly = 0;
+        }
+        self.day = day;
+        self.daily = 0;
+    }
+}
+
+enum Store {
+    // A line of `day daily month monthly user` for every user.
+    File(PathBuf),
+    #[cfg(feature = "sqlite")]
+    Sqlite(Mutex<rusqlite::Connection>)
+}
+
+impl Store {
+    fn open(config: &QuotaStore) -> io::Result<Store> {
+        match config {
+            QuotaStore::File(path) => Ok(Store::File(path.clone())),
+            #[cfg(feature = "sqlite")]
+            QuotaStore::Sqlite(path) => {
+                let db = rusqlite::Connection::open(path).map_err(sql_error)?;
+                db.execute("CREATE TABLE IF NOT EXISTS usage (user TEXT PRIMARY KEY, \
+                            day INTEGER NOT NULL, daily INTEGER NOT NULL, \
+                            month INTEGER NOT NULL, monthly INTEGER NOT NULL)", [])
+                    .map_err(sql_error)?;
+                Ok(Store::Sqlite(Mutex::new(db)))
+            }
+            #[cfg(not(feature = "sqlite"))]
+            QuotaStore::Sqlite(_) => unreachable!("SQLite is refused by Config::validate"),
+        }
+    }
+
+    fn load(&self) -> io::Result<Vec<(String, Used)>> {
+        match self {
+            Store::File(path) => {
+                let text = match fs::read_to_string(path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                    text => text?,
+                };
+                let invalid = || other(&format!("invalid quota usage in {}", path.display()));
+                let mut usage = Vec::new();
+                for line in text.lines() {
+                    let mut fields = line.splitn(5, ' ');
+                    let mut number = || {
+                        fields.next().and_then(|f| f.parse::<i64>().ok()).ok_or_else(invalid)
+                    };
+                    let (day, daily, month, monthly) = (number()?, number()?, number()?, number()?);
+                    let user = fields.next().ok_or_else(invalid)?;
+                    let used = Used { day, daily: daily as u64, month, monthly: monthly as u64 };
+                    usage.push((user.to_string(), used));
+                }
+                Ok(usage)
+            }
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => {
+                let db = db.lock().unwrap();
+                let mut query = db.prepare("SELECT user, day, daily, month, monthly FROM usage")
+                    .map_err(sql_error)?;
+                let rows = query.query_map([], |row| {
+                    let used = Used {
+                        day: row.get(1)?,
+                        daily: row.get::<_, i64>(2)? as u64,
+                        month: row.get(3)?,
+                        monthly: row.get::<_, i64>(4)? as u64
+                    };
+                    Ok((row.get(0)?, used))
+                }).map_err(sql_error)?;
+                rows.collect::<Result<_, _>>().map_err(sql_error)
+            }
+        }
+    }
+
+    fn save(&self, usage: &[(String, Used)]) -> io::Result<()> {
+        match self {
+            Store::File(path) => {
+                let mut text = String::new();
+                // A line can't hold a user whose name has a line break in it.
+                for (user, u) in usage.iter().filter(|(user, _)| !user.contains('\n')) {
+                    text += &format!("{} {} {} {} {}\n", u.day, u.daily, u.month, u.monthly, user);
+                }
+                // Written next to it first, so a crash never leaves half a
+                // file behind.
+                let mut tmp = path.clone().into_os_string();
+                tmp.push(".tmp");
+                fs::write(&tmp, text)?;
+                fs::rename(&tmp, path)
+            }
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(db) => {
+                let mut db = db.lock().unwrap();
+                let tx = db.transaction().map_err(sql_error)?;
+                for (user, u) in usage {
+                    tx.execute("INSERT INTO usage VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT (user) \
+                                DO UPDATE SET day = ?2, daily = ?3, month = ?4, monthly = ?5",
+                               (user, u.day, u.daily as i64, u.month, u.monthly as i64))
+                        .map_err(sql_error)?;
+                }
+                tx.commit().map_err(sql_error)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(path: PathBuf) -> Arc<Quotas> {
+        let config = QuotaConfig { daily: Some(1 << 20), monthly: None, cut: false,
+                                   store: Some(QuotaStore::File(path)), users: HashMap::new() };
+        Arc::new(Quotas::open(&config).unwrap())
+    }
+
+    #[test]
+    fn usage_which_failed_to_save_is_saved_next_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("usage");
+        let quotas = quotas(path.clone());
+        let account = quotas.account("alice").unwrap();
+        account.add(100);
+        assert!(quotas.save().is_err());
+        assert!(account.dirty.load(Ordering::Relaxed));
+        fs::create_dir(dir.path().join("missing")).unwrap();
+        quotas.save().unwrap();
+        assert!(!account.dirty.load(Ordering::Relaxed));
+        assert!(fs::read_to_string(&path).unwrap().ends_with(" 100 alice\n"));
+    }
+
+    #[tokio::test]
+    async fn usage_is_saved_once_more_when_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usage");
+        let quotas = quotas(path.clone());
+        quotas.account("alice").unwrap().add(100);
+        let stop = Arc::new(Notify::new());
+        let persist = tokio::spawn(quotas.clone().persist(stop.clone()));
+        stop.notify_one();
+        persist.await.unwrap();
+        assert!(fs::read_to_string(&path).unwrap().ends_with(" 100 alice\n"));
+    }
+}
@@ -0,0 +1,347 @@
+//! Upload and download rates of clients, limited with token buckets.
+//!
+//! A bucket holds up to a second worth of bytes, and is refilled at its
+//! rate. Bytes are paid for after they were read from the client (upload)
+//! or written to it (download). A half which took more than there was waits
+//! until the debt is paid off before it goes on, so the sessions sharing a
+//! bucket take turns and get about the same share of it, however many of
+//! them one user opens.
+//!
+//! A session pays into the bucket of every limit it falls under: the one of
+//! all listeners, of its listener, of its source address and of its user.
+//! See `RateLimitConfig`.
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Sleep};
+use std::future::Future;
+use std::hash::Hash;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::client::Origin;
+use crate::config::{RateConfig, RateLimitConfig};
+use crate::endpoint::Endpoint;
+use crate::utilities::{Swept, SWEEP_EVERY};
+
+/// Tokens (bytes, or whatever is limited) per second, and what was left
+/// over or is owed.
+pub struct Bucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>
+}
+
+impl Bucket {
+    /// A full bucket, which holds a second worth of `rate`.
+    pub fn new(rate: u64) -> Bucket {
+        let rate = rate as f64;
+        Bucket { rate, state: Mutex::new((rate, Instant::now())) }
+    }
+
+    // The tokens there are now.
+    fn refill(&self, state: &mut (f64, Instant)) {
+        let now = Instant::now();
+        state.0 = (state.0 + now.duration_since(state.1).as_secs_f64() * self.rate).min(self.rate);
+        state.1 = now;
+    }
+
+    // Takes `n` tokens out, going into debt if there weren't as many, and
+    // tells how long it takes until the debt is paid off.
+    fn take(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.0 -= n as f64;
+        if state.0 >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.0 / self.rate)
+        }
+    }
+
+    /// Takes a token out if there is one.
+    pub fn try_take(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        if state.0 < 1.0 {
+            return false;
+        }
+        state.0 -= 1.0;
+        true
+    }
+
+    /// Whether the bucket is as full as it gets.
+    pub fn is_full(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.0 >= self.rate
+    }
+}
+
+// The buckets of one limit.
+#[derive(Clone, Default)]
+struct Pair {
+    up: Option<Arc<Bucket>>,
+    down: Option<Arc<Bucket>>
+}
+
+impl Pair {
+    fn new(rate: &RateConfig) -> Pair {
+        Pair {
+            up: rate.upload.map(|r| Arc::new(Bucket::new(r))),
+            down: rate.download.map(|r| Arc::new(Bucket::new(r)))
+        }
+    }
+}
+
+// A limit of its own for every address or user.
+struct Keyed<K> {
+    rate: RateConfig,
+    pairs: Mutex<Swept<K, Pair>>
+}
+
+impl<K: Hash + Eq> Keyed<K> {
+    fn new(rate: &RateConfig) -> Keyed<K> {
+        Keyed { rate: rate.clone(), pairs: Mutex::new(Swept::new(SWEEP_EVERY)) }
+    }
+
+    fn get(&self, key: K) -> Pair {
+        let mut pairs = self.pairs.lock().unwrap();
+        // Buckets nobody else holds on to anymore which filled up again can
+        // just as well be made again. Those still in debt are kept, or a
+        // client could pay it off by coming back.
+        let kept = |b: &Option<Arc<Bucket>>| {
+            b.as_ref().is_some_and(|b| Arc::strong_count(b) > 1 || !b.is_full())
+        };
+        pairs.sweep(|_, pair| kept(&pair.up) || kept(&pair.down));
+        pairs.entry(key).or_insert_with(|| Pair::new(&self.rate)).clone()
+    }
+}
+
+/// The limits the sessions of a listener fall under. Clones share them.
+#[derive(Clone)]
+pub struct Limiter {
+    global: Pair,
+    total: Pair,
+    per_ip: Option<Arc<Keyed<IpAddr>>>,
+    per_user: Option<Arc<Keyed<String>>>
+}
+
+/// The limit of all listeners, which are handed to each of them.
+#[derive(Clone, Default)]
+pub struct GlobalLimit(Pair);
+
+impl GlobalLimit {
+    pub fn new(rate: Option<&RateConfig>) -> GlobalLimit {
+        GlobalLimit(rate.map(Pair::new).unwrap_or_default())
+    }
+}
+
+impl Limiter {
+    /// The limits of a listener, if there are any.
+    pub fn new(global: &GlobalLimit, config: Option<&RateLimitConfig>) -> Option<Limiter> {
+        let limiter = Limiter {
+            global: global.0.clone(),
+            total: config.and_then(|c| c.total.as_ref()).map(Pair::new).unwrap_or_default(),
+            per_ip: config.and_then(|c| c.per_ip.as_ref()).map(|r| Arc::new(Keyed::new(r))),
+            per_user: config.and_then(|c| c.per_user.as_ref()).map(|r| Arc::new(Keyed::new(r)))
+        };
+        let unlimited = |p: &Pair| p.up.is_none() && p.down.is_none();
+        if unlimited(&limiter.global) && unlimited(&limiter.total) && limiter.per_ip.is_none()
+            && limiter.per_user.is_none()
+        {
+            return None;
+        }
+        Some(limiter)
+    }
+
+    /// `ep` of a client from `origin`, held to the limits it falls under.
+    pub fn limit<E: Endpoint>(&self, ep: E, origin: &Origin) -> RateLimited<E> {
+        let mut pairs = vec![self.global.clone(), self.total.clone()];
+        if let Some(ref per_ip) = self.per_ip {
+            pairs.push(per_ip.get(origin.addr.ip()));
+        }
+        if let (Some(ref per_user), Some(ref user)) = (&self.per_user, &origin.user) {
+            pairs.push(per_user.get(user.clone()));
+        }
+        RateLimited {
+            ep,
+            up: pairs.iter().filter_map(|p| p.up.clone()).collect(),
+            down: pairs.iter().filter_map(|p| p.down.clone()).collect()
+        }
+    }
+}
+
+/// The endpoint of a client, whose reads pay into the upload buckets and
+/// whose writes pay into the download ones.
+pub struct RateLimited<E> {
+    ep: E,
+    up: Vec<Arc<Bucket>>,
+    down: Vec<Arc<Bucket>>
+}
+
+impl<E: Endpoint> Endpoint for RateLimited<E> {
+    type ReadHalf = Limited<E::ReadHalf>;
+    type WriteHalf = Limited<E::WriteHalf>;
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        let (r, w) = self.ep.split();
+        (Limited::new(r, self.up), Limited::new(w, self.down))
+    }
+
+    // Spliced bytes would go around the buckets.
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        match (self.up.is_empty(), self.down.is_empty()) {
+            (true, true) => self.ep.as_tcp(),
+            _ => None,
+        }
+    }
+}
+
+/// A half of a `RateLimited` endpoint.
+pub struct Limited<T> {
+    inner: T,
+    buckets: Vec<Arc<Bucket>>,
+    // Until the debt of the last read or write is paid off.
+    delay: Option<Pin<Box<Sleep>>>
+}
+
+impl<T> Limited<T> {
+    fn new(inner: T, buckets: Vec<Arc<Bucket>>) -> Limited<T> {
+        Limited { inner, buckets, delay: None }
+    }
+
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(ref mut delay) = self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    fn pay(&mut self, n: usize) {
+        let wait = self.buckets.iter().map(|b| b.take(n)).max().unwrap_or_default();
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(sleep(wait)));
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Limited<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        ready!(self.poll_delay(cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let n = buf.filled().len() - before;
+        self.pay(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Limited<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        ready!(self.poll_delay(cx));
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.pay(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::endpoint::new_streamendpoint;
+
+    const RATE: u64 = 64 << 10;
+
+    fn origin(addr: &str) -> Origin {
+        Origin { addr: addr.parse().unwrap(), user: Some("alice".to_string()) }
+    }
+
+    // Uploads `n` bytes through `limiter` as the client from `origin`, a
+    // few KiB at a time, and tells how long that took.
+    async fn upload(limiter: &Limiter, origin: &Origin, n: usize) -> Duration {
+        let (client, mut peer) = tokio::io::duplex(n);
+        let (mut r, _w) = limiter.limit(new_streamendpoint(client), origin).split();
+        peer.write_all(&vec![0u8; n]).await.unwrap();
+        let start = Instant::now();
+        let mut buf = [0u8; 4096];
+        let mut left = n;
+        while left > 0 {
+            left -= r.read(&mut buf[..left.min(4096)]).await.unwrap();
+        }
+        start.elapsed()
+    }
+
+    #[test]
+    fn buckets_hold_a_second_worth() {
+        let bucket = Bucket::new(2);
+        assert!(bucket.is_full());
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        assert!(!bucket.is_full());
+        assert_eq!(Bucket::new(1000).take(1500), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn listeners_without_limits_have_no_limiter() {
+        assert!(Limiter::new(&GlobalLimit::default(), None).is_none());
+        let config = RateLimitConfig { total: None, per_ip: None, per_user: None };
+        assert!(Limiter::new(&GlobalLimit::default(), Some(&config)).is_none());
+    }
+
+    #[tokio::test]
+    async fn transfers_take_as_long_as_the_rate_says() {
+        let rate = RateConfig { upload: Some(RATE), download: None };
+        let config = RateLimitConfig { total: Some(rate), per_ip: None, per_user: None };
+        let limiter = Limiter::new(&GlobalLimit::default(), Some(&config)).unwrap();
+        // A second worth is in the bucket already, the other one is waited
+        // for.
+        let took = upload(&limiter, &origin("192.0.2.1:1000"), 2 * RATE as usize).await;
+        assert!(took >= Duration::from_millis(900) && took < Duration::from_millis(1500),
+                "took {:?}", took);
+    }
+
+    #[tokio::test]
+    async fn sessions_of_a_user_share_its_bucket() {
+        let rate = RateConfig { upload: Some(RATE), download: None };
+        let config = RateLimitConfig { total: None, per_ip: None, per_user: Some(rate) };
+        let limiter = Limiter::new(&GlobalLimit::default(), Some(&config)).unwrap();
+        // Once the bucket is empty, half a second worth each takes half a
+        // second alone, and a second side by side.
+        let (a, b) = (origin("192.0.2.1:1000"), origin("192.0.2.2:1000"));
+        upload(&limiter, &a, RATE as usize).await;
+        let half = RATE as usize / 2;
+        let (took_a, took_b) = tokio::join!(upload(&limiter, &a, half), upload(&limiter, &b, half));
+        for took in [took_a, took_b] {
+            assert!(took >= Duration::from_millis(800) && took < Duration::from_millis(1500),
+                    "took {:?}", took);
+        }
+    }
+
+    #[test]
+    fn buckets_in_debt_outlive_their_sessions() {
+        let mut keyed = Keyed::<IpAddr>::new(&RateConfig { upload: Some(RATE), download: None });
+        keyed.pairs = Mutex::new(Swept::new(Duration::ZERO));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        keyed.get(ip).up.unwrap().take(2 * RATE as usize);
+        // Nobody holds the bucket anymore, but it is still owed a second.
+        assert!(!keyed.get(ip).up.unwrap().is_full());
+    }
+}
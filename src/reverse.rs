@@ -0,0 +1,186 @@
+//! Services behind NAT exposed through a remote instance, like `ssh -R`.
+//!
+//! The instance next to the service keeps a reverse tunnel open to the
+//! remote one (see the `tunnel` module for how it looks on the wire). The
+//! remote instance listens on the requested address for as long as the
+//! tunnel is up, and relays every connection it accepts over a stream of
+//! the tunnel, which the other end connects to its target.
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::buffer_pool::BufferPool;
+use crate::client::{Origin, Protocol, Settings};
+use crate::codec::{parse_addr, reply_code, write_addr};
+use crate::client_channel::{serve, tcp_channel};
+use crate::config::{ReverseConfig, Timeouts};
+use crate::connector::{Connector, TargetAddr};
+use crate::endpoint::{Connection, RelayOptions};
+use crate::mux::{self, Session};
+use crate::transport::Transport;
+use crate::tunnel::{self, Key};
+use crate::utilities::{other, timeout, BindRange, BoxFuture, TimeoutKind};
+
+// Longest wait before trying to open the tunnel again.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Keeps the reverse tunnel of `config` open, and serves its streams.
+pub async fn run(config: ReverseConfig, buffers: BufferPool) {
+    let target: TargetAddr = config.target.parse().expect("target is checked by Config::load");
+    let transport = match Transport::new(&config.transport) {
+        Ok(transport) => transport,
+        Err(e) => {
+            error!("reverse tunnel for {}: {}", config.bind, e);
+            return;
+        }
+    };
+    let relay = RelayOptions {
+        buffers,
+        buffer_size: config.buffer_size,
+        idle_timeout: config.timeouts.idle(),
+        lifetime: config.timeouts.lifetime()
+    };
+    // Streams may only ask for the target given here.
+    let protocol = Protocol::Forward(target.clone());
+    let settings = Arc::new(Settings::new(protocol, Arc::new(Connector::direct()), config.timeouts,
+                                          relay));
+    // Where the clients of the remote instance come from isn't known here.
+    let origin = Origin { addr: SocketAddr::from(([0, 0, 0, 0], 0)), user: None };
+    let key = Key::new(&config.password);
+
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match open(&transport, &key, &config.bind, &target, &config.timeouts).await {
+            Ok(session) => {
+                info!("reverse tunnel up, {} is exposed on {}", target, config.bind);
+                backoff = Duration::from_secs(1);
+                let (a, b) = tunnel::serve_multiplexed(session, &origin, &settings).await.unwrap_or((0, 0));
+                warn!("reverse tunnel for {} closed after {}/{} bytes", config.bind, a, b);
+            }
+            Err(e) => error!("reverse tunnel for {} failed: {}", config.bind, e),
+        }
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn open(transport: &Transport, key: &Key, bind: &SocketAddr, target: &TargetAddr,
+              timeouts: &Timeouts) -> io::Result<Session>
+{
+    let handshake = async {
+        let mut s = transport.connect().await?;
+        let mut req = key.digest().to_vec();
+        req.push(tunnel::REVERSE);
+        write_addr(&mut req, &TargetAddr::Ip(*bind))?;
+        write_addr(&mut req, target)?;
+        tunnel::request(&mut s, &req).await?;
+        Ok(s)
+    };
+    let s = timeout(timeouts.connect(), handshake, TimeoutKind::Connect).await?;
+    // Streams are only ever opened by the other end.
+    Ok(Session::new(s, mux::Mode::Server))
+}
+
+/// Serves a reverse tunnel whose request follows on `conn`, for as long as
+/// it stays open. It may listen on the addresses in `allowed` only.
+///
+/// The clients of the exposed listener fall under the same rates, caps,
+/// lockout and hooks as those of the listener of the tunnel, and are
+/// logged alike. They don't log in, and come from nowhere a PROXY protocol
+/// header could be trusted from.
+///
+/// The future is boxed as the accept loop which got us here is part of it.
+pub fn expose<'a, S: Connection>(mut conn: S, allowed: &'a [BindRange], settings: &'a Settings)
+    -> BoxFuture<'a, io::Result<(u64, u64)>>
+{
+    Box::pin(async move {
+        let timeouts = &settings.timeouts;
+        let request = async {
+            let mut atyp = [0u8];
+            conn.read_exact(&mut atyp).await?;
+            let bind = parse_addr(&mut conn, atyp[0]).await?;
+            conn.read_exact(&mut atyp).await?;
+            let target = parse_addr(&mut conn, atyp[0]).await?;
+            Ok::<_, io::Error>((bind, target))
+        };
+        let (bind, target) = timeout(timeouts.handshake(), request, TimeoutKind::Handshake).await?;
+        let listener = match bind {
+            TargetAddr::Ip(addr) if allowed.iter().any(|range| range.contains(addr)) => {
+                TcpListener::bind(addr).await
+            }
+            TargetAddr::Ip(addr) => {
+                let msg = format!("reverse tunnels may not listen on {}", addr);
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, msg))
+            }
+            TargetAddr::Domain(..) => Err(other("reverse tunnels listen on IP addresses only")),
+        };
+        let rep = reply_code(&listener);
+        let reply = async {
+            conn.write_all(&[rep]).await?;
+            conn.flush().await
+        };
+        timeout(timeouts.handshake(), reply, TimeoutKind::Handshake).await?;
+        let listener = listener?;
+
+        info!("Listening for {} of a reverse tunnel on {}", target, bind);
+        let session = Arc::new(Session::new(conn, mux::Mode::Client));
+        let router = Arc::new(Connector::reverse(session.clone()));
+        let mut exposed = Settings::new(Protocol::Forward(target), router, settings.timeouts,
+                                        settings.relay.clone());
+        exposed.hooks = settings.hooks.clone();
+        exposed.access_log = settings.access_log.clone();
+        exposed.limiter = settings.limiter.clone();
+        exposed.quotas = settings.quotas.clone();
+        exposed.connection_limiter = settings.connection_limiter.clone();
+        exposed.lockout = settings.lockout.clone();
+        let exposed = Arc::new(exposed);
+        // The listener goes away with the tunnel.
+        tokio::select! {
+            _ = serve(tcp_channel(listener, exposed)) => {}
+            _ = session.closed() => {}
+        }
+        info!("Reverse tunnel on {} closed", bind);
+        Ok((0, 0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+    use crate::config::Timeouts;
+
+    // Asks for a reverse tunnel on `bind`, and returns the reply code.
+    async fn ask(bind: SocketAddr, allowed: &[BindRange]) -> u8 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        let relay = RelayOptions { buffers: BufferPool::new(1 << 20), buffer_size: 4096,
+                                   idle_timeout: None, lifetime: None };
+        let settings = Settings::new(Protocol::Socks5, Arc::new(Connector::direct()),
+                                     Timeouts::default(), relay);
+        let allowed = allowed.to_vec();
+        let server = tokio::spawn(async move { expose(conn, &allowed, &settings).await });
+        let mut req = Vec::new();
+        write_addr(&mut req, &TargetAddr::Ip(bind)).unwrap();
+        write_addr(&mut req, &"127.0.0.1:22".parse().unwrap()).unwrap();
+        client.write_all(&req).await.unwrap();
+        let mut rep = [0u8];
+        client.read_exact(&mut rep).await.unwrap();
+        drop(client);
+        server.abort();
+        rep[0]
+    }
+
+    #[tokio::test]
+    async fn binds_outside_the_allowed_ranges_are_refused() {
+        let allowed = ["127.0.0.1:0".parse().unwrap()];
+        assert_eq!(ask("127.0.0.1:0".parse().unwrap(), &allowed).await, 0);
+        assert_eq!(ask("127.0.0.1:2222".parse().unwrap(), &allowed).await, 2);
+        assert_eq!(ask("0.0.0.0:0".parse().unwrap(), &allowed).await, 2);
+    }
+}
@@ -0,0 +1,347 @@
+//! Listeners and reverse tunnels run from inside another application.
+use std::io;
+use std::panic;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::access_log::AccessLog;
+use crate::admin;
+use crate::auth::{self, Authenticator};
+use crate::buffer_pool::BufferPool;
+use crate::client::{Protocol, Settings};
+use crate::client_channel::{listen_tcp, listen_tls, serve, websocket, ClientChannel};
+#[cfg(target_os = "linux")]
+use crate::client_channel::listen_transparent;
+use crate::conn_limit::ConnectionLimiter;
+use crate::config::{self, AccessLogConfig, Config, Listener, ReverseConfig, WebSocketConfig};
+use crate::connector::{Connector, Router};
+use crate::endpoint::RelayOptions;
+use crate::hooks::SessionHooks;
+use crate::lockout::Lockout;
+use crate::quota::Quotas;
+use crate::rate_limit::{GlobalLimit, Limiter};
+use crate::reverse;
+use crate::shadowsocks::Cipher;
+#[cfg(target_os = "linux")]
+use crate::transparent;
+use crate::tunnel::Key;
+use crate::utilities::other;
+
+/// Puts a `ProxyServer` together.
+#[derive(Default)]
+pub struct Builder {
+    config: Config,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    router: Option<Arc<dyn Router>>,
+    hooks: Option<Arc<dyn SessionHooks>>
+}
+
+impl Builder {
+    /// Everything of a configuration file, on top of what was added so far.
+    pub fn config(mut self, config: Config) -> Builder {
+        self.config.memory_limit = config.memory_limit;
+        self.config.listeners.extend(config.listeners);
+        self.config.reverses.extend(config.reverses);
+        if config.access_log.is_some() {
+            self.config.access_log = config.access_log;
+        }
+        if config.rate_limit.is_some() {
+            self.config.rate_limit = config.rate_limit;
+        }
+        if config.quota.is_some() {
+            self.config.quota = config.quota;
+        }
+        if config.lockout.is_some() {
+            self.config.lockout = config.lockout;
+        }
+        if config.admin.is_some() {
+            self.config.admin = config.admin;
+        }
+        self
+    }
+
+    pub fn listener(mut self, listener: Listener) -> Builder {
+        self.config.listeners.push(listener);
+        self
+    }
+
+    pub fn reverse(mut self, reverse: ReverseConfig) -> Builder {
+        self.config.reverses.push(reverse);
+        self
+    }
+
+    /// Where a record of every session goes.
+    pub fn access_log(mut self, access_log: AccessLogConfig) -> Builder {
+        self.config.access_log = Some(access_log);
+        self
+    }
+
+    /// Upper bound of memory used by relay buffers of all sessions.
+    pub fn memory_limit(mut self, limit: usize) -> Builder {
+        self.config.memory_limit = limit;
+        self
+    }
+
+    /// Checks the credentials of clients of the SOCKS5 listeners without an
+    /// `auth` table of their own.
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Builder {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Routes the sessions of the listeners without an upstream of their own.
+    /// Their `send_proxy_protocol` is refused, as the router picks connectors
+    /// of its own: see `Connector::send_proxy_protocol` instead.
+    pub fn router(mut self, router: impl Router + 'static) -> Builder {
+        self.router = Some(Arc::new(router));
+        self
+    }
+
+    /// Told about the sessions of all listeners.
+    pub fn hooks(mut self, hooks: impl SessionHooks + 'static) -> Builder {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// The server, if the listeners make sense.
+    pub fn build(self) -> io::Result<ProxyServer> {
+        self.config.validate()?;
+        if self.router.is_some() {
+            let mut routed = self.config.listeners.iter().filter(|l| l.upstream.is_none());
+            if let Some(l) = routed.find(|l| l.send_proxy_protocol.is_some()) {
+                let msg = format!("send_proxy_protocol of listener {} is up to the connectors \
+                                   of the router", l.address);
+                return Err(other(&msg));
+            }
+        }
+        Ok(ProxyServer { builder: self })
+    }
+}
+
+/// Listeners and reverse tunnels which are ready to run.
+pub struct ProxyServer {
+    builder: Builder
+}
+
+impl ProxyServer {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Binds every listener and opens every reverse tunnel. They run on the
+    /// current runtime until the returned handle shuts them down.
+    pub async fn start(self) -> io::Result<ServerHandle> {
+        let mut handle = ServerHandle { servers: Vec::new(), persist: None,
+                                        stop: Arc::new(Notify::new()) };
+        if let Err(e) = self.spawn(&mut handle).await {
+            handle.shutdown();
+            return Err(e);
+        }
+        Ok(handle)
+    }
+
+    async fn spawn(&self, handle: &mut ServerHandle) -> io::Result<()> {
+        let servers = &mut handle.servers;
+        let config = &self.builder.config;
+        // The buffer pool, the access log, the rate limit, the quotas and the
+        // lockout of all listeners are shared by them.
+        let shared = Shared {
+            buffers: BufferPool::new(config.memory_limit),
+            access_log: match config.access_log {
+                Some(ref log) => Some(Arc::new(AccessLog::open(log)?)),
+                None => None,
+            },
+            rate_limit: GlobalLimit::new(config.rate_limit.as_ref()),
+            quotas: match config.quota {
+                Some(ref quota) => Some(Arc::new(Quotas::open(quota)?)),
+                None => None,
+            },
+            lockout: config.lockout.map(|lockout| Arc::new(Lockout::new(lockout)))
+        };
+        if let Some(ref quotas) = shared.quotas {
+            handle.persist = Some(tokio::spawn(quotas.clone().persist(handle.stop.clone())));
+        }
+        if let Some(ref admin) = config.admin {
+            let listener = TcpListener::bind(admin.address).await?;
+            info!("Serving the admin API on {}", admin.address);
+            servers.push(tokio::spawn(admin::serve(listener, admin.clone(),
+                                                   shared.lockout.clone())));
+        }
+
+        for l in &config.listeners {
+            let settings = Arc::new(self.listener_settings(l, &shared)?);
+            let name = match l.protocol {
+                config::Protocol::Socks5 => "socks5",
+                config::Protocol::Tunnel => "tunnel",
+                config::Protocol::Shadowsocks => "shadowsocks",
+                config::Protocol::Redirect => "redirected",
+                config::Protocol::Tproxy => "tproxy",
+                config::Protocol::Forward => "forwarded",
+            };
+            let over = match (&l.tls, &l.websocket) {
+                (None, None) => "",
+                (Some(_), None) => " over TLS",
+                (None, Some(_)) => " over WebSocket",
+                (Some(_), Some(_)) => " over WebSocket over TLS",
+            };
+            let server = match l.tls {
+                _ if l.protocol.is_transparent() => spawn_transparent(l, settings)?,
+                Some(ref tls) => {
+                    let channel = listen_tls(&l.address, tls, settings).await?;
+                    spawn_server(channel, l.websocket.as_ref())
+                }
+                None => {
+                    let channel = listen_tcp(&l.address, settings).await?;
+                    spawn_server(channel, l.websocket.as_ref())
+                }
+            };
+            info!("Listening for {}{} proxy connections on {}", name, over, l.address);
+            servers.push(server);
+        }
+        for r in &config.reverses {
+            info!("Exposing {} on {} through {}", r.target, r.bind, r.transport.address);
+            servers.push(tokio::spawn(reverse::run(r.clone(), shared.buffers.clone())));
+        }
+        Ok(())
+    }
+
+    fn listener_settings(&self, l: &Listener, shared: &Shared) -> io::Result<Settings> {
+        let protocol = match l.protocol {
+            config::Protocol::Socks5 => Protocol::Socks5,
+            config::Protocol::Tunnel => {
+                let key = Key::new(l.password.as_deref().unwrap_or(""));
+                Protocol::Tunnel { key, reverse: l.reverse_bind()? }
+            }
+            config::Protocol::Redirect | config::Protocol::Tproxy => Protocol::Transparent,
+            config::Protocol::Forward => {
+                Protocol::Forward(l.target.as_deref().unwrap_or("").parse()?)
+            }
+            config::Protocol::Shadowsocks => {
+                let method = l.method.ok_or_else(|| other("shadowsocks needs a method"))?;
+                let cipher = Cipher::new(method, l.password.as_deref().unwrap_or(""))?;
+                Protocol::Shadowsocks(Arc::new(cipher))
+            }
+        };
+        let router = match (&l.upstream, &self.builder.router) {
+            (None, Some(router)) => router.clone(),
+            (upstream, _) => {
+                let connector = Connector::new(upstream.as_ref())?;
+                Arc::new(match upstream {
+                    None => connector.send_proxy_protocol(l.send_proxy_protocol.clone()),
+                    Some(_) => connector,
+                })
+            }
+        };
+        let authenticator = match (&l.auth, &self.builder.authenticator) {
+            _ if l.protocol != config::Protocol::Socks5 => None,
+            (Some(auth), _) => Some(auth::open(auth)?),
+            (None, authenticator) => authenticator.clone(),
+        };
+        let relay = RelayOptions {
+            buffers: shared.buffers.clone(),
+            buffer_size: l.buffer_size,
+            idle_timeout: l.timeouts.idle(),
+            lifetime: l.timeouts.lifetime()
+        };
+        Ok(Settings {
+            protocol,
+            router,
+            timeouts: l.timeouts,
+            relay,
+            trusted_proxies: l.proxy_protocol.as_ref().map_or(Ok(Vec::new()), |p| p.trusted())?,
+            hooks: self.builder.hooks.clone(),
+            access_log: shared.access_log.clone(),
+            limiter: Limiter::new(&shared.rate_limit, l.rate_limit.as_ref()),
+            quotas: shared.quotas.clone(),
+            connection_limiter: l.connection_limit.as_ref().map(ConnectionLimiter::new),
+            // Only logins can fail.
+            lockout: shared.lockout.clone().filter(|_| authenticator.is_some()),
+            authenticator
+        })
+    }
+}
+
+// What the listeners of a server share.
+struct Shared {
+    buffers: BufferPool,
+    access_log: Option<Arc<AccessLog>>,
+    rate_limit: GlobalLimit,
+    quotas: Option<Arc<Quotas>>,
+    lockout: Option<Arc<Lockout>>
+}
+
+/// A server which was started. Dropping the handle leaves it running.
+pub struct ServerHandle {
+    servers: Vec<JoinHandle<()>>,
+    // Saves quota usage until `stop` is notified, and once more then.
+    persist: Option<JoinHandle<()>>,
+    stop: Arc<Notify>
+}
+
+impl ServerHandle {
+    /// Closes every listener and reverse tunnel, and saves quota usage one
+    /// last time. Sessions in progress are left to finish, but what they
+    /// relay from then on isn't saved, so it is not counted against their
+    /// users once the server is started again.
+    pub fn shutdown(&self) {
+        for server in &self.servers {
+            server.abort();
+        }
+        self.stop.notify_one();
+    }
+
+    /// Resolves once the server was shut down and quota usage was saved,
+    /// which doesn't wait for the sessions still in progress.
+    pub async fn wait(self) {
+        for server in self.servers.into_iter().chain(self.persist) {
+            if let Err(e) = server.await {
+                if e.is_panic() {
+                    panic::resume_unwind(e.into_panic());
+                }
+            }
+        }
+    }
+}
+
+fn spawn_server(channel: impl ClientChannel + Send + 'static, ws: Option<&WebSocketConfig>)
+    -> JoinHandle<()>
+{
+    match ws {
+        Some(ws) => tokio::spawn(serve(websocket(channel, ws))),
+        None => tokio::spawn(serve(channel)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn spawn_transparent(l: &Listener, settings: Arc<Settings>) -> io::Result<JoinHandle<()>> {
+    let mode = match l.protocol {
+        config::Protocol::Tproxy => transparent::Mode::Tproxy,
+        _ => transparent::Mode::Redirect,
+    };
+    let channel = listen_transparent(&l.address, mode, settings)?;
+    Ok(tokio::spawn(serve(channel)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn spawn_transparent(_: &Listener, _: Arc<Settings>) -> io::Result<JoinHandle<()>> {
+    unreachable!("transparent listeners are refused by Config::validate")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SendProxyProtocolConfig;
+
+    #[test]
+    fn routed_listeners_refuse_send_proxy_protocol() {
+        let mut listener = Listener::new("127.0.0.1:0".parse().unwrap());
+        listener.send_proxy_protocol = Some(SendProxyProtocolConfig { version: 2,
+                                                                      authority: false,
+                                                                      username: false });
+        let builder = || ProxyServer::builder().listener(listener.clone());
+        assert!(builder().build().is_ok());
+        assert!(builder().router(Connector::direct()).build().is_err());
+    }
+}
@@ -0,0 +1,635 @@
+//! Shadowsocks AEAD ciphers, both the original ones (SIP004) and the 2022
+//! edition (SIP022), for listeners as well as for upstreams.
+//!
+//! Every direction of a session starts with a random salt, which together
+//! with the pre-shared key gives the key of that direction. The bytes are
+//! then sent in chunks, each made of its sealed length and its sealed
+//! payload. The first chunk from the client holds the target address in
+//! the SOCKS5 format.
+//!
+//! The 2022 edition puts a typed and timestamped header in front of the
+//! stream of each direction, and the header of the server echoes the salt
+//! of the client, which rules out replays of either side.
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::client::{connect_target, relay_outbound, Origin, Settings};
+use crate::codec::{parse_addr, write_addr};
+use crate::config::CipherMethod;
+use crate::connector::TargetAddr;
+use crate::endpoint::{new_streamendpoint, Connection};
+use crate::utilities::{other, timeout, TimeoutKind};
+
+// All supported ciphers have 32 byte keys, salts as long as their keys and
+// 16 byte tags.
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = KEY_LEN;
+const TAG_LEN: usize = 16;
+
+// 2022 edition: type, timestamp and length of the variable header.
+const REQUEST_HEADER_LEN: usize = 1 + 8 + 2;
+// 2022 edition: type, timestamp, salt of the request and length of the
+// first chunk.
+const RESPONSE_HEADER_LEN: usize = 1 + 8 + SALT_LEN + 2;
+const MAX_TIME_DIFF: u64 = 30;
+const MAX_PADDING: u16 = 900;
+
+// Salts are remembered for this long to refuse replayed sessions.
+const SALT_LIFETIME: Duration = Duration::from_secs(60);
+
+/// A method together with its pre-shared key.
+pub struct Cipher {
+    method: CipherMethod,
+    key: [u8; KEY_LEN],
+    salts: Mutex<HashMap<[u8; SALT_LEN], Instant>>
+}
+
+impl Cipher {
+    /// For the original methods the key is derived from `password`, the 2022
+    /// edition takes the base64 encoded key itself.
+    pub fn new(method: CipherMethod, password: &str) -> io::Result<Cipher> {
+        let mut key = [0u8; KEY_LEN];
+        if method.is_2022() {
+            use base64::Engine;
+            let psk = base64::engine::general_purpose::STANDARD.decode(password)
+                .map_err(|_| other("shadowsocks 2022 password is not valid base64"))?;
+            if psk.len() != KEY_LEN {
+                return Err(other("shadowsocks 2022 password must encode a 32 byte key"));
+            }
+            key.copy_from_slice(&psk);
+        } else {
+            bytes_to_key(password.as_bytes(), &mut key);
+        }
+        Ok(Cipher { method, key, salts: Mutex::new(HashMap::new()) })
+    }
+
+    fn max_chunk(&self) -> usize {
+        if self.method.is_2022() { 0xffff } else { 0x3fff }
+    }
+
+    fn algorithm(&self) -> &'static aead::Algorithm {
+        match self.method {
+            CipherMethod::Aes256Gcm | CipherMethod::Blake3Aes256Gcm => &aead::AES_256_GCM,
+            CipherMethod::Chacha20IetfPoly1305 | CipherMethod::Blake3Chacha20Poly1305 => {
+                &aead::CHACHA20_POLY1305
+            }
+        }
+    }
+
+    fn session(&self, salt: &[u8]) -> Session {
+        let mut subkey = [0u8; KEY_LEN];
+        if self.method.is_2022() {
+            let mut material = self.key.to_vec();
+            material.extend_from_slice(salt);
+            subkey = blake3::derive_key("shadowsocks 2022 session subkey", &material);
+        } else {
+            hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt)
+                .extract(&self.key)
+                .expand(&[b"ss-subkey"], self.algorithm())
+                .and_then(|okm| okm.fill(&mut subkey))
+                .expect("subkey length is the key length of the cipher");
+        }
+        let key = UnboundKey::new(self.algorithm(), &subkey)
+            .expect("subkey length is the key length of the cipher");
+        Session { key: LessSafeKey::new(key), counter: 0 }
+    }
+
+    // Remembers `salt`, and tells whether it was new.
+    fn fresh_salt(&self, salt: &[u8; SALT_LEN]) -> bool {
+        let now = Instant::now();
+        let mut salts = self.salts.lock().unwrap();
+        salts.retain(|_, seen| now.duration_since(*seen) < SALT_LIFETIME);
+        salts.insert(*salt, now).is_none()
+    }
+}
+
+// EVP_BytesToKey of OpenSSL with MD5 and a single round, as used by the
+// original methods.
+fn bytes_to_key(password: &[u8], key: &mut [u8]) {
+    use md5::{Digest, Md5};
+    let mut last: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    while pos < key.len() {
+        let mut md5 = Md5::new();
+        md5.update(&last);
+        md5.update(password);
+        last = md5.finalize().to_vec();
+        let n = (key.len() - pos).min(last.len());
+        key[pos..pos + n].copy_from_slice(&last[..n]);
+        pos += n;
+    }
+}
+
+fn random_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new().fill(&mut salt).expect("system random generator failed");
+    salt
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn check_timestamp(ts: &[u8]) -> io::Result<()> {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(ts);
+    if u64::from_be_bytes(buf).abs_diff(now()) > MAX_TIME_DIFF {
+        return Err(other("shadowsocks: timestamp out of range"));
+    }
+    Ok(())
+}
+
+// The key of one direction of a session, and its nonce counter.
+struct Session {
+    key: LessSafeKey,
+    counter: u64
+}
+
+impl Session {
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    // Appends `plain` sealed to `out`.
+    fn seal(&mut self, plain: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(plain);
+        let nonce = self.nonce();
+        let tag = self.key.seal_in_place_separate_tag(nonce, Aad::empty(), &mut out[start..])
+            .expect("chunks are small enough to seal");
+        out.extend_from_slice(tag.as_ref());
+    }
+
+    // Opens `sealed` in place, and returns its plain part.
+    fn open<'a>(&mut self, sealed: &'a mut [u8]) -> io::Result<&'a mut [u8]> {
+        let nonce = self.nonce();
+        self.key.open_in_place(nonce, Aad::empty(), sealed)
+            .map_err(|_| other("shadowsocks: decryption failed"))
+    }
+
+    // Appends a chunk carrying `data`.
+    fn seal_chunk(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.seal(&(data.len() as u16).to_be_bytes(), out);
+        self.seal(data, out);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ReadState {
+    Salt,
+    ResponseHeader,
+    Length,
+    Payload(usize)
+}
+
+/// A stream whose bytes are sealed by a shadowsocks cipher.
+pub struct ShadowStream<S> {
+    inner: S,
+    cipher: Arc<Cipher>,
+    // Our own salt, which the 2022 edition wants the server to echo.
+    salt: [u8; SALT_LEN],
+    // Salt of the client, echoed by a 2022 server.
+    request_salt: Option<[u8; SALT_LEN]>,
+    dec: Option<Session>,
+    state: ReadState,
+    rbuf: Vec<u8>,
+    rfilled: usize,
+    plain: Vec<u8>,
+    plain_pos: usize,
+    enc: Option<Session>,
+    wbuf: Vec<u8>,
+    wpos: usize
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ShadowStream<S> {
+    fn new(inner: S, cipher: Arc<Cipher>, state: ReadState) -> ShadowStream<S> {
+        ShadowStream {
+            inner, cipher, salt: [0u8; SALT_LEN], request_salt: None,
+            dec: None, state, rbuf: Vec::new(), rfilled: 0, plain: Vec::new(), plain_pos: 0,
+            enc: None, wbuf: Vec::new(), wpos: 0
+        }
+    }
+
+    // Reads until `n` raw bytes are buffered. Resolves to `false` if the
+    // stream ended before any of them.
+    fn poll_fill(&mut self, cx: &mut Context<'_>, n: usize) -> Poll<io::Result<bool>> {
+        if self.rbuf.len() < n {
+            self.rbuf.resize(n, 0);
+        }
+        while self.rfilled < n {
+            let mut buf = ReadBuf::new(&mut self.rbuf[self.rfilled..n]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            match buf.filled().len() {
+                0 if self.rfilled == 0 => return Poll::Ready(Ok(false)),
+                0 => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                got => self.rfilled += got,
+            }
+        }
+        Poll::Ready(Ok(true))
+    }
+
+    // Reads exactly `n` raw bytes, and opens them.
+    fn poll_open(&mut self, cx: &mut Context<'_>, n: usize) -> Poll<io::Result<Vec<u8>>> {
+        if !ready!(self.poll_fill(cx, n))? {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.rfilled = 0;
+        let dec = self.dec.as_mut().expect("the salt comes first");
+        Poll::Ready(dec.open(&mut self.rbuf[..n]).map(|plain| plain.to_vec()))
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.wpos < self.wbuf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf[self.wpos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wpos += n;
+        }
+        self.wbuf.clear();
+        self.wpos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn seal_data(&mut self, data: &[u8]) {
+        let enc = match self.enc {
+            Some(ref mut enc) => enc,
+            None => {
+                // The first bytes of a server start its half of the session.
+                let salt = random_salt();
+                self.wbuf.extend_from_slice(&salt);
+                let mut enc = self.cipher.session(&salt);
+                if let Some(request_salt) = self.request_salt {
+                    let mut header = vec![1u8];
+                    header.extend_from_slice(&now().to_be_bytes());
+                    header.extend_from_slice(&request_salt);
+                    header.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                    enc.seal(&header, &mut self.wbuf);
+                    enc.seal(data, &mut self.wbuf);
+                    self.enc = Some(enc);
+                    return;
+                }
+                self.enc.insert(enc)
+            }
+        };
+        enc.seal_chunk(data, &mut self.wbuf);
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ShadowStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match this.state {
+                ReadState::Salt => {
+                    if !ready!(this.poll_fill(cx, SALT_LEN))? {
+                        return Poll::Ready(Ok(()));
+                    }
+                    this.rfilled = 0;
+                    this.dec = Some(this.cipher.session(&this.rbuf[..SALT_LEN]));
+                    this.state = if this.cipher.method.is_2022() {
+                        ReadState::ResponseHeader
+                    } else {
+                        ReadState::Length
+                    };
+                }
+                ReadState::ResponseHeader => {
+                    let header = ready!(this.poll_open(cx, RESPONSE_HEADER_LEN + TAG_LEN))?;
+                    if header[0] != 1 || header[9..9 + SALT_LEN] != this.salt {
+                        return Poll::Ready(Err(other("shadowsocks: invalid response header")));
+                    }
+                    check_timestamp(&header[1..9])?;
+                    let len = u16::from_be_bytes([header[41], header[42]]) as usize;
+                    this.state = ReadState::Payload(len);
+                }
+                ReadState::Length => {
+                    if !ready!(this.poll_fill(cx, 2 + TAG_LEN))? {
+                        return Poll::Ready(Ok(()));
+                    }
+                    let len = ready!(this.poll_open(cx, 2 + TAG_LEN))?;
+                    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                    this.state = ReadState::Payload(len & this.cipher.max_chunk());
+                }
+                ReadState::Payload(len) => {
+                    this.plain = ready!(this.poll_open(cx, len + TAG_LEN))?;
+                    this.plain_pos = 0;
+                    this.state = ReadState::Length;
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ShadowStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        let n = buf.len().min(this.cipher.max_chunk());
+        if n == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        this.seal_data(&buf[..n]);
+        // Push the chunk out right away if we can, it stays buffered
+        // otherwise.
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Starts a session to a shadowsocks server, asking for `target`.
+pub async fn connect<S>(inner: S, cipher: Arc<Cipher>, target: &TargetAddr)
+    -> io::Result<ShadowStream<S>>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut s = ShadowStream::new(inner, cipher.clone(), ReadState::Salt);
+    s.salt = random_salt();
+    s.wbuf.extend_from_slice(&s.salt);
+    let mut enc = cipher.session(&s.salt);
+    let mut addr = Vec::new();
+    write_addr(&mut addr, target)?;
+    if cipher.method.is_2022() {
+        // Without any initial payload the header has to be padded.
+        let mut padding = [0u8; 2];
+        SystemRandom::new().fill(&mut padding).expect("system random generator failed");
+        let padding = u16::from_be_bytes(padding) % MAX_PADDING + 1;
+        addr.extend_from_slice(&padding.to_be_bytes());
+        addr.resize(addr.len() + padding as usize, 0);
+        let mut header = vec![0u8];
+        header.extend_from_slice(&now().to_be_bytes());
+        header.extend_from_slice(&(addr.len() as u16).to_be_bytes());
+        enc.seal(&header, &mut s.wbuf);
+        enc.seal(&addr, &mut s.wbuf);
+    } else {
+        enc.seal_chunk(&addr, &mut s.wbuf);
+    }
+    s.enc = Some(enc);
+    s.flush().await?;
+    Ok(s)
+}
+
+/// Accepts a session from a shadowsocks client, and returns it along with
+/// the target it asked for.
+pub async fn accept<S>(mut inner: S, cipher: Arc<Cipher>)
+    -> io::Result<(ShadowStream<S>, TargetAddr)>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut salt = [0u8; SALT_LEN];
+    inner.read_exact(&mut salt).await?;
+    if !cipher.fresh_salt(&salt) {
+        return Err(other("shadowsocks: replayed salt"));
+    }
+    let mut dec = cipher.session(&salt);
+    let request = if cipher.method.is_2022() {
+        let mut header = [0u8; REQUEST_HEADER_LEN + TAG_LEN];
+        inner.read_exact(&mut header).await?;
+        let header = dec.open(&mut header)?;
+        if header[0] != 0 {
+            return Err(other("shadowsocks: invalid request header"));
+        }
+        check_timestamp(&header[1..9])?;
+        let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+        let mut request = vec![0u8; len + TAG_LEN];
+        inner.read_exact(&mut request).await?;
+        dec.open(&mut request)?.to_vec()
+    } else {
+        let mut len = [0u8; 2 + TAG_LEN];
+        inner.read_exact(&mut len).await?;
+        let len = dec.open(&mut len)?;
+        let len = (u16::from_be_bytes([len[0], len[1]]) & 0x3fff) as usize;
+        let mut request = vec![0u8; len + TAG_LEN];
+        inner.read_exact(&mut request).await?;
+        dec.open(&mut request)?.to_vec()
+    };
+
+    let mut rest = &request[..];
+    let mut atyp = [0u8];
+    rest.read_exact(&mut atyp).await?;
+    let target = parse_addr(&mut rest, atyp[0]).await?;
+    if cipher.method.is_2022() {
+        let padding = rest.read_u16().await? as usize;
+        rest = rest.get(padding..).ok_or_else(|| other("shadowsocks: truncated padding"))?;
+    }
+
+    let mut s = ShadowStream::new(inner, cipher.clone(), ReadState::Length);
+    s.dec = Some(dec);
+    s.plain = rest.to_vec();
+    if cipher.method.is_2022() {
+        s.request_salt = Some(salt);
+    }
+    Ok((s, target))
+}
+
+/// Serves a connection from a shadowsocks client.
+pub async fn serve<S: Connection>(conn: S, origin: &Origin, cipher: &Arc<Cipher>,
+                                  settings: &Settings)
+    -> io::Result<(u64, u64)>
+{
+    let timeouts = &settings.timeouts;
+    let request = accept(conn, cipher.clone());
+    let (stream, target) = timeout(timeouts.handshake(), request, TimeoutKind::Handshake).await?;
+    let out = connect_target(settings, origin, target).await?;
+    relay_outbound(new_streamendpoint(stream), out, origin, settings).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    const METHODS: [CipherMethod; 4] = [CipherMethod::Chacha20IetfPoly1305, CipherMethod::Aes256Gcm,
+                                        CipherMethod::Blake3Aes256Gcm,
+                                        CipherMethod::Blake3Chacha20Poly1305];
+
+    fn cipher(method: CipherMethod) -> Arc<Cipher> {
+        let password = if method.is_2022() {
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8="
+        } else {
+            "secret"
+        };
+        Arc::new(Cipher::new(method, password).unwrap())
+    }
+
+    fn target() -> TargetAddr {
+        TargetAddr::Domain("example.com".to_string(), 443)
+    }
+
+    // The raw bytes a client sends to ask for `target`, followed by `data`.
+    async fn request(cipher: &Arc<Cipher>, data: &[u8]) -> Vec<u8> {
+        let (ours, mut theirs) = duplex(1 << 20);
+        let mut s = connect(ours, cipher.clone(), &target()).await.unwrap();
+        s.write_all(data).await.unwrap();
+        s.shutdown().await.unwrap();
+        drop(s);
+        let mut raw = Vec::new();
+        theirs.read_to_end(&mut raw).await.unwrap();
+        raw
+    }
+
+    async fn accept_raw(cipher: &Arc<Cipher>, raw: &[u8])
+        -> io::Result<(ShadowStream<DuplexStream>, TargetAddr)>
+    {
+        let (ours, mut theirs) = duplex(1 << 20);
+        theirs.write_all(raw).await.unwrap();
+        // Left open, so that the server side can still write.
+        tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut theirs, &mut tokio::io::sink()).await;
+        });
+        accept(ours, cipher.clone()).await
+    }
+
+    #[tokio::test]
+    async fn sessions_round_trip() {
+        // More than fits in one chunk of either edition.
+        let upload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let download: Vec<u8> = upload.iter().rev().copied().collect();
+        for method in METHODS {
+            let cipher = cipher(method);
+            let (client, server) = duplex(1 << 16);
+            let server_cipher = cipher.clone();
+            let (up, down) = (upload.clone(), download.clone());
+            let server = tokio::spawn(async move {
+                let (mut s, target) = accept(server, server_cipher).await.unwrap();
+                assert_eq!(target, TargetAddr::Domain("example.com".to_string(), 443));
+                let mut got = vec![0u8; up.len()];
+                s.read_exact(&mut got).await.unwrap();
+                assert!(got == up);
+                s.write_all(&down).await.unwrap();
+                s.shutdown().await.unwrap();
+            });
+            let mut s = connect(client, cipher, &target()).await.unwrap();
+            s.write_all(&upload).await.unwrap();
+            s.flush().await.unwrap();
+            let mut got = Vec::new();
+            s.read_to_end(&mut got).await.unwrap();
+            assert!(got == download);
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_salts_are_refused() {
+        for method in METHODS {
+            let cipher = cipher(method);
+            let raw = request(&cipher, b"hello").await;
+            let (mut s, _) = accept_raw(&cipher, &raw).await.unwrap();
+            let mut got = [0u8; 5];
+            s.read_exact(&mut got).await.unwrap();
+            assert_eq!(&got, b"hello");
+            let e = accept_raw(&cipher, &raw).await.err().unwrap();
+            assert!(e.to_string().contains("replayed salt"));
+        }
+    }
+
+    #[tokio::test]
+    async fn tampered_chunks_are_refused() {
+        for method in METHODS {
+            let cipher = cipher(method);
+            let mut raw = request(&cipher, b"").await;
+            raw[SALT_LEN + 1] ^= 1;
+            let e = accept_raw(&cipher, &raw).await.err().unwrap();
+            assert!(e.to_string().contains("decryption failed"));
+
+            let mut raw = request(&cipher, b"hello").await;
+            let last = raw.len() - 1;
+            raw[last] ^= 1;
+            let (mut s, _) = accept_raw(&cipher, &raw).await.unwrap();
+            assert!(s.read(&mut [0u8; 5]).await.is_err());
+        }
+    }
+
+    // A 2022 request for `target` with the header fields given.
+    fn request_2022(cipher: &Cipher, kind: u8, timestamp: u64) -> Vec<u8> {
+        let salt = random_salt();
+        let mut raw = salt.to_vec();
+        let mut enc = cipher.session(&salt);
+        let mut addr = Vec::new();
+        write_addr(&mut addr, &target()).unwrap();
+        addr.extend_from_slice(&[0, 1, 0]);
+        let mut header = vec![kind];
+        header.extend_from_slice(&timestamp.to_be_bytes());
+        header.extend_from_slice(&(addr.len() as u16).to_be_bytes());
+        enc.seal(&header, &mut raw);
+        enc.seal(&addr, &mut raw);
+        raw
+    }
+
+    #[tokio::test]
+    async fn request_headers_are_checked() {
+        let cipher = cipher(CipherMethod::Blake3Aes256Gcm);
+        assert!(accept_raw(&cipher, &request_2022(&cipher, 0, now())).await.is_ok());
+        let e = accept_raw(&cipher, &request_2022(&cipher, 1, now())).await.err().unwrap();
+        assert!(e.to_string().contains("invalid request header"));
+        for ts in [now() - 2 * MAX_TIME_DIFF, now() + 2 * MAX_TIME_DIFF] {
+            let e = accept_raw(&cipher, &request_2022(&cipher, 0, ts)).await.err().unwrap();
+            assert!(e.to_string().contains("timestamp out of range"));
+        }
+    }
+
+    #[tokio::test]
+    async fn response_must_echo_the_request_salt() {
+        let cipher = cipher(CipherMethod::Blake3Chacha20Poly1305);
+        let (client, server) = duplex(1 << 16);
+        let server_cipher = cipher.clone();
+        tokio::spawn(async move {
+            let (mut s, _) = accept(server, server_cipher).await.unwrap();
+            s.request_salt = Some(random_salt());
+            s.write_all(b"answer").await.unwrap();
+            let _ = s.read(&mut [0u8; 1]).await;
+        });
+        let mut s = connect(client, cipher, &target()).await.unwrap();
+        let e = s.read(&mut [0u8; 6]).await.unwrap_err();
+        assert!(e.to_string().contains("invalid response header"));
+    }
+
+    #[test]
+    fn keys_are_derived_as_openssl_does() {
+        use md5::{Digest, Md5};
+        let mut key = [0u8; KEY_LEN];
+        bytes_to_key(b"secret", &mut key);
+        let first = Md5::digest(b"secret");
+        let mut second = Md5::new();
+        second.update(first);
+        second.update(b"secret");
+        assert_eq!(key[..16], first[..]);
+        assert_eq!(key[16..], second.finalize()[..]);
+    }
+
+    #[test]
+    fn keys_of_2022_methods_must_be_32_bytes() {
+        assert!(Cipher::new(CipherMethod::Blake3Aes256Gcm, "c2hvcnQ=").is_err());
+        assert!(Cipher::new(CipherMethod::Blake3Aes256Gcm, "not base64!").is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! The client side of SOCKS5, for chaining to upstream SOCKS5 servers and
+//! for applications which go through one.
+//!
+//! The handshake can be run over any stream with `connect`, or a
+//! `Socks5Client` makes the TCP connections to its server itself, which also
+//! allows for the `BIND` and `UDP ASSOCIATE` commands:
+//!
+//! ```no_run
+//! use rustoxy::connector::TargetAddr;
+//! use rustoxy::socks5_client::Socks5Client;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let client = Socks5Client::new("127.0.0.1:1080").credentials("alice", "secret");
+//! let target = TargetAddr::Domain("example.com".to_string(), 80);
+//! let stream = client.connect(&target).await?;
+//! # Ok(())
+//! # }
+//! ```
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use std::io;
+use std::net::SocketAddr;
+
+use crate::codec::{parse_addr, reply_error, v5, write_addr};
+use crate::connector::TargetAddr;
+use crate::utilities::other;
+
+// The largest header of a UDP datagram, with a domain name of 255 bytes.
+const MAX_UDP_HEADER: usize = 4 + 1 + 255 + 2;
+
+/// What the username/password method (RFC 1929) logs in with.
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String
+}
+
+/// Negotiates a method with the server at the other end of `s`, and logs in
+/// with `credentials` if the server wants them.
+pub async fn negotiate<S>(s: &mut S, credentials: Option<&Credentials>) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let hello: &[u8] = match credentials {
+        Some(_) => &[v5::VERSION, 2, v5::METH_NO_AUTH, v5::METH_USER_PASS],
+        None => &[v5::VERSION, 1, v5::METH_NO_AUTH],
+    };
+    s.write_all(hello).await?;
+    s.flush().await?;
+    let mut buf = [0u8; 2];
+    s.read_exact(&mut buf).await?;
+    if buf[0] != v5::VERSION {
+        return Err(other("server didn't answer with v5 version"));
+    }
+    match (buf[1], credentials) {
+        (v5::METH_NO_AUTH, _) => Ok(()),
+        (v5::METH_USER_PASS, Some(credentials)) => log_in(s, credentials).await,
+        (v5::METH_NO_ACCEPTABLE, _) => Err(other("server accepted none of our methods")),
+        (method, _) => Err(other(&format!("server chose unsupported method {}", method))),
+    }
+}
+
+async fn log_in<S>(s: &mut S, credentials: &Credentials) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let (user, password) = (credentials.username.as_bytes(), credentials.password.as_bytes());
+    if user.len() > 255 || password.len() > 255 {
+        return Err(other("username or password longer than 255 bytes"));
+    }
+    let mut req = vec![v5::USER_PASS_VERSION, user.len() as u8];
+    req.extend_from_slice(user);
+    req.push(password.len() as u8);
+    req.extend_from_slice(password);
+    s.write_all(&req).await?;
+    s.flush().await?;
+    let mut buf = [0u8; 2];
+    s.read_exact(&mut buf).await?;
+    if buf[0] != v5::USER_PASS_VERSION {
+        return Err(other("server didn't answer with the username/password version"));
+    }
+    if buf[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  "server refused username and password"));
+    }
+    Ok(())
+}
+
+/// Sends a request with command `cmd` for `addr`, and resolves to the
+/// address in the reply.
+pub async fn request<S>(s: &mut S, cmd: u8, addr: &TargetAddr) -> io::Result<TargetAddr>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let mut req = vec![v5::VERSION, cmd, 0];
+    write_addr(&mut req, addr)?;
+    s.write_all(&req).await?;
+    s.flush().await?;
+    read_reply(s).await
+}
+
+// VER, REP, RSV and the bound address. Anything but a success is turned
+// back into the error the server ran into.
+async fn read_reply<S>(s: &mut S) -> io::Result<TargetAddr>
+    where S: AsyncRead + Unpin
+{
+    let mut buf = [0u8; 4];
+    s.read_exact(&mut buf).await?;
+    if buf[0] != v5::VERSION {
+        return Err(other("server didn't reply with v5 version"));
+    }
+    if buf[1] != 0 {
+        return Err(reply_error(buf[1]));
+    }
+    parse_addr(s, buf[3]).await
+}
+
+/// Connects to `target` through the server at the other end of `s`. Once
+/// this resolved `s` is relayed to the target, and the address the server
+/// connected from is returned.
+pub async fn connect<S>(s: &mut S, target: &TargetAddr, credentials: Option<&Credentials>)
+    -> io::Result<TargetAddr>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    negotiate(s, credentials).await?;
+    request(s, v5::CMD_CONNECT, target).await
+}
+
+/// A SOCKS5 server, and how to log in to it.
+#[derive(Clone)]
+pub struct Socks5Client {
+    server: String,
+    credentials: Option<Credentials>
+}
+
+impl Socks5Client {
+    /// The server at `server`, as `host:port`.
+    pub fn new(server: &str) -> Socks5Client {
+        Socks5Client { server: server.to_string(), credentials: None }
+    }
+
+    /// Logs in with a username and password.
+    pub fn credentials(self, username: &str, password: &str) -> Socks5Client {
+        let credentials = Credentials { username: username.to_string(), password: password.to_string() };
+        Socks5Client { credentials: Some(credentials), ..self }
+    }
+
+    async fn open(&self) -> io::Result<TcpStream> {
+        let mut s = TcpStream::connect(&self.server).await?;
+        s.set_nodelay(true)?;
+        negotiate(&mut s, self.credentials.as_ref()).await?;
+        Ok(s)
+    }
+
+    /// A connection to `target` through the server, which is relayed as an
+    /// `Endpoint` like any other `Connection`.
+    pub async fn connect(&self, target: &TargetAddr) -> io::Result<TcpStream> {
+        let mut s = self.open().await?;
+        request(&mut s, v5::CMD_CONNECT, target).await?;
+        Ok(s)
+    }
+
+    /// Asks the server to accept a connection from `peer`, such as the data
+    /// connection of an FTP server.
+    pub async fn bind(&self, peer: &TargetAddr) -> io::Result<Bound> {
+        let mut s = self.open().await?;
+        let addr = request(&mut s, v5::CMD_BIND, peer).await?;
+        Ok(Bound { stream: s, addr })
+    }
+
+    /// Asks the server to relay UDP datagrams.
+    pub async fn udp_associate(&self) -> io::Result<UdpAssociation> {
+        let mut control = self.open().await?;
+        // Datagrams are only relayed from the address given here.
+        let socket = UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0)).await?;
+        let relay = request(&mut control, v5::CMD_UDP_ASSOCIATE,
+                            &TargetAddr::Ip(socket.local_addr()?)).await?;
+        let mut relay = relay.resolve().await?;
+        // A server which doesn't care to tell relays on the address we
+        // reached it on.
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+        Ok(UdpAssociation { _control: control, socket, relay })
+    }
+}
+
+/// A `BIND` request which the server is listening for.
+pub struct Bound {
+    stream: TcpStream,
+    addr: TargetAddr
+}
+
+impl Bound {
+    /// Where the server listens, to be handed to the peer.
+    pub fn addr(&self) -> &TargetAddr {
+        &self.addr
+    }
+
+    /// Waits for the peer to connect, and resolves to the connection
+    /// relayed to it and the address it connected from.
+    pub async fn accept(mut self) -> io::Result<(TcpStream, TargetAddr)> {
+        let peer = read_reply(&mut self.stream).await?;
+        Ok((self.stream, peer))
+    }
+}
+
+/// Datagrams relayed by the server. The association ends when this is
+/// dropped.
+pub struct UdpAssociation {
+    // The server keeps relaying for as long as this stays open.
+    _control: TcpStream,
+    socket: UdpSocket,
+    relay: SocketAddr
+}
+
+impl UdpAssociation {
+    /// Sends `buf` to `target`.
+    pub async fn send_to(&self, buf: &[u8], target: &TargetAddr) -> io::Result<usize> {
+        // RSV, FRAG, and the address as in a request.
+        let mut datagram = vec![0, 0, 0];
+        write_addr(&mut datagram, target)?;
+        datagram.extend_from_slice(buf);
+        self.socket.send_to(&datagram, self.relay).await?;
+        Ok(buf.len())
+    }
+
+    /// Receives a datagram into `buf`, and resolves to its length and where
+    /// it came from.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+        let mut datagram = vec![0u8; buf.len() + MAX_UDP_HEADER];
+        loop {
+            let (n, from) = self.socket.recv_from(&mut datagram).await?;
+            // Fragments are not supported, and are dropped as RFC 1928
+            // allows.
+            if from != self.relay || n < 4 || datagram[2] != 0 {
+                continue;
+            }
+            let mut rest = &datagram[4..n];
+            let source = match parse_addr(&mut rest, datagram[3]).await {
+                Ok(source) => source,
+                Err(_) => continue,
+            };
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            return Ok((len, source));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use crate::auth::Users;
+    use crate::buffer_pool::BufferPool;
+    use crate::client::{Origin, Protocol, Settings};
+    use crate::client_channel::{serve, tcp_channel};
+    use crate::config::Timeouts;
+    use crate::connector::{Connector, Router};
+    use crate::endpoint::RelayOptions;
+
+    // Refuses sessions to port 9, and sends the others straight on.
+    struct NoDiscard(Connector);
+
+    impl Router for NoDiscard {
+        fn route(&self, _: &Origin, target: &TargetAddr) -> io::Result<&Connector> {
+            match target.port() {
+                9 => Err(io::Error::new(io::ErrorKind::PermissionDenied, "discard")),
+                _ => Ok(&self.0),
+            }
+        }
+    }
+
+    // A SOCKS5 server, which wants alice to log in if `auth` is set.
+    async fn server(auth: bool) -> String {
+        let relay = RelayOptions { buffers: BufferPool::new(1 << 20), buffer_size: 4096,
+                                   idle_timeout: None, lifetime: None };
+        let router = Arc::new(NoDiscard(Connector::direct()));
+        let mut settings = Settings::new(Protocol::Socks5, router, Timeouts::default(), relay);
+        if auth {
+            settings.authenticator = Some(Arc::new(Users::new([("alice", "secret")])));
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(tcp_channel(listener, Arc::new(settings))));
+        addr.to_string()
+    }
+
+    // A server which answers every connection with what it was sent.
+    async fn echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut s, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = s.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn round_trip(mut s: TcpStream) {
+        s.write_all(b"hello").await.unwrap();
+        let mut got = [0u8; 5];
+        s.read_exact(&mut got).await.unwrap();
+        assert_eq!(&got, b"hello");
+    }
+
+    #[tokio::test]
+    async fn connects_to_addresses_without_logging_in() {
+        let client = Socks5Client::new(&server(false).await);
+        round_trip(client.connect(&TargetAddr::Ip(echo().await)).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn connects_to_domains_once_logged_in() {
+        let client = Socks5Client::new(&server(true).await).credentials("alice", "secret");
+        let target = TargetAddr::Domain("localhost".to_string(), echo().await.port());
+        round_trip(client.connect(&target).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn wrong_passwords_are_refused() {
+        let target = TargetAddr::Ip(echo().await);
+        let server = server(true).await;
+        let client = Socks5Client::new(&server).credentials("alice", "guess");
+        let e = client.connect(&target).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        // Without credentials none of our methods will do.
+        assert!(Socks5Client::new(&server).connect(&target).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn replies_turn_back_into_errors() {
+        let client = Socks5Client::new(&server(false).await);
+        let refused = TargetAddr::Ip("127.0.0.1:9".parse().unwrap());
+        let e = client.connect(&refused).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        // Nothing listens where the echo server was.
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let e = client.connect(&TargetAddr::Ip(closed)).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn login_answers_need_the_right_version() {
+        let (mut c, mut s) = tokio::io::duplex(1024);
+        let credentials = Credentials { username: "alice".into(), password: "secret".into() };
+        let server = async move {
+            let mut hello = [0u8; 4];
+            s.read_exact(&mut hello).await.unwrap();
+            s.write_all(&[v5::VERSION, v5::METH_USER_PASS]).await.unwrap();
+            let mut login = [0u8; 3 + 5 + 6];
+            s.read_exact(&mut login).await.unwrap();
+            // The version of SOCKS rather than of the login.
+            s.write_all(&[v5::VERSION, 0]).await.unwrap();
+            s
+        };
+        let (res, _s) = tokio::join!(negotiate(&mut c, Some(&credentials)), server);
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Zero-copy relaying between two TCP sockets with splice(2).
+//!
+//! Bytes are moved from the source socket into a pipe and from the pipe into
+//! the destination socket without ever being copied into userspace.
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use crate::endpoint::{Activity, Direction};
+
+// The default capacity of a pipe on Linux.
+const PIPE_SIZE: usize = 1 << 16;
+
+struct Pipe {
+    r: OwnedFd,
+    w: OwnedFd
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds = [0 as RawFd; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            Ok(Pipe { r: OwnedFd::from_raw_fd(fds[0]), w: OwnedFd::from_raw_fd(fds[1]) })
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len,
+                     libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+// Passes an EOF on to the peer as a FIN. The peer may have closed the whole
+// connection already, in which case there is nobody left to tell.
+fn shutdown_write(s: &TcpStream) -> io::Result<()> {
+    if unsafe { libc::shutdown(s.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::NotConnected {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Moves everything `src` sends into `dst` until `src` reaches EOF, and
+// returns the number of bytes moved. The EOF itself is passed on as well,
+// while the other direction keeps going.
+async fn splice_one(src: &TcpStream, dst: &TcpStream, activity: &Activity<'_>,
+                    direction: Direction)
+    -> io::Result<u64>
+{
+    let pipe = Pipe::new()?;
+    let mut total = 0u64;
+    loop {
+        // Fill the pipe from the source socket. The pipe is always drained
+        // below, so only the socket can make this block.
+        src.readable().await?;
+        let n = match src.try_io(Interest::READABLE,
+                                 || splice(src.as_raw_fd(), pipe.w.as_raw_fd(), PIPE_SIZE)) {
+            Ok(0) => {
+                shutdown_write(dst)?;
+                return Ok(total);
+            }
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+        activity.moved(direction, n as u64);
+
+        // Drain the pipe into the destination socket.
+        let mut left = n;
+        while left > 0 {
+            dst.writable().await?;
+            match dst.try_io(Interest::WRITABLE,
+                             || splice(pipe.r.as_raw_fd(), dst.as_raw_fd(), left)) {
+                Ok(m) => left -= m,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total += n as u64;
+    }
+}
+
+pub async fn transfer(s1: &TcpStream, s2: &TcpStream, activity: &Activity<'_>)
+    -> io::Result<(u64, u64)>
+{
+    tokio::try_join!(splice_one(s1, s2, activity, Direction::Up),
+                     splice_one(s2, s1, activity, Direction::Down))
+}
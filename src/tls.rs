@@ -0,0 +1,379 @@
+//! TLS for the connections of a listener, and for connections to upstream
+//! servers.
+//!
+//! The TLS handshake of a client is not done by the listener. It is driven
+//! by the first read of the SOCKS handshake instead, so it runs in the
+//! client's own task and is covered by the handshake timeout.
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::Accept;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use crate::config::{ClientTlsConfig, TlsConfig};
+use crate::endpoint::{new_streamendpoint, Connection, Endpoint};
+use crate::utilities::other;
+
+pub fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| other(&format!("can't load certificates from {}: {}", path.display(), e)))
+}
+
+pub fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| other(&format!("can't load private key from {}: {}", path.display(), e)))
+}
+
+pub fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| other(&e.to_string()))?;
+    }
+    Ok(roots)
+}
+
+pub fn server_config(tls: &TlsConfig) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder();
+    let builder = match tls.client_ca {
+        Some(ref ca) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(ca)?))
+                .build().map_err(|e| other(&e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(&tls.cert)?, load_key(&tls.key)?)
+        .map_err(|e| other(&e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+pub fn client_config(tls: &ClientTlsConfig) -> io::Result<Arc<ClientConfig>> {
+    let roots = match tls.ca {
+        Some(ref ca) => load_roots(ca)?,
+        None => RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() },
+    };
+    let webpki = WebPkiServerVerifier::builder(Arc::new(roots))
+        .build().map_err(|e| other(&e.to_string()))?;
+    let builder = if tls.pins.is_empty() {
+        ClientConfig::builder().with_webpki_verifier(webpki)
+    } else {
+        let pins = tls.pins.iter().map(|p| parse_pin(p)).collect::<io::Result<_>>()?;
+        let verifier = PinnedVerifier { webpki, pins };
+        ClientConfig::builder().dangerous().with_custom_certificate_verifier(Arc::new(verifier))
+    };
+    let mut config = match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| other(&e.to_string()))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(other("a client certificate needs both cert and key")),
+    };
+    config.enable_sni = tls.sni;
+    Ok(Arc::new(config))
+}
+
+pub fn server_name(tls: &ClientTlsConfig, address: &str) -> io::Result<ServerName<'static>> {
+    let name = match tls.server_name {
+        Some(ref name) => name.as_str(),
+        // Strip the port, and the brackets of an IPv6 address.
+        None => address.rsplit_once(':').map_or(address, |(host, _)| host)
+                       .trim_start_matches('[').trim_end_matches(']'),
+    };
+    ServerName::try_from(name.to_string())
+        .map_err(|_| other(&format!("invalid TLS server name: {}", name)))
+}
+
+fn parse_pin(pin: &str) -> io::Result<[u8; 32]> {
+    let mut digest = [0u8; 32];
+    if pin.len() != 64 || !pin.is_ascii() {
+        return Err(other(&format!("invalid certificate pin: {}", pin)));
+    }
+    for (i, d) in digest.iter_mut().enumerate() {
+        *d = u8::from_str_radix(&pin[i * 2..i * 2 + 2], 16)
+            .map_err(|_| other(&format!("invalid certificate pin: {}", pin)))?;
+    }
+    Ok(digest)
+}
+
+// Accepts exactly the server certificates whose digest is pinned. These
+// need no chain to a trusted CA, so pinned self-signed certificates work,
+// but the handshake signatures are still checked against them.
+#[derive(Debug)]
+struct PinnedVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>,
+                          _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>,
+                          _ocsp_response: &[u8], _now: UnixTime)
+        -> Result<ServerCertVerified, rustls::Error>
+    {
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+        if self.pins.iter().any(|p| p[..] == *digest.as_ref()) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("server certificate doesn't match any pin".to_string()))
+        }
+    }
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error>
+    {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct)
+        -> Result<HandshakeSignatureValid, rustls::Error>
+    {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+/// A client connection which does its TLS handshake on first use.
+pub struct TlsConnection {
+    state: State
+}
+
+enum State {
+    Handshaking(Box<Accept<TcpStream>>),
+    Ready(Box<TlsStream<TcpStream>>),
+    Failed
+}
+
+impl TlsConnection {
+    pub fn new(accept: Accept<TcpStream>) -> TlsConnection {
+        TlsConnection { state: State::Handshaking(Box::new(accept)) }
+    }
+
+    fn poll_stream(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&mut TlsStream<TcpStream>>> {
+        if let State::Handshaking(ref mut accept) = self.state {
+            match ready!(Pin::new(&mut **accept).poll(cx)) {
+                Ok(stream) => self.state = State::Ready(Box::new(stream)),
+                Err(e) => {
+                    self.state = State::Failed;
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        match self.state {
+            State::Ready(ref mut stream) => Poll::Ready(Ok(stream)),
+            _ => Poll::Ready(Err(other("TLS handshake failed"))),
+        }
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        let stream = ready!(self.get_mut().poll_stream(cx))?;
+        Pin::new(stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let stream = ready!(self.get_mut().poll_stream(cx))?;
+        Pin::new(stream).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_stream(cx))?;
+        Pin::new(stream).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_stream(cx))?;
+        Pin::new(stream).poll_shutdown(cx)
+    }
+}
+
+impl Connection for TlsConnection {
+    fn into_endpoint(self) -> impl Endpoint + Send {
+        new_streamendpoint(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use std::fs;
+    use crate::buffer_pool::BufferPool;
+    use crate::client::{Protocol, Settings};
+    use crate::client_channel::{serve, tls_channel};
+    use crate::config::Timeouts;
+    use crate::connector::{Connector, TargetAddr};
+    use crate::endpoint::RelayOptions;
+    use crate::socks5_client;
+    use tempfile::TempDir;
+
+    // A CA, a leaf for localhost signed by it and a self-signed leaf for
+    // localhost, as ca.crt, leaf.crt/leaf.key and self.crt/self.key.
+    struct Pki {
+        dir: TempDir,
+        leaf: Vec<u8>,
+        selfsigned: Vec<u8>
+    }
+
+    fn pki() -> Pki {
+        let dir = tempfile::tempdir().unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+        let leaf_key = KeyPair::generate().unwrap();
+        let leaf = CertificateParams::new(vec!["localhost".to_string()]).unwrap()
+            .signed_by(&leaf_key, &issuer).unwrap();
+        let self_key = KeyPair::generate().unwrap();
+        let selfsigned = CertificateParams::new(vec!["localhost".to_string()]).unwrap()
+            .self_signed(&self_key).unwrap();
+        fs::write(dir.path().join("ca.crt"), ca.pem()).unwrap();
+        fs::write(dir.path().join("leaf.crt"), leaf.pem()).unwrap();
+        fs::write(dir.path().join("leaf.key"), leaf_key.serialize_pem()).unwrap();
+        fs::write(dir.path().join("self.crt"), selfsigned.pem()).unwrap();
+        fs::write(dir.path().join("self.key"), self_key.serialize_pem()).unwrap();
+        Pki { dir, leaf: leaf.der().to_vec(), selfsigned: selfsigned.der().to_vec() }
+    }
+
+    fn pin(der: &[u8]) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, der);
+        digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn client(pki: &Pki, ca: bool, pins: Vec<String>) -> ClientTlsConfig {
+        ClientTlsConfig { server_name: None, sni: true,
+                          ca: ca.then(|| pki.dir.path().join("ca.crt")),
+                          pins, cert: None, key: None }
+    }
+
+    // Serves `name`.crt over TLS on loopback, and tells whether `client`
+    // gets through a handshake and an echo with it.
+    async fn handshake(pki: &Pki, name: &str, client: &ClientTlsConfig) -> bool {
+        let server = TlsConfig { cert: pki.dir.path().join(format!("{}.crt", name)),
+                                 key: pki.dir.path().join(format!("{}.key", name)),
+                                 client_ca: None };
+        let acceptor = TlsAcceptor::from(server_config(&server).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = acceptor.accept(conn).await {
+                let mut buf = [0u8; 4];
+                if stream.read_exact(&mut buf).await.is_ok() {
+                    let _ = stream.write_all(&buf).await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+        let connector = TlsConnector::from(client_config(client).unwrap());
+        let conn = TcpStream::connect(addr).await.unwrap();
+        let name = server_name(client, "localhost:443").unwrap();
+        let Ok(mut stream) = connector.connect(name, conn).await else {
+            return false;
+        };
+        let mut buf = [0u8; 4];
+        stream.write_all(b"ping").await.is_ok()
+            && stream.read_exact(&mut buf).await.is_ok()
+            && &buf == b"ping"
+    }
+
+    #[tokio::test]
+    async fn ca_signed_certificate_is_verified() {
+        let pki = pki();
+        assert!(handshake(&pki, "leaf", &client(&pki, true, Vec::new())).await);
+        assert!(!handshake(&pki, "leaf", &client(&pki, false, Vec::new())).await);
+        assert!(!handshake(&pki, "self", &client(&pki, true, Vec::new())).await);
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate_needs_no_chain() {
+        let pki = pki();
+        let pinned = client(&pki, false, vec![pin(&pki.selfsigned)]);
+        assert!(handshake(&pki, "self", &pinned).await);
+        assert!(!handshake(&pki, "leaf", &pinned).await);
+        let pinned = client(&pki, true, vec![pin(&pki.leaf)]);
+        assert!(handshake(&pki, "leaf", &pinned).await);
+        assert!(!handshake(&pki, "self", &pinned).await);
+    }
+
+    // Asks a SOCKS5 server behind TLS, which wants a client certificate if
+    // `mtls` is set, for a session to an echo server, with the leaf as the
+    // client certificate if `cert` is set.
+    async fn socks5_over_tls(pki: &Pki, mtls: bool, cert: bool) -> io::Result<()> {
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let target = TargetAddr::Ip(echo.local_addr()?);
+        tokio::spawn(async move {
+            let (mut s, _) = echo.accept().await.unwrap();
+            let (mut r, mut w) = s.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+
+        let relay = RelayOptions { buffers: BufferPool::new(1 << 20), buffer_size: 4096,
+                                   idle_timeout: None, lifetime: None };
+        let router = Arc::new(Connector::direct());
+        let settings = Settings::new(Protocol::Socks5, router, Timeouts::default(), relay);
+        let server = TlsConfig { cert: pki.dir.path().join("leaf.crt"),
+                                 key: pki.dir.path().join("leaf.key"),
+                                 client_ca: mtls.then(|| pki.dir.path().join("ca.crt")) };
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(tls_channel(listener, &server, Arc::new(settings))?));
+
+        let mut client = client(pki, true, Vec::new());
+        if cert {
+            client.cert = Some(pki.dir.path().join("leaf.crt"));
+            client.key = Some(pki.dir.path().join("leaf.key"));
+        }
+        let connector = TlsConnector::from(client_config(&client)?);
+        let conn = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(server_name(&client, "localhost:1080")?, conn).await?;
+        socks5_client::connect(&mut stream, &target, None).await?;
+        stream.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn socks5_runs_over_the_tls_listener() {
+        let pki = pki();
+        socks5_over_tls(&pki, false, false).await.unwrap();
+        socks5_over_tls(&pki, true, true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mtls_turns_away_clients_without_a_certificate() {
+        let pki = pki();
+        assert!(socks5_over_tls(&pki, true, false).await.is_err());
+    }
+
+    #[test]
+    fn pins_are_parsed() {
+        let digest = parse_pin(&pin(b"certificate")).unwrap();
+        assert_eq!(&digest[..], ring::digest::digest(&ring::digest::SHA256, b"certificate").as_ref());
+        assert!(parse_pin("9f86d0").is_err());
+        assert!(parse_pin(&"zz".repeat(32)).is_err());
+    }
+}
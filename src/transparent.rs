@@ -0,0 +1,171 @@
+//! Connections redirected to a listener by the firewall, from applications
+//! which know nothing about proxies.
+//!
+//! With an iptables `REDIRECT` (or `DNAT`) rule the connection is handed to
+//! the listener as if it had been made to it, and the netfilter connection
+//! tracking remembers where it was meant to go:
+//!
+//! ```text
+//! iptables -t nat -A OUTPUT -p tcp -m owner ! --uid-owner proxy \
+//!     -j REDIRECT --to-ports 12345
+//! ```
+//!
+//! With a `TPROXY` rule the connection isn't rewritten at all, and its local
+//! address is the original destination. The listening socket has to be
+//! transparent for that, which takes `CAP_NET_ADMIN`:
+//!
+//! ```text
+//! iptables -t mangle -A PREROUTING -p tcp -j TPROXY --on-port 12345 \
+//!     --tproxy-mark 1
+//! ip rule add fwmark 1 lookup 100
+//! ip route add local 0.0.0.0/0 dev lo table 100
+//! ```
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::unix::io::AsRawFd;
+
+use crate::utilities::other;
+
+/// How connections get to a transparent listener.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Redirect,
+    Tproxy
+}
+
+/// Binds a listener for `mode`.
+pub fn bind(addr: &SocketAddr, mode: Mode) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    if mode == Mode::Tproxy {
+        let (level, name) = if addr.is_ipv4() {
+            (libc::SOL_IP, libc::IP_TRANSPARENT)
+        } else {
+            (libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+        };
+        let on: libc::c_int = 1;
+        let res = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), level, name, &on as *const _ as *const libc::c_void,
+                             mem::size_of_val(&on) as libc::socklen_t)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    socket.bind(*addr)?;
+    socket.listen(1024)
+}
+
+/// Where the application meant `s`, accepted by a listener on `port`, to
+/// go. Connections made to the listener itself are refused, as relaying
+/// them would go round in circles.
+pub fn original_dst(s: &TcpStream, mode: Mode, port: u16) -> io::Result<SocketAddr> {
+    let local = canonical(s.local_addr()?);
+    let (dst, redirected) = match mode {
+        // A TPROXY connection to one of our own addresses could still be
+        // meant for another service, just not for the listener.
+        Mode::Tproxy => (local, local.port() != port || !is_local(local.ip())),
+        Mode::Redirect => {
+            let dst = match redirect_dst(s, local.is_ipv4()) {
+                Ok(dst) => dst,
+                // Without a NAT entry there is nothing to look up.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => local,
+                Err(e) => return Err(e),
+            };
+            let dst = canonical(dst);
+            (dst, dst != local)
+        }
+    };
+    if !redirected {
+        return Err(other("connection was not redirected"));
+    }
+    Ok(dst)
+}
+
+/// `addr` with an IPv4 address in place of a v4-mapped one, as IPv4
+/// connections accepted by a listener on `[::]` have.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// Connections over IPv4 are looked up as such, even on an IPv6 socket.
+fn redirect_dst(s: &TcpStream, ipv4: bool) -> io::Result<SocketAddr> {
+    if ipv4 {
+        let addr: libc::sockaddr_in = getsockopt(s, libc::SOL_IP, libc::SO_ORIGINAL_DST)?;
+        let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+        Ok(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+    } else {
+        let addr: libc::sockaddr_in6 = getsockopt(s, libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)?;
+        let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+        Ok(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), 0, 0).into())
+    }
+}
+
+// Only addresses of this host can be bound without IP_TRANSPARENT.
+fn is_local(ip: IpAddr) -> bool {
+    UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+fn getsockopt<T>(s: &TcpStream, level: libc::c_int, name: libc::c_int) -> io::Result<T> {
+    let mut value: T = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(s.as_raw_fd(), level, name, &mut value as *mut T as *mut libc::c_void,
+                         &mut len)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The accepted end of a connection from `from` to a listener on `addr`.
+    async fn accepted(addr: &str, from: &str) -> io::Result<(TcpStream, u16)> {
+        let listener = TcpListener::bind(addr).await?;
+        let port = listener.local_addr()?.port();
+        let connect = TcpSocket::new_v4()?;
+        connect.bind(from.parse().unwrap())?;
+        let (connected, accepted) = tokio::join!(
+            connect.connect(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+            listener.accept());
+        connected?;
+        Ok((accepted?.0, port))
+    }
+
+    #[tokio::test]
+    async fn tproxy_refuses_connections_to_the_listener() {
+        let (s, port) = accepted("127.0.0.1:0", "127.0.0.1:0").await.unwrap();
+        let e = original_dst(&s, Mode::Tproxy, port).unwrap_err();
+        assert_eq!(e.to_string(), "connection was not redirected");
+        // The same address might be meant for another port of this host.
+        let dst = original_dst(&s, Mode::Tproxy, port.wrapping_add(1)).unwrap();
+        assert_eq!(dst, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+    }
+
+    #[tokio::test]
+    async fn redirect_without_a_nat_entry_is_not_redirected() {
+        let (s, port) = accepted("127.0.0.1:0", "127.0.0.1:0").await.unwrap();
+        let e = original_dst(&s, Mode::Redirect, port).unwrap_err();
+        assert_eq!(e.to_string(), "connection was not redirected");
+    }
+
+    #[tokio::test]
+    async fn ipv4_clients_of_an_ipv6_listener_are_told_apart() {
+        // Not every host has IPv6.
+        let Ok((s, port)) = accepted("[::]:0", "127.0.0.1:0").await else {
+            return;
+        };
+        assert!(s.local_addr().unwrap().is_ipv6());
+        assert!(canonical(s.peer_addr().unwrap()).ip().is_loopback());
+        let e = original_dst(&s, Mode::Redirect, port).unwrap_err();
+        assert_eq!(e.to_string(), "connection was not redirected");
+        let dst = original_dst(&s, Mode::Tproxy, port.wrapping_add(1)).unwrap();
+        assert_eq!(dst, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+    }
+}
@@ -0,0 +1,61 @@
+//! Connections to upstream servers.
+//!
+//! A `Transport` is how an upstream connector, such as the local end of a
+//! tunnel, reaches its server: either plain TCP or TLS on top of it, and
+//! optionally a WebSocket on top of that.
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use std::io;
+
+use crate::config::{TransportConfig, WebSocketConfig};
+use crate::tls;
+use crate::websocket;
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncStream for T {}
+
+pub type BoxStream = Box<dyn AsyncStream>;
+
+#[derive(Clone)]
+pub struct Transport {
+    address: String,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    websocket: Option<WebSocketConfig>
+}
+
+impl Transport {
+    pub fn new(config: &TransportConfig) -> io::Result<Transport> {
+        let tls = match config.tls {
+            Some(ref tls) => {
+                let connector = TlsConnector::from(tls::client_config(tls)?);
+                Some((connector, tls::server_name(tls, &config.address)?))
+            }
+            None => None,
+        };
+        Ok(Transport { address: config.address.clone(), tls, websocket: config.websocket.clone() })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Opens a new stream to the upstream server.
+    pub async fn connect(&self) -> io::Result<BoxStream> {
+        let s = TcpStream::connect(&self.address).await?;
+        s.set_nodelay(true)?;
+        let s: BoxStream = match self.tls {
+            Some((ref connector, ref name)) => Box::new(connector.connect(name.clone(), s).await?),
+            None => Box::new(s),
+        };
+        match self.websocket {
+            Some(ref ws) => {
+                let host = ws.host.as_deref().unwrap_or(&self.address);
+                Ok(Box::new(websocket::connect(s, &ws.path, host).await?))
+            }
+            None => Ok(s),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! An encrypted tunnel between two instances.
+//!
+//! The local instance accepts SOCKS5 from applications and hands each
+//! request over to the remote instance, which dials the target. Every
+//! session opens its own TLS connection to the remote instance, and starts
+//! it with
+//!
+//! ```text
+//! +-------------------+------+----------+----------+
+//! | SHA-256(password) | ATYP | DST.ADDR | DST.PORT |
+//! +-------------------+------+----------+----------+
+//! |        32         |  1   | Variable |    2     |
+//! +-------------------+------+----------+----------+
+//! ```
+//!
+//! where the address is encoded as in a SOCKS5 request. The remote instance
+//! answers with a single SOCKS5 reply code, and if that is a success the
+//! relay starts right away.
+//!
+//! With `multiplex = true` on the upstream, the local instance keeps a
+//! single connection instead, whose `ATYP` is 0 and which carries streams
+//! of the `mux` module from then on. Each stream starts with `ATYP`,
+//! `DST.ADDR` and `DST.PORT` and is answered just like a connection of its
+//! own.
+//!
+//! A reverse tunnel (see the `reverse` module) is a multiplexed connection
+//! the other way round. Its `ATYP` is 255, followed by the address the
+//! remote instance is asked to listen on and the target connections to it
+//! are meant for, both encoded as in a SOCKS5 request. Once the remote
+//! instance answered with a reply code, it opens a stream for every
+//! connection it accepts.
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+use std::io;
+use std::sync::Arc;
+
+use crate::access_log;
+use crate::client::{connect_target, relay_outbound, Origin, Protocol, Settings};
+use crate::codec::{parse_addr, reply_code, reply_error, write_addr};
+use crate::connector::TargetAddr;
+use crate::endpoint::Connection;
+use crate::mux::{self, MuxStream, Session};
+use crate::transport::{BoxStream, Transport};
+use crate::utilities::{digests_match, other, timeout, BindRange, TimeoutKind};
+
+/// The secret both ends of a tunnel share.
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn new(password: &str) -> Key {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, password.as_bytes()).as_ref());
+        Key(key)
+    }
+
+    pub fn digest(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn matches(&self, other: &[u8; 32]) -> bool {
+        digests_match(&self.0, other)
+    }
+}
+
+// `ATYP` of a connection carrying multiplexed streams.
+const MULTIPLEXED: u8 = 0;
+/// `ATYP` of a reverse tunnel.
+pub const REVERSE: u8 = 255;
+
+/// The local end of a tunnel.
+pub struct TunnelClient {
+    transport: Transport,
+    key: Key,
+    // The shared connection, if sessions are multiplexed.
+    session: Option<Mutex<Option<Arc<Session>>>>
+}
+
+impl TunnelClient {
+    pub fn new(transport: Transport, key: Key, multiplex: bool) -> TunnelClient {
+        let session = if multiplex { Some(Mutex::new(None)) } else { None };
+        TunnelClient { transport, key, session }
+    }
+
+    /// Asks the remote instance to connect to `target`, and returns the
+    /// stream to relay once it did.
+    pub async fn connect(&self, target: &TargetAddr) -> io::Result<BoxStream> {
+        debug!("tunneling to {} through {}", target, self.transport.address());
+        match self.session {
+            Some(ref session) => {
+                let s = open_stream(&*self.session(session).await?, target).await?;
+                Ok(Box::new(s))
+            }
+            None => {
+                let mut req = self.key.0.to_vec();
+                write_addr(&mut req, target)?;
+                let mut s = self.transport.connect().await?;
+                request(&mut s, &req).await?;
+                Ok(s)
+            }
+        }
+    }
+
+    // The shared connection, made anew if there is none yet or the last one
+    // is gone.
+    async fn session(&self, session: &Mutex<Option<Arc<Session>>>) -> io::Result<Arc<Session>> {
+        let mut session = session.lock().await;
+        if let Some(ref s) = *session {
+            if !s.is_closed() {
+                return Ok(s.clone());
+            }
+        }
+        debug!("opening multiplexed tunnel to {}", self.transport.address());
+        let mut s = self.transport.connect().await?;
+        s.write_all(&self.key.0).await?;
+        s.write_all(&[MULTIPLEXED]).await?;
+        s.flush().await?;
+        let s = Arc::new(Session::new(s, mux::Mode::Client));
+        *session = Some(s.clone());
+        Ok(s)
+    }
+}
+
+/// Opens a stream of a multiplexed tunnel to `target`.
+pub async fn open_stream(session: &Session, target: &TargetAddr) -> io::Result<MuxStream> {
+    let mut s = session.open()?;
+    let mut req = Vec::new();
+    write_addr(&mut req, target)?;
+    request(&mut s, &req).await?;
+    Ok(s)
+}
+
+/// Sends `req` and waits for the reply code.
+pub async fn request<S>(s: &mut S, req: &[u8]) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    s.write_all(req).await?;
+    s.flush().await?;
+    let mut rep = [0u8];
+    s.read_exact(&mut rep).await?;
+    match rep[0] {
+        0 => Ok(()),
+        rep => Err(reply_error(rep)),
+    }
+}
+
+/// Serves a connection from the local end of a tunnel.
+pub async fn serve<S: Connection>(mut conn: S, origin: &Origin, key: &Key,
+                                  reverse: &[BindRange], settings: &Arc<Settings>)
+    -> io::Result<(u64, u64)>
+{
+    let timeouts = &settings.timeouts;
+    let request = authenticate(&mut conn, key);
+    let atyp = timeout(timeouts.handshake(), request, TimeoutKind::Handshake).await?;
+    match atyp {
+        MULTIPLEXED => {
+            let session = Session::new(conn, mux::Mode::Server);
+            return serve_multiplexed(session, origin, settings).await;
+        }
+        REVERSE if !reverse.is_empty() => {
+            return crate::reverse::expose(conn, reverse, settings).await;
+        }
+        REVERSE => return Err(other("reverse tunnels are not allowed")),
+        _ => {}
+    }
+    let request = parse_addr(&mut conn, atyp);
+    let target = timeout(timeouts.handshake(), request, TimeoutKind::Handshake).await?;
+    serve_target(conn, origin, target, settings).await
+}
+
+/// Serves every stream of a multiplexed connection, and resolves to the sum
+/// of their bytes.
+pub async fn serve_multiplexed(mut session: Session, origin: &Origin, settings: &Arc<Settings>)
+    -> io::Result<(u64, u64)>
+{
+    let mut streams = tokio::task::JoinSet::new();
+    let mut total = (0, 0);
+    loop {
+        tokio::select! {
+            stream = session.accept() => match stream {
+                Some(stream) => {
+                    let settings = settings.clone();
+                    let origin = origin.clone();
+                    streams.spawn(async move { serve_stream(stream, &origin, &settings).await });
+                }
+                None => break,
+            },
+            Some(res) = streams.join_next() => {
+                if let Ok(Ok((a, b))) = res {
+                    total = (total.0 + a, total.1 + b);
+                }
+            }
+        }
+    }
+    while let Some(res) = streams.join_next().await {
+        if let Ok(Ok((a, b))) = res {
+            total = (total.0 + a, total.1 + b);
+        }
+    }
+    Ok(total)
+}
+
+// Every stream is a session of its own in the access log.
+async fn serve_stream(mut stream: MuxStream, origin: &Origin, settings: &Settings)
+    -> io::Result<(u64, u64)>
+{
+    let serve = async {
+        let request = async {
+            let mut atyp = [0u8];
+            stream.read_exact(&mut atyp).await?;
+            parse_addr(&mut stream, atyp[0]).await
+        };
+        let target = timeout(settings.timeouts.handshake(), request, TimeoutKind::Handshake).await?;
+        let res = serve_target(stream, origin, target.clone(), settings).await;
+        if let Err(ref e) = res {
+            debug!("tunneled stream to {} failed: {}", target, e);
+        }
+        res
+    };
+    let (res, mut record) = access_log::collect(serve).await;
+    if let Some(ref log) = settings.access_log {
+        record.close(origin, &res);
+        log.write(&record);
+    }
+    res
+}
+
+async fn serve_target<S: Connection>(mut conn: S, origin: &Origin, target: TargetAddr,
+                                     settings: &Settings)
+    -> io::Result<(u64, u64)>
+{
+    let timeouts = &settings.timeouts;
+    // The far end of a reverse tunnel connects to its own target only.
+    let out = match settings.protocol {
+        Protocol::Forward(ref only) if *only != target => {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "target not allowed"))
+        }
+        _ => connect_target(settings, origin, target).await,
+    };
+    let rep = reply_code(&out);
+    let reply = async {
+        conn.write_all(&[rep]).await?;
+        conn.flush().await
+    };
+    timeout(timeouts.handshake(), reply, TimeoutKind::Handshake).await?;
+    relay_outbound(conn.into_endpoint(), out?, origin, settings).await
+}
+
+// Checks the key, and returns the `ATYP` following it.
+async fn authenticate<S>(conn: &mut S, key: &Key) -> io::Result<u8>
+    where S: AsyncRead + Unpin
+{
+    let mut digest = [0u8; 32];
+    conn.read_exact(&mut digest).await?;
+    if !key.matches(&digest) {
+        return Err(other("tunnel authentication failed"));
+    }
+    let mut atyp = [0u8];
+    conn.read_exact(&mut atyp).await?;
+    Ok(atyp[0])
+}
@@ -1,111 +1,219 @@
-use futures::{Future,Poll};
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::str;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::net::{IpAddr, SocketAddr};
+use std::str::{self, FromStr};
 use std::io::{self};
-use std::time::Duration;
-use tokio_core::reactor::{self,Handle};
-
-// Mix two different Future into one,
-// to be used for impl Trait feature.
-pub enum EitherFuture<L,R,T,E>
-    where L:Future<Item=T,Error=E>,
-          R:Future<Item=T,Error=E>
-{
-    Left(L),
-    Right(R)
-}
-
-use self::EitherFuture::{Left,Right};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time;
 
-impl<L,R,T,E> Future for EitherFuture<L,R,T,E>
-    where L:Future<Item=T,Error=E>,
-          R:Future<Item=T,Error=E>
-{
-    type Item=T;
-    type Error=E;
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self {
-            Left(f) => f.poll(),
-            Right(f) => f.poll()
-        }
-    }
-}
+use crate::connector::TargetAddr;
 
-// Extracts the name and port from addr_buf and returns them, converting
-// the name to the form that the trust-dns client can use. If the original
-// name can be parsed as an IP address, makes a SocketAddr from that
-// address and the port and returns it; we skip DNS resolution in that
-// case.
-pub fn name_port(addr_buf: &[u8]) -> io::Result<SocketAddr> {
+// Extracts the name and port from addr_buf and returns them. If the
+// original name can be parsed as an IP address, makes a SocketAddr from that
+// address and the port and returns it, so no DNS resolution is needed.
+pub fn name_port(addr_buf: &[u8]) -> io::Result<TargetAddr> {
     // The last two bytes of the buffer are the port, and the other parts of it
     // are the hostname.
     let hostname = &addr_buf[..addr_buf.len() - 2];
-    let hostname = try!(str::from_utf8(hostname).map_err(|_e| {
+    let hostname = str::from_utf8(hostname).map_err(|_e| {
         other("hostname buffer provided was not valid utf-8")
-    }));
+    })?;
     let pos = addr_buf.len() - 2;
     let port = ((addr_buf[pos] as u16) << 8) | (addr_buf[pos + 1] as u16);
 
     if let Ok(ip) = hostname.parse() {
-        return Ok(SocketAddr::new(ip, port))
+        return Ok(TargetAddr::Ip(SocketAddr::new(ip, port)))
     }
-
-    format!("{}:{}",hostname,port).to_socket_addrs()?.next()
-    .map_or(Err(other("host name didn't resolve to valid IP address")), |a| {
-        info!("target: {}:{} = {:?}", hostname, port, a);
-        Ok(a)
-    })
+    Ok(TargetAddr::Domain(hostname.to_string(), port))
 }
 
+/// The future of a trait method which has to be object safe.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
 pub fn other(desc: &str) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, desc)
-}
-
-// Here we create a timeout future, using the `Timeout::new` method,
-// which will create a future that will resolve to `()` in 10 seconds.
-// We then apply this timeout to the entire future all at once by
-// performing a `select` between the timeout and the future itself.
-pub fn timeout<T>(handle: &Handle, future:impl Future<Item=T,Error=io::Error>, msg:&'static str) 
-    -> impl Future<Item=T,Error=io::Error> {
-    let timeout = reactor::Timeout::new(Duration::new(10, 0), handle).unwrap();
-    struct Timeout<F,TO> { f:F, m:&'static str, t:TO };
-    impl<T,F,TO> Future for Timeout<F,TO>
-        where F: Future<Item=T, Error=io::Error>,
-              TO: Future<Item=(), Error=io::Error>
-    {
-        type Item = T;
-        type Error = io::Error;        
-        fn poll(&mut self) -> Poll<T, io::Error> {
-            let Timeout {f:future, m:msg, t:timeout} = self;
-            future.map(Ok).select(timeout.map(Err)).then(move |res| {
-            match res {
-                    // The future finished before the timeout fired, so we
-                    // drop the future representing the timeout, canceling the
-                    // timeout, and then return the pair of connections the
-                    // handshake resolved with.
-                    Ok((Ok(t), _timeout)) => Ok(t),
-
-                    // The timeout fired before the future finished. In this
-                    // case we drop the future.
-                    //
-                    // This automatically "cancels" any I/O associated with the
-                    // handshake: reads, writes, TCP connects, etc. All of those
-                    // I/O resources are owned by the future, so if we drop the
-                    // future they're all released!
-                    Ok((Err(()), _handshake)) => {
-                        Err(other(msg))
-                    }
-
-                    // One of the futures (handshake or timeout) hit an error
-                    // along the way. We're not entirely sure which at this
-                    // point, but in any case that shouldn't happen, so we just
-                    // keep propagating along the error.
-                    Err((e, _other)) => Err(e),
-                }
-            }).poll()
+    io::Error::other(desc.to_string())
+}
+
+#[cfg(feature = "sqlite")]
+pub fn sql_error(e: rusqlite::Error) -> io::Error {
+    other(&e.to_string())
+}
+
+/// A block of IP addresses, such as `10.0.0.0/8`. A bare address is a
+/// block of its own.
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Listeners on IPv6 addresses see IPv4 peers as mapped addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
         }
     }
-    Timeout{f:future, m:msg, t:timeout}
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Cidr> {
+        let invalid = || other(&format!("invalid address block {}", s));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// An address with a port or a range of ports, such as `0.0.0.0:2000-2999`
+/// or `[::1]:8080`.
+#[derive(Clone, Copy, Debug)]
+pub struct BindRange {
+    addr: IpAddr,
+    ports: (u16, u16)
+}
+
+impl BindRange {
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        addr.ip() == self.addr && (self.ports.0..=self.ports.1).contains(&addr.port())
+    }
+}
+
+impl FromStr for BindRange {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<BindRange> {
+        let invalid = || other(&format!("invalid address and ports {}", s));
+        let (addr, ports) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let addr: IpAddr = addr.trim_start_matches('[').trim_end_matches(']').parse()
+            .map_err(|_| invalid())?;
+        let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+        let ports: (u16, u16) = (first.parse().map_err(|_| invalid())?,
+                                 last.parse().map_err(|_| invalid())?);
+        if ports.0 > ports.1 {
+            return Err(invalid());
+        }
+        Ok(BindRange { addr, ports })
+    }
+}
+
+/// Whether the digests `a` and `b` are the same, compared in full so that
+/// it takes equally long however many bytes match and a secret can't be
+/// guessed byte by byte.
+pub fn digests_match(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// How often the entries of a `Swept` map are looked over.
+pub const SWEEP_EVERY: Duration = Duration::from_secs(10);
+
+/// A map of entries by address or user, whose stale entries are swept out
+/// every so often rather than looked over whenever one is added. It holds
+/// on to the entries which are in use, plus those which went stale since
+/// the last sweep.
+pub struct Swept<K, V> {
+    entries: HashMap<K, V>,
+    every: Duration,
+    last: Instant
+}
+
+impl<K: Hash + Eq, V> Swept<K, V> {
+    pub fn new(every: Duration) -> Swept<K, V> {
+        Swept { entries: HashMap::new(), every, last: Instant::now() }
+    }
+
+    /// Drops the entries `keep` turns down, unless that was done less than
+    /// `every` ago.
+    pub fn sweep(&mut self, keep: impl FnMut(&K, &mut V) -> bool) {
+        if self.last.elapsed() >= self.every {
+            self.entries.retain(keep);
+            self.last = Instant::now();
+        }
+    }
+}
+
+impl<K, V> Deref for Swept<K, V> {
+    type Target = HashMap<K, V>;
+    fn deref(&self) -> &HashMap<K, V> {
+        &self.entries
+    }
+}
+
+impl<K, V> DerefMut for Swept<K, V> {
+    fn deref_mut(&mut self) -> &mut HashMap<K, V> {
+        &mut self.entries
+    }
+}
+
+/// The stage of a session which took too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Handshake,
+    Connect,
+    Idle,
+    Lifetime
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutKind::Handshake => "timeout during handshake",
+            TimeoutKind::Connect => "timeout connecting to target",
+            TimeoutKind::Idle => "session idle timeout",
+            TimeoutKind::Lifetime => "session lifetime exceeded",
+        })
+    }
+}
+
+impl Error for TimeoutKind {}
+
+pub fn timeout_error(kind: TimeoutKind) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, kind)
+}
+
+/// Tells which timeout, if any, caused `e`.
+pub fn timeout_kind(e: &io::Error) -> Option<TimeoutKind> {
+    e.get_ref().and_then(|e| e.downcast_ref::<TimeoutKind>()).copied()
+}
+
+// Here we apply a timeout to the entire future all at once. If the timer
+// fires first the future is dropped, which automatically "cancels" any I/O
+// associated with it: reads, writes, TCP connects, etc. All of those I/O
+// resources are owned by the future, so if we drop the future they're all
+// released!
+pub async fn timeout<T>(limit: Duration, future: impl Future<Output=io::Result<T>>,
+                        kind: TimeoutKind)
+    -> io::Result<T>
+{
+    match time::timeout(limit, future).await {
+        Ok(res) => res,
+        Err(_elapsed) => Err(timeout_error(kind)),
+    }
 }
 
 //use std::mem::size_of;
@@ -115,3 +223,105 @@ pub fn timeout<T>(handle: &Handle, future:impl Future<Item=T,Error=io::Error>, m
 //    println!("{} size={},type name len={}", name, size_of::<T>(), s.len());
 //}
 
+/// The UTC date and time of `t`, down to milliseconds.
+pub fn civil(t: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+    // Days since 1970-01-01 to a date of the proleptic Gregorian calendar,
+    // after http://howardhinnant.github.io/date_algorithms.html.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, rest / 3600, rest / 60 % 60, rest % 60, since.subsec_millis())
+}
+
+/// `t` as an RFC 3339 timestamp in UTC, down to milliseconds.
+pub fn timestamp(t: SystemTime) -> String {
+    let (year, month, day, h, m, s, ms) = civil(t);
+    format!("{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, h, m, s, ms)
+}
+
+/// Appends `s` to `out` as a JSON string.
+pub fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_match_only_in_full() {
+        let a = [7u8; 32];
+        let mut b = a;
+        assert!(digests_match(&a, &b));
+        b[31] ^= 1;
+        assert!(!digests_match(&a, &b));
+    }
+
+    #[tokio::test]
+    async fn timeouts_tell_their_kind() {
+        let kinds = [TimeoutKind::Handshake, TimeoutKind::Connect, TimeoutKind::Idle,
+                     TimeoutKind::Lifetime];
+        for kind in kinds {
+            let never = std::future::pending::<io::Result<()>>();
+            let e = timeout(Duration::from_millis(10), never, kind).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(timeout_kind(&e), Some(kind));
+            assert_eq!(e.to_string(), kind.to_string());
+        }
+        let quick = async { Ok(7) };
+        assert_eq!(timeout(Duration::from_secs(10), quick, TimeoutKind::Connect).await.unwrap(), 7);
+        assert_eq!(timeout_kind(&io::Error::from(io::ErrorKind::TimedOut)), None);
+        assert_eq!(timeout_kind(&other("timeout during handshake")), None);
+    }
+
+    #[test]
+    fn sweeps_wait_for_their_turn() {
+        let mut map = Swept::new(Duration::from_millis(50));
+        map.insert(1, ());
+        map.sweep(|_, _| false);
+        assert_eq!(map.len(), 1);
+        std::thread::sleep(Duration::from_millis(50));
+        map.insert(2, ());
+        map.sweep(|&k, _| k == 2);
+        assert_eq!(map.keys().collect::<Vec<_>>(), [&2]);
+        // The next one is a while off again.
+        map.sweep(|_, _| false);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn bind_ranges_hold_their_ports() {
+        let range: BindRange = "0.0.0.0:2000-2999".parse().unwrap();
+        assert!(range.contains("0.0.0.0:2000".parse().unwrap()));
+        assert!(range.contains("0.0.0.0:2999".parse().unwrap()));
+        assert!(!range.contains("0.0.0.0:3000".parse().unwrap()));
+        assert!(!range.contains("127.0.0.1:2000".parse().unwrap()));
+        let single: BindRange = "[::1]:8080".parse().unwrap();
+        assert!(single.contains("[::1]:8080".parse().unwrap()));
+        assert!(!single.contains("[::1]:8081".parse().unwrap()));
+        for invalid in ["0.0.0.0", "0.0.0.0:3000-2000", "host:80", "0.0.0.0:1-70000"] {
+            assert!(invalid.parse::<BindRange>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,601 @@
+//! Byte streams carried in the binary messages of a WebSocket, so they get
+//! through HTTP proxies and CDNs which pass nothing else.
+//!
+//! Just like TLS, the upgrade of an accepted connection is driven by its
+//! first read, so it runs in the client's own task and is covered by the
+//! handshake timeout. Closing the write half sends a close frame, and a
+//! close frame from the peer reads as the end of the stream.
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use crate::config::WebSocketConfig;
+use crate::endpoint::{new_streamendpoint, Connection, Endpoint};
+use crate::utilities::other;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Upper bound of the head of an upgrade request or response.
+const MAX_HEAD: usize = 8 << 10;
+const MAX_FRAME: usize = 16 << 10;
+
+const CONTINUATION: u8 = 0;
+const TEXT: u8 = 1;
+const BINARY: u8 = 2;
+const CLOSE: u8 = 8;
+const PING: u8 = 9;
+const PONG: u8 = 10;
+
+/// Which side of the upgrade a stream is. Frames from clients are masked,
+/// those from servers are not.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Client,
+    Server
+}
+
+struct FrameHeader {
+    opcode: u8,
+    len: u64,
+    mask: Option<[u8; 4]>,
+    // Length of the header itself.
+    size: usize
+}
+
+pub struct WsStream<S> {
+    inner: S,
+    role: Role,
+    rng: SystemRandom,
+    // Raw bytes read but not processed yet.
+    rbuf: Vec<u8>,
+    // Payload left of the current data frame, and its mask.
+    payload: u64,
+    mask: Option<[u8; 4]>,
+    mask_pos: usize,
+    closed: bool,
+    wbuf: Vec<u8>,
+    wpos: usize,
+    close_sent: bool
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+    fn new(inner: S, role: Role, rbuf: Vec<u8>) -> WsStream<S> {
+        WsStream {
+            inner, role, rng: SystemRandom::new(), rbuf, payload: 0, mask: None, mask_pos: 0,
+            closed: false, wbuf: Vec::new(), wpos: 0, close_sent: false
+        }
+    }
+
+    // Reads more raw bytes, and resolves to how many there were.
+    fn poll_more(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut chunk = [0u8; 4096];
+        let mut buf = ReadBuf::new(&mut chunk);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+        self.rbuf.extend_from_slice(buf.filled());
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.wpos < self.wbuf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf[self.wpos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wpos += n;
+        }
+        self.wbuf.clear();
+        self.wpos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let masked = if self.role == Role::Client { 0x80 } else { 0 };
+        self.wbuf.push(0x80 | opcode);
+        match payload.len() {
+            n if n < 126 => self.wbuf.push(masked | n as u8),
+            n if n <= 0xffff => {
+                self.wbuf.push(masked | 126);
+                self.wbuf.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                self.wbuf.push(masked | 127);
+                self.wbuf.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        if self.role == Role::Client {
+            let mut mask = [0u8; 4];
+            self.rng.fill(&mut mask).expect("system random generator failed");
+            self.wbuf.extend_from_slice(&mask);
+            self.wbuf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            self.wbuf.extend_from_slice(payload);
+        }
+    }
+
+    // Parses the frame header at the start of `rbuf`, if it is all there.
+    fn parse_header(&self) -> io::Result<Option<FrameHeader>> {
+        let b = &self.rbuf;
+        if b.len() < 2 {
+            return Ok(None);
+        }
+        let opcode = b[0] & 0x0f;
+        let masked = b[1] & 0x80 != 0;
+        if masked != (self.role == Role::Server) {
+            return Err(other("websocket: wrong masking of frame"));
+        }
+        let (len, mut pos) = match b[1] & 0x7f {
+            126 if b.len() >= 4 => (u16::from_be_bytes([b[2], b[3]]) as u64, 4),
+            127 if b.len() >= 10 => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&b[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(None),
+            n => (n as u64, 2),
+        };
+        let mask = if masked {
+            if b.len() < pos + 4 {
+                return Ok(None);
+            }
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&b[pos..pos + 4]);
+            pos += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        Ok(Some(FrameHeader { opcode, len, mask, size: pos }))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        let this = self.get_mut();
+        loop {
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+            if this.payload > 0 {
+                if this.rbuf.is_empty() && ready!(this.poll_more(cx))? == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                let n = (this.payload.min(this.rbuf.len() as u64) as usize).min(buf.remaining());
+                if let Some(mask) = this.mask {
+                    for (i, b) in this.rbuf[..n].iter_mut().enumerate() {
+                        *b ^= mask[(this.mask_pos + i) % 4];
+                    }
+                }
+                buf.put_slice(&this.rbuf[..n]);
+                this.rbuf.drain(..n);
+                this.payload -= n as u64;
+                this.mask_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let FrameHeader { opcode, len, mask, size: head } = match this.parse_header()? {
+                Some(header) => header,
+                None => {
+                    // The peer may just drop the connection instead of
+                    // sending a close frame.
+                    if ready!(this.poll_more(cx))? == 0 {
+                        if this.rbuf.is_empty() {
+                            return Poll::Ready(Ok(()));
+                        }
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    continue;
+                }
+            };
+            match opcode {
+                CONTINUATION | BINARY => {
+                    this.rbuf.drain(..head);
+                    this.payload = len;
+                    this.mask = mask;
+                    this.mask_pos = 0;
+                    continue;
+                }
+                TEXT => return Poll::Ready(Err(other("websocket: unexpected text frame"))),
+                CLOSE | PING | PONG => {}
+                _ => return Poll::Ready(Err(other("websocket: unknown opcode"))),
+            }
+
+            // Control frames are small, and handled once they are complete.
+            if len > 125 {
+                return Poll::Ready(Err(other("websocket: oversized control frame")));
+            }
+            let end = head + len as usize;
+            if this.rbuf.len() < end {
+                if ready!(this.poll_more(cx))? == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                continue;
+            }
+            let mut payload: Vec<u8> = this.rbuf.drain(..end).skip(head).collect();
+            if let Some(mask) = mask {
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= mask[i % 4];
+                }
+            }
+            match opcode {
+                CLOSE => this.closed = true,
+                PING if !this.close_sent => {
+                    this.queue_frame(PONG, &payload);
+                    // The pong goes out with the next write if it can't now.
+                    if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        if this.close_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = buf.len().min(MAX_FRAME);
+        if n == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        this.queue_frame(BINARY, &buf[..n]);
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            ready!(this.poll_write_buf(cx))?;
+            // Normal closure.
+            this.queue_frame(CLOSE, &1000u16.to_be_bytes());
+            this.close_sent = true;
+        }
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    use base64::Engine;
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(key.as_bytes());
+    ctx.update(GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(ctx.finish())
+}
+
+// Reads an HTTP head, and returns it along with whatever came after it.
+async fn read_head<S: AsyncRead + Unpin>(s: &mut S) -> io::Result<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            let head = String::from_utf8(buf).map_err(|_| other("websocket: invalid HTTP head"))?;
+            return Ok((head, rest));
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(other("websocket: HTTP head too long"));
+        }
+        let n = s.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (n, v) = line.split_once(':')?;
+        if n.trim().eq_ignore_ascii_case(name) { Some(v.trim()) } else { None }
+    })
+}
+
+/// Upgrades a connection to a WebSocket server.
+pub async fn connect<S>(mut s: S, path: &str, host: &str) -> io::Result<WsStream<S>>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    use base64::Engine;
+    let mut key = [0u8; 16];
+    SystemRandom::new().fill(&mut key).expect("system random generator failed");
+    let key = base64::engine::general_purpose::STANDARD.encode(key);
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\n\
+                           Connection: Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+                           Sec-WebSocket-Version: 13\r\n\r\n", path, host, key);
+    s.write_all(request.as_bytes()).await?;
+    s.flush().await?;
+    let (head, rest) = read_head(&mut s).await?;
+    let status = head.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(other(&format!("websocket: upgrade refused: {}", status)));
+    }
+    if header(&head, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(other("websocket: invalid Sec-WebSocket-Accept"));
+    }
+    Ok(WsStream::new(s, Role::Client, rest))
+}
+
+/// Accepts the upgrade of a client connection.
+pub async fn accept<S>(mut s: S, config: &WebSocketConfig) -> io::Result<WsStream<S>>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    let (head, rest) = read_head(&mut s).await?;
+    let mut request = head.lines().next().unwrap_or("").split_whitespace();
+    let (method, target) = (request.next(), request.next());
+    let path = target.map(|t| t.split('?').next().unwrap_or(t));
+    let host_ok = match config.host {
+        Some(ref host) => header(&head, "Host").is_some_and(|h| h.eq_ignore_ascii_case(host)),
+        None => true,
+    };
+    let key = header(&head, "Sec-WebSocket-Key");
+    let upgrade = header(&head, "Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+
+    let refusal = if method != Some("GET") || !upgrade || key.is_none() {
+        Some("400 Bad Request")
+    } else if path != Some(config.path.as_str()) || !host_ok {
+        Some("404 Not Found")
+    } else {
+        None
+    };
+    if let Some(status) = refusal {
+        let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                               status);
+        s.write_all(response.as_bytes()).await?;
+        s.shutdown().await?;
+        return Err(other(&format!("websocket: refused upgrade of {:?}", target.unwrap_or(""))));
+    }
+
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                            Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                           accept_key(key.unwrap_or("")));
+    s.write_all(response.as_bytes()).await?;
+    s.flush().await?;
+    Ok(WsStream::new(s, Role::Server, rest))
+}
+
+type Upgrade<S> = Pin<Box<dyn Future<Output = io::Result<WsStream<S>>> + Send>>;
+
+/// A client connection which is upgraded to a WebSocket on first use.
+pub struct WsConnection<S> {
+    state: State<S>
+}
+
+enum State<S> {
+    Upgrading(Upgrade<S>),
+    Ready(Box<WsStream<S>>),
+    Failed
+}
+
+impl<S: Connection> WsConnection<S> {
+    pub fn new(conn: S, config: WebSocketConfig) -> WsConnection<S> {
+        let upgrade = Box::pin(async move { accept(conn, &config).await });
+        WsConnection { state: State::Upgrading(upgrade) }
+    }
+
+    fn poll_stream(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&mut WsStream<S>>> {
+        if let State::Upgrading(ref mut upgrade) = self.state {
+            match ready!(upgrade.as_mut().poll(cx)) {
+                Ok(stream) => self.state = State::Ready(Box::new(stream)),
+                Err(e) => {
+                    self.state = State::Failed;
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        match self.state {
+            State::Ready(ref mut stream) => Poll::Ready(Ok(stream)),
+            _ => Poll::Ready(Err(other("websocket upgrade failed"))),
+        }
+    }
+}
+
+impl<S: Connection> AsyncRead for WsConnection<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+        -> Poll<io::Result<()>>
+    {
+        let stream = ready!(self.get_mut().poll_stream(cx))?;
+        Pin::new(stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: Connection> AsyncWrite for WsConnection<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let stream = ready!(self.get_mut().poll_stream(cx))?;
+        Pin::new(stream).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_stream(cx))?;
+        Pin::new(stream).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_stream(cx))?;
+        Pin::new(stream).poll_shutdown(cx)
+    }
+}
+
+impl<S: Connection> Connection for WsConnection<S> {
+    fn into_endpoint(self) -> impl Endpoint + Send {
+        new_streamendpoint(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    fn config() -> WebSocketConfig {
+        WebSocketConfig { path: "/ws".to_string(), host: Some("example.com".to_string()) }
+    }
+
+    // A stream of `role` over one end of a pipe, and the other end raw.
+    fn raw(role: Role, capacity: usize) -> (WsStream<DuplexStream>, DuplexStream) {
+        let (a, b) = duplex(capacity);
+        (WsStream::new(a, role, Vec::new()), b)
+    }
+
+    // An unmasked frame, as a server sends it.
+    fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let (mut s, _) = raw(Role::Server, 1);
+        s.queue_frame(opcode, payload);
+        s.wbuf
+    }
+
+    // The same frame masked, as a client sends it.
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let (mut s, _) = raw(Role::Client, 1);
+        s.queue_frame(opcode, payload);
+        s.wbuf
+    }
+
+    #[test]
+    fn accept_keys_follow_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn lengths_take_7_16_or_64_bits() {
+        assert_eq!(frame(BINARY, &[7; 125])[..2], [0x82, 125]);
+        assert_eq!(frame(BINARY, &[7; 126])[..4], [0x82, 126, 0, 126]);
+        assert_eq!(frame(BINARY, &[7; 0xffff])[..4], [0x82, 126, 0xff, 0xff]);
+        assert_eq!(frame(BINARY, &[7; 0x10000])[..10], [0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(frame(BINARY, &[7; 0x10000]).len(), 10 + 0x10000);
+    }
+
+    #[test]
+    fn frames_of_clients_are_masked() {
+        let f = masked(BINARY, b"hello");
+        assert_eq!(f[..2], [0x82, 0x80 | 5]);
+        let mask = &f[2..6];
+        let plain: Vec<u8> = f[6..].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+        assert_eq!(plain, b"hello");
+    }
+
+    #[tokio::test]
+    async fn streams_round_trip() {
+        let (client, server) = duplex(1 << 16);
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let server = tokio::spawn(async move {
+            let mut s = accept(server, &config()).await.unwrap();
+            let mut got = Vec::new();
+            s.read_to_end(&mut got).await.unwrap();
+            assert!(got == sent);
+            s.write_all(b"done").await.unwrap();
+            s.shutdown().await.unwrap();
+        });
+        let mut s = connect(client, "/ws", "example.com").await.unwrap();
+        s.write_all(&data).await.unwrap();
+        s.shutdown().await.unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"done");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_frames_are_read_in_pieces() {
+        // A single byte at a time through the pipe.
+        let (mut s, mut peer) = raw(Role::Client, 1);
+        let data: Vec<u8> = (0..0x10001u32).map(|i| i as u8).collect();
+        let mut bytes = frame(BINARY, &data[..300]);
+        bytes.extend(frame(CONTINUATION, &data[300..]));
+        bytes.extend(frame(CLOSE, &1000u16.to_be_bytes()));
+        let writer = tokio::spawn(async move { peer.write_all(&bytes).await.unwrap(); peer });
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).await.unwrap();
+        assert!(got == data);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_and_close_ends_the_stream() {
+        let (mut s, mut peer) = raw(Role::Server, 1 << 16);
+        let mut bytes = masked(PING, b"are you there");
+        bytes.extend(masked(BINARY, b"data"));
+        bytes.extend(masked(CLOSE, &[]));
+        peer.write_all(&bytes).await.unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"data");
+        let pong = frame(PONG, b"are you there");
+        let mut answer = vec![0u8; pong.len()];
+        peer.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, pong);
+        // Closing our side sends a close frame with normal closure.
+        s.shutdown().await.unwrap();
+        let mut close = Vec::new();
+        peer.read_to_end(&mut close).await.unwrap();
+        assert_eq!(close, frame(CLOSE, &1000u16.to_be_bytes()));
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_refused() {
+        let cases = [
+            (Role::Server, frame(BINARY, b"unmasked"), "wrong masking"),
+            (Role::Client, masked(BINARY, b"masked"), "wrong masking"),
+            (Role::Client, frame(TEXT, b"text"), "unexpected text frame"),
+            (Role::Client, frame(PING, &[0; 126]), "oversized control frame"),
+            (Role::Client, frame(5, &[]), "unknown opcode"),
+        ];
+        for (role, bytes, msg) in cases {
+            let (mut s, mut peer) = raw(role, 1 << 16);
+            peer.write_all(&bytes).await.unwrap();
+            let e = s.read(&mut [0u8; 16]).await.unwrap_err();
+            assert!(e.to_string().contains(msg), "{}: {}", msg, e);
+        }
+        let (mut s, mut peer) = raw(Role::Client, 1 << 16);
+        let bytes = frame(BINARY, b"cut short");
+        peer.write_all(&bytes[..bytes.len() - 1]).await.unwrap();
+        drop(peer);
+        let mut got = Vec::new();
+        let e = s.read_to_end(&mut got).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn upgrades_are_checked() {
+        let requests = [
+            ("GET /other HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", "404"),
+            ("GET /ws HTTP/1.1\r\nHost: elsewhere.com\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", "404"),
+            ("GET /ws HTTP/1.1\r\nHost: example.com\r\n\r\n", "400"),
+            ("POST /ws HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", "400"),
+            ("GET /ws?x=1 HTTP/1.1\r\nHost: Example.com\r\nUpgrade: WebSocket\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", "101"),
+        ];
+        for (request, status) in requests {
+            let (mut client, server) = duplex(1 << 16);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let accepted = accept(server, &config()).await;
+            assert_eq!(accepted.is_ok(), status == "101");
+            let (head, _) = read_head(&mut client).await.unwrap();
+            assert!(head.starts_with(&format!("HTTP/1.1 {}", status)), "{}", head);
+            if status == "101" {
+                assert_eq!(header(&head, "sec-websocket-accept"),
+                           Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+            }
+        }
+    }
+}